@@ -0,0 +1,56 @@
+// Reports the active tab to the qmkontext native-messaging host, which forwards it to the daemon.
+const api = globalThis.browser ?? globalThis.chrome;
+const HOST_NAME = "qmkontext";
+const RECONNECT_DELAY_MS = 10000;
+
+let port = null;
+let lastMessage = null;
+
+function connect() {
+  port = api.runtime.connectNative(HOST_NAME);
+  port.onDisconnect.addListener(() => {
+    port = null;
+    lastMessage = null;
+    setTimeout(connect, RECONNECT_DELAY_MS);
+  });
+  report();
+}
+
+function domainOf(url) {
+  try {
+    return new URL(url).hostname;
+  } catch (e) {
+    return "";
+  }
+}
+
+async function report() {
+  if (port === null) {
+    return;
+  }
+  const focusedWindow = await api.windows.getLastFocused().catch(() => null);
+  const [tab] = await api.tabs.query({ active: true, lastFocusedWindow: true });
+  const url = tab?.url ?? "";
+  const message = {
+    url,
+    domain: domainOf(url),
+    audible: tab?.audible ?? false,
+    focused: focusedWindow?.focused ?? false,
+  };
+
+  const serialized = JSON.stringify(message);
+  if (serialized !== lastMessage) {
+    lastMessage = serialized;
+    port.postMessage(message);
+  }
+}
+
+api.tabs.onActivated.addListener(report);
+api.tabs.onUpdated.addListener((tabId, changeInfo) => {
+  if ("url" in changeInfo || "audible" in changeInfo) {
+    report();
+  }
+});
+api.windows.onFocusChanged.addListener(report);
+
+connect();
@@ -1,5 +1,49 @@
+#include <string.h>
+#include "raw_hid.h"
 #include "/usr/share/qmkontext/qmkontext.h"
 
+#define QMKONTEXT_HELLO_PAGES 2
+#define QMKONTEXT_HELLO_PAGE_COMMANDS 128
+#define QMKONTEXT_HELLO_BITMAP_OFFSET 6
+#define QMKONTEXT_HELLO_CAPABILITIES_OFFSET (QMKONTEXT_HELLO_BITMAP_OFFSET + QMKONTEXT_HELLO_PAGE_COMMANDS / 8)
+#define QMKONTEXT_ACK_HEADER_LENGTH 2
+#define QMKONTEXT_BATCH_ENTRY_HEADER_LENGTH 2
+
+#ifdef QMKONTEXT_REPORT_HEADER
+static const uint8_t qmkontext_report_header[] = {QMKONTEXT_REPORT_HEADER};
+#define QMKONTEXT_REPORT_HEADER_LENGTH sizeof(qmkontext_report_header)
+#else
+#define QMKONTEXT_REPORT_HEADER_LENGTH 0
+#endif
+#define QMKONTEXT_MESSAGE_LENGTH (QMKONTEXT_REPORT_LENGTH - QMKONTEXT_REPORT_HEADER_LENGTH)
+// id_unhandled of VIA
+#define QMKONTEXT_VIA_UNHANDLED 0xFF
+
+static uint8_t qmkontext_text_commands[MAX_QMKONTEXT_TEXT_COMMANDS];
+static qmkontext_text_callback_t qmkontext_text_callbacks[MAX_QMKONTEXT_TEXT_COMMANDS];
+static uint8_t qmkontext_text_commands_count = 0;
+
+// Text being reassembled. Only one text is sent at a time, so a single buffer is enough
+static char qmkontext_text_buffer[QMKONTEXT_MAX_TEXT_LENGTH + 1];
+static int16_t qmkontext_text_command = -1;
+static uint8_t qmkontext_text_next_chunk = 0;
+static uint8_t qmkontext_text_received = 0;
+static uint8_t qmkontext_text_total_length = 0;
+
+// Sends a message to the host, after the report header and padded with zeros
+void qmkontext_send_message(const uint8_t* message, uint8_t length) {
+    uint8_t report[QMKONTEXT_REPORT_LENGTH];
+    memset(report, 0, sizeof(report));
+#ifdef QMKONTEXT_REPORT_HEADER
+    memcpy(report, qmkontext_report_header, QMKONTEXT_REPORT_HEADER_LENGTH);
+#endif
+    if (length > QMKONTEXT_MESSAGE_LENGTH) {
+        length = QMKONTEXT_MESSAGE_LENGTH;
+    }
+    memcpy(&report[QMKONTEXT_REPORT_HEADER_LENGTH], message, length);
+    raw_hid_send(report, QMKONTEXT_REPORT_LENGTH);
+}
+
 bool qmkontext_unhandled(uint8_t data) {
     return false;
 }
@@ -8,15 +52,204 @@ void qmkontext_register_callback(int event_type, qmkontext_callback_t callback)
     qmkontext_callbacks[event_type] = callback;
 }
 
-bool qmkontext_on_receive(uint8_t* data, uint8_t length) {
+void qmkontext_register_payload_callback(int event_type, qmkontext_payload_callback_t callback) {
+    qmkontext_payload_callbacks[event_type] = callback;
+}
+
+bool qmkontext_register_text_callback(int event_type, qmkontext_text_callback_t callback) {
+    if (qmkontext_text_commands_count >= MAX_QMKONTEXT_TEXT_COMMANDS) {
+        return false;
+    }
+    qmkontext_text_commands[qmkontext_text_commands_count] = event_type;
+    qmkontext_text_callbacks[qmkontext_text_commands_count] = callback;
+    qmkontext_text_commands_count++;
+    return true;
+}
+
+bool qmkontext_on_text_chunk(uint8_t command, qmkontext_text_callback_t callback, uint8_t* payload, uint8_t length) {
+    if (length < QMKONTEXT_TEXT_HEADER_LENGTH) {
+        return false;
+    }
+    uint8_t index = payload[0];
+    uint8_t total_length = payload[1];
+
+    if (index == 0) {
+        qmkontext_text_command = command;
+        qmkontext_text_next_chunk = 0;
+        qmkontext_text_received = 0;
+        qmkontext_text_total_length = total_length;
+    }
+    if (qmkontext_text_command != command || index != qmkontext_text_next_chunk) {
+        // A chunk was lost: drop the text and wait for the next one
+        qmkontext_text_command = -1;
+        return false;
+    }
+    if (total_length != qmkontext_text_total_length) {
+        // The chunk belongs to another text, which would overrun the buffer
+        qmkontext_text_command = -1;
+        return false;
+    }
+
+    uint8_t chunk_length = length - QMKONTEXT_TEXT_HEADER_LENGTH;
+    uint8_t remaining = total_length - qmkontext_text_received;
+    if (chunk_length > remaining) {
+        chunk_length = remaining;
+    }
+    memcpy(&qmkontext_text_buffer[qmkontext_text_received], &payload[QMKONTEXT_TEXT_HEADER_LENGTH], chunk_length);
+    qmkontext_text_received += chunk_length;
+    qmkontext_text_next_chunk++;
+    if (qmkontext_text_received < total_length) {
+        return true;
+    }
+
+    qmkontext_text_buffer[total_length] = '\0';
+    qmkontext_text_command = -1;
+    return callback(qmkontext_text_buffer, total_length);
+}
+
+bool qmkontext_is_handled(uint8_t command) {
+    if (qmkontext_callbacks[command] != qmkontext_unhandled || qmkontext_payload_callbacks[command] != NULL) {
+        return true;
+    }
+    for (uint8_t i = 0; i < qmkontext_text_commands_count; i++) {
+        if (qmkontext_text_commands[i] == command) {
+            return true;
+        }
+    }
+    return false;
+}
+
+// Replies with [QMKONTEXT_HELLO_COMMAND, 'Q', 'K', version, message length (after QMKONTEXT_REPORT_HEADER), page,
+// bitmap of 128 command ids, capabilities]
+bool qmkontext_on_hello(uint8_t length) {
+    uint8_t reply[QMKONTEXT_HELLO_CAPABILITIES_OFFSET + 1];
+    if (length > QMKONTEXT_MESSAGE_LENGTH) {
+        length = QMKONTEXT_MESSAGE_LENGTH;
+    }
+    for (uint8_t page = 0; page < QMKONTEXT_HELLO_PAGES; page++) {
+        memset(reply, 0, sizeof(reply));
+        reply[0] = QMKONTEXT_HELLO_COMMAND;
+        reply[1] = 'Q';
+        reply[2] = 'K';
+        reply[3] = QMKONTEXT_PROTOCOL_VERSION;
+        reply[4] = length;
+        reply[5] = page;
+        for (uint8_t i = 0; i < QMKONTEXT_HELLO_PAGE_COMMANDS; i++) {
+            if (qmkontext_is_handled(page * QMKONTEXT_HELLO_PAGE_COMMANDS + i)) {
+                reply[QMKONTEXT_HELLO_BITMAP_OFFSET + i / 8] |= 1 << (i % 8);
+            }
+        }
+        reply[QMKONTEXT_HELLO_CAPABILITIES_OFFSET] = QMKONTEXT_CAPABILITY_ACK | QMKONTEXT_CAPABILITY_BATCH;
+        qmkontext_send_message(reply, sizeof(reply));
+    }
+    return true;
+}
+
+void qmkontext_send(uint8_t command_id, const uint8_t* payload, uint8_t length) {
+    uint8_t message[QMKONTEXT_MESSAGE_LENGTH];
+    if (length > QMKONTEXT_MESSAGE_LENGTH - 1) {
+        length = QMKONTEXT_MESSAGE_LENGTH - 1;
+    }
+    message[0] = command_id;
+    memcpy(&message[1], payload, length);
+    qmkontext_send_message(message, length + 1);
+}
+
+bool qmkontext_on_message(uint8_t* data, uint8_t length);
+
+// Handles [QMKONTEXT_ACK_COMMAND, sequence, command...] and replies with [QMKONTEXT_ACK_COMMAND, sequence, handled]
+bool qmkontext_on_ack_command(uint8_t* data, uint8_t length) {
+    if (length <= QMKONTEXT_ACK_HEADER_LENGTH || data[QMKONTEXT_ACK_HEADER_LENGTH] == QMKONTEXT_ACK_COMMAND) {
+        return false;
+    }
+    bool handled = qmkontext_on_message(&data[QMKONTEXT_ACK_HEADER_LENGTH], length - QMKONTEXT_ACK_HEADER_LENGTH);
+
+    uint8_t reply[] = {QMKONTEXT_ACK_COMMAND, data[1], handled};
+    qmkontext_send_message(reply, sizeof(reply));
+    return handled;
+}
+
+// Handles [QMKONTEXT_BATCH_COMMAND, count, (command_id, length, payload...) * count]. Every command is copied to
+// its own message padded with zeros, as if it had been sent alone
+bool qmkontext_on_batch(uint8_t* data, uint8_t length) {
+    uint8_t message[QMKONTEXT_MESSAGE_LENGTH];
+    uint8_t message_length = length < sizeof(message) ? length : sizeof(message);
+    bool handled = true;
+    uint8_t offset = 2;
+    for (uint8_t i = 0; length >= 2 && i < data[1]; i++) {
+        if (offset + QMKONTEXT_BATCH_ENTRY_HEADER_LENGTH > length) {
+            return false;
+        }
+        uint8_t command = data[offset];
+        uint8_t payload_length = data[offset + 1];
+        offset += QMKONTEXT_BATCH_ENTRY_HEADER_LENGTH;
+        if (offset + payload_length > length || payload_length >= message_length || command >= QMKONTEXT_BATCH_COMMAND) {
+            return false;
+        }
+        memset(message, 0, sizeof(message));
+        message[0] = command;
+        memcpy(&message[1], &data[offset], payload_length);
+        offset += payload_length;
+        handled = qmkontext_on_message(message, message_length) && handled;
+    }
+    return handled;
+}
+
+// Handles a report without its header: the command id followed by its payload
+bool qmkontext_on_message(uint8_t* data, uint8_t length) {
     uint8_t command = data[0];
+    if (command == QMKONTEXT_HELLO_COMMAND) {
+        return qmkontext_on_hello(length);
+    }
+    if (command == QMKONTEXT_ACK_COMMAND) {
+        return qmkontext_on_ack_command(data, length);
+    }
+    if (command == QMKONTEXT_BATCH_COMMAND) {
+        return qmkontext_on_batch(data, length);
+    }
+    for (uint8_t i = 0; i < qmkontext_text_commands_count; i++) {
+        if (qmkontext_text_commands[i] == command) {
+            return qmkontext_on_text_chunk(command, qmkontext_text_callbacks[i], &data[1], length - 1);
+        }
+    }
+    if (qmkontext_payload_callbacks[command] != NULL) {
+        return (qmkontext_payload_callbacks[command])(&data[1], length - 1);
+    }
     uint8_t payload = data[1];
     return (qmkontext_callbacks[command])(payload);
 }
 
+bool qmkontext_has_header(uint8_t* data, uint8_t length) {
+#ifdef QMKONTEXT_REPORT_HEADER
+    return length > QMKONTEXT_REPORT_HEADER_LENGTH && memcmp(data, qmkontext_report_header, QMKONTEXT_REPORT_HEADER_LENGTH) == 0;
+#else
+    return length > 0;
+#endif
+}
+
+bool qmkontext_on_receive(uint8_t* data, uint8_t length) {
+    if (!qmkontext_has_header(data, length)) {
+        return false;
+    }
+    return qmkontext_on_message(&data[QMKONTEXT_REPORT_HEADER_LENGTH], length - QMKONTEXT_REPORT_HEADER_LENGTH);
+}
+
+bool qmkontext_on_via_receive(uint8_t* data, uint8_t length) {
+    if (!qmkontext_has_header(data, length)) {
+        return false;
+    }
+    bool handled = qmkontext_on_message(&data[QMKONTEXT_REPORT_HEADER_LENGTH], length - QMKONTEXT_REPORT_HEADER_LENGTH);
+    // VIA sends the buffer back to the host, which must not take it for a report of the keyboard
+    data[0] = QMKONTEXT_VIA_UNHANDLED;
+    return handled;
+}
+
 
 void qmkontext_init(void) {
     for (int i = 0; i < MAX_QMKONTEXT_COMMANDS; i++) {
         qmkontext_register_callback(i, qmkontext_unhandled);
+        qmkontext_register_payload_callback(i, NULL);
     }
+    qmkontext_text_commands_count = 0;
+    qmkontext_text_command = -1;
 }
@@ -2,10 +2,35 @@
 #define __QMKONTEXT_H__
 
 #define MAX_QMKONTEXT_COMMANDS 256
+#define MAX_QMKONTEXT_TEXT_COMMANDS 4
+#define QMKONTEXT_TEXT_HEADER_LENGTH 2
+#define QMKONTEXT_MAX_TEXT_LENGTH 255
+#define QMKONTEXT_PROTOCOL_VERSION 1
+// Command id reserved for the handshake sent by the host when it connects. Do not register callbacks for it.
+#define QMKONTEXT_HELLO_COMMAND 0xFF
+// Command id reserved for the commands sent in ack mode. Do not register callbacks for it.
+#define QMKONTEXT_ACK_COMMAND 0xFE
+// Command id reserved for the batches of commands. Do not register callbacks for it.
+#define QMKONTEXT_BATCH_COMMAND 0xFD
+// Capabilities reported in the handshake
+#define QMKONTEXT_CAPABILITY_ACK (1 << 0)
+#define QMKONTEXT_CAPABILITY_BATCH (1 << 1)
+// Length of the raw HID reports. Boards with 64 byte reports can define it in their config.h.
+#ifndef QMKONTEXT_REPORT_LENGTH
+#define QMKONTEXT_REPORT_LENGTH 32
+#endif
+// Bytes before the command id, matching the header of the keyboard config (including the framing). Not defined
+// by default. Define it in config.h, so qmkontext.c is built with it. Examples:
+// #define QMKONTEXT_REPORT_HEADER 0x51        // framing = "prefix"
+// #define QMKONTEXT_REPORT_HEADER 0x07, 0x51  // framing = "via"
+
 
 typedef bool (*qmkontext_callback_t)(uint8_t);
+typedef bool (*qmkontext_payload_callback_t)(uint8_t*, uint8_t);
+typedef bool (*qmkontext_text_callback_t)(const char*, uint8_t);
 
 qmkontext_callback_t qmkontext_callbacks[MAX_QMKONTEXT_COMMANDS];
+qmkontext_payload_callback_t qmkontext_payload_callbacks[MAX_QMKONTEXT_COMMANDS];
 
 /**
  * Init function that initializes the callbacks.
@@ -20,12 +45,58 @@ void qmkontext_init(void);
  */
 void qmkontext_register_callback(int event_type, qmkontext_callback_t callback);
 
+/**
+ * Method for registering a callback handler that receives the whole payload instead of its first byte.
+ * Takes precedence over the callback registered with qmkontext_register_callback for the same command_id.
+ * @param event_type The command_id of the qmkontext config.
+ * @param callback Callback for handling the event. Receives the payload (the report without the command_id,
+ * padded with zeros) and its length. Should return true if the event has been properly handled.
+ */
+void qmkontext_register_payload_callback(int event_type, qmkontext_payload_callback_t callback);
+
+/**
+ * Method for registering a callback handler for a text command (window_title, or custom commands with text = true).
+ * Texts are split across several reports, each one starting with the chunk index and the total text length,
+ * and are reassembled before calling the callback. Up to MAX_QMKONTEXT_TEXT_COMMANDS text commands can be registered.
+ * @param event_type The command_id of the qmkontext config.
+ * @param callback Callback for handling the text. Receives the NUL-terminated text and its length.
+ * Should return true if the event has been properly handled.
+ * @return false if there are already MAX_QMKONTEXT_TEXT_COMMANDS text commands registered.
+ */
+bool qmkontext_register_text_callback(int event_type, qmkontext_text_callback_t callback);
+
+/**
+ * Method for sending a report to the host, which receives it as an Event::Received.
+ * @param command_id Command id of the report. QMKONTEXT_HELLO_COMMAND is reserved.
+ * @param payload Payload of the report. It is padded with zeros up to the report length.
+ * @param length Length of the payload, up to QMKONTEXT_REPORT_LENGTH - 1 bytes, minus the QMKONTEXT_REPORT_HEADER bytes.
+ */
+void qmkontext_send(uint8_t command_id, const uint8_t* payload, uint8_t length);
+
 /**
  * Method for handling a hid event. The params are the same that raw_hid_receive receives.
  * @param data data pointer received by raw_hid_receive.
  * @param length length indicator raw_hid_receive.
+ * Answers the handshake of the host with the protocol version, the report length, the command ids that have
+ * a registered callback, so the host can warn about the commands that will be ignored, and the capabilities.
+ * In ack mode, every command is prefixed with [QMKONTEXT_ACK_COMMAND, sequence] and answered with
+ * [QMKONTEXT_ACK_COMMAND, sequence, handled], so the host can retry the lost ones.
+ * Batches of commands ([QMKONTEXT_BATCH_COMMAND, count, (command_id, length, payload...) * count]) are unpacked,
+ * and every command is handled as if it had been sent alone.
+ * Reports not starting with QMKONTEXT_REPORT_HEADER, when defined, are ignored.
  * @return return value of the qmkontext_callback_t that handles the command. false if no handler has been found.
  */
 bool qmkontext_on_receive(uint8_t* data, uint8_t length);
 
+/**
+ * Method for handling a hid event on keyboards with VIA or Vial enabled, which own raw_hid_receive.
+ * Call it from raw_hid_receive_kb (framing = "prefix") or via_custom_value_command_user (framing = "via").
+ * VIA sends the buffer back to the host after handling it, so its first byte is replaced with id_unhandled
+ * when the report belongs to qmkontext.
+ * @param data data pointer received by the VIA hook.
+ * @param length length received by the VIA hook.
+ * @return same as qmkontext_on_receive.
+ */
+bool qmkontext_on_via_receive(uint8_t* data, uint8_t length);
+
 #endif
\ No newline at end of file
@@ -1,8 +1,11 @@
 use config::{Config as CConfig, ConfigError, File};
-use std::path::Path;
+use qmkontext::{MAX_TEXT_LENGTH, REPORT_LENGTH};
+use std::path::{Path, PathBuf};
 
 const DEFAULT_FILE_NAME: &str = "config.toml";
 const DEFAULT_INSTALL_CONFIG_PATH: &str = "/etc/qmkontext";
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/qmkontext/qmkontext.sock";
+const DEFAULT_STATE_PATH: &str = "/var/lib/qmkontext";
 
 #[cfg(debug_assertions)]
 fn default_log_level() -> String {
@@ -18,6 +21,10 @@ fn default_debug_mode() -> bool {
     false
 }
 
+fn default_control_socket() -> PathBuf {
+    PathBuf::from(DEFAULT_CONTROL_SOCKET)
+}
+
 fn default_usage() -> u16 {
     0x61
 }
@@ -26,6 +33,27 @@ fn default_usage_page() -> u16 {
     0xFF60
 }
 
+fn default_report_length() -> usize {
+    REPORT_LENGTH
+}
+
+fn default_framing() -> FramingConfig {
+    FramingConfig::Raw
+}
+
+fn default_framing_byte() -> u8 {
+    // 'Q', which is neither a VIA command nor a VIA channel
+    0x51
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FramingConfig {
+    Raw,
+    Prefix,
+    Via,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct KeyboardConfig {
     pub vendor_id: u16,
@@ -34,6 +62,20 @@ pub struct KeyboardConfig {
     pub usage: u16,
     #[serde(default = "default_usage_page")]
     pub usage_page: u16,
+    #[serde(default = "default_report_length")]
+    pub report_length: usize,
+    #[serde(default)]
+    pub report_id: u8,
+    #[serde(default)]
+    pub header: Vec<u8>,
+    #[serde(default)]
+    pub padding: u8,
+    #[serde(default = "default_framing")]
+    pub framing: FramingConfig,
+    #[serde(default = "default_framing_byte")]
+    pub prefix: u8,
+    #[serde(default = "default_framing_byte")]
+    pub via_channel: u8,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -41,16 +83,568 @@ pub struct CurrentProgramConfig {
     pub enable: bool,
     pub command_id: u8,
     pub interval_seconds: u16,
-    pub default_value: u8,
+    pub default_value: PayloadConfig,
     #[serde(default)]
-    pub mappings: Vec<CurrentProgramMapping>,
+    pub mappings: Vec<PayloadMapping>,
     pub use_lowercase: bool,
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProfileConfig {
+    pub name: String,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
 }
 
+/// Either a single byte (`value = 3`) or a multi-byte payload (`value = [3, 0, 255]`).
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct CurrentProgramMapping {
+#[serde(untagged)]
+pub enum PayloadConfig {
+    Byte(u8),
+    Bytes(Vec<u8>),
+}
+
+impl Default for PayloadConfig {
+    fn default() -> Self {
+        PayloadConfig::Byte(0)
+    }
+}
+
+impl PayloadConfig {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            PayloadConfig::Byte(byte) => vec![byte],
+            PayloadConfig::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PayloadMapping {
     pub key: String,
+    pub value: PayloadConfig,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceBackendConfig {
+    Auto,
+    X11,
+    Sway,
+    Hyprland,
+}
+
+fn default_workspace_backend() -> WorkspaceBackendConfig {
+    WorkspaceBackendConfig::Auto
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WorkspaceConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: PayloadConfig,
+    #[serde(default = "default_workspace_backend")]
+    pub backend: WorkspaceBackendConfig,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WindowStateConfig {
+    pub enable: bool,
+    pub interval_seconds: u16,
+    #[serde(default)]
+    pub fullscreen_command_id: Option<u8>,
+    #[serde(default)]
+    pub urgent_command_id: Option<u8>,
+    #[serde(default)]
+    pub monitor_command_id: Option<u8>,
+}
+
+/// Read from the `kind` key, along with the keys each check requires.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NetworkCheckConfig {
+    InterfaceUp { interface: String },
+    DefaultRoute,
+    ConnectionType,
+}
+
+fn default_network_down_value() -> u8 {
+    0
+}
+
+fn default_network_up_value() -> u8 {
+    1
+}
+
+fn default_network_wifi_value() -> u8 {
+    2
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NetworkConfig {
+    #[serde(flatten)]
+    pub check: NetworkCheckConfig,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    #[serde(default = "default_network_down_value")]
+    pub down_value: u8,
+    #[serde(default = "default_network_up_value")]
+    pub up_value: u8,
+    #[serde(default = "default_network_up_value")]
+    pub wired_value: u8,
+    #[serde(default = "default_network_wifi_value")]
+    pub wifi_value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProcessPresenceConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: u8,
+    #[serde(default)]
+    pub rules: Vec<ProcessPresenceRule>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProcessPresenceRule {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub args_regex: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
     pub value: u8,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+fn default_calendar_soon_minutes() -> u16 {
+    5
+}
+
+fn default_calendar_upcoming_minutes() -> u16 {
+    15
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CalendarConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub paths: Vec<String>,
+    pub free_value: u8,
+    pub in_meeting_value: u8,
+    pub soon_value: u8,
+    #[serde(default = "default_calendar_soon_minutes")]
+    pub soon_minutes: u16,
+    pub upcoming_value: u8,
+    #[serde(default = "default_calendar_upcoming_minutes")]
+    pub upcoming_minutes: u16,
+}
+
+/// Read from the `mode` key, along with the keys each mode requires.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum FileWatchModeConfig {
+    Contents,
+    JsonPointer { json_pointer: String },
+    Exists,
+}
+
+fn default_file_watch_exists_value() -> PayloadConfig {
+    PayloadConfig::Byte(1)
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FileWatchConfig {
+    pub path: String,
+    #[serde(flatten)]
+    pub mode: FileWatchModeConfig,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: PayloadConfig,
+    #[serde(default = "default_file_watch_exists_value")]
+    pub exists_value: PayloadConfig,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MaildirConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub folders: Vec<String>,
+    #[serde(default)]
+    pub buckets: Vec<MaildirBucketConfig>,
+    #[serde(default)]
+    pub default_value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MaildirBucketConfig {
+    pub min_count: u32,
+    pub value: u8,
+}
+
+fn default_backlight_path() -> String {
+    "/sys/class/backlight".to_string()
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BacklightConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    #[serde(default = "default_backlight_path")]
+    pub path: String,
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputMethodBackendConfig {
+    Auto,
+    Fcitx5,
+    IBus,
+}
+
+fn default_input_method_backend() -> InputMethodBackendConfig {
+    InputMethodBackendConfig::Auto
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InputMethodConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: PayloadConfig,
+    #[serde(default = "default_input_method_backend")]
+    pub backend: InputMethodBackendConfig,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemdBusConfig {
+    System,
+    User,
+}
+
+fn default_systemd_bus() -> SystemdBusConfig {
+    SystemdBusConfig::System
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SystemdUnitConfig {
+    pub name: String,
+    pub command_id: u8,
+    pub default_value: PayloadConfig,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SystemdConfig {
+    pub enable: bool,
+    #[serde(default = "default_systemd_bus")]
+    pub bus: SystemdBusConfig,
+    pub interval_seconds: u16,
+    #[serde(default)]
+    pub failed_command_id: Option<u8>,
+    #[serde(default)]
+    pub ok_value: PayloadConfig,
+    #[serde(default)]
+    pub failed_value: PayloadConfig,
+    #[serde(default)]
+    pub units: Vec<SystemdUnitConfig>,
+}
+
+fn default_pomodoro_state_path() -> PathBuf {
+    let state_dir = match (std::env::var("XDG_STATE_HOME"), std::env::var("HOME")) {
+        (Ok(state_home), _) => PathBuf::from(state_home).join("qmkontext"),
+        (_, Ok(home)) => PathBuf::from(home).join(".local/state/qmkontext"),
+        _ => PathBuf::from(DEFAULT_STATE_PATH),
+    };
+    state_dir.join("pomodoro.json")
+}
+
+fn default_work_phases_before_long_break() -> u32 {
+    4
+}
+
+fn default_remaining_buckets() -> u8 {
+    u8::MAX
+}
+
+fn default_auto_continue() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PomodoroConfig {
+    pub enable: bool,
+    pub interval_seconds: u16,
+    pub work_minutes: u16,
+    pub short_break_minutes: u16,
+    pub long_break_minutes: u16,
+    #[serde(default = "default_work_phases_before_long_break")]
+    pub work_phases_before_long_break: u32,
+    #[serde(default = "default_auto_continue")]
+    pub auto_continue: bool,
+    #[serde(default = "default_pomodoro_state_path")]
+    pub state_path: PathBuf,
+    #[serde(default)]
+    pub phase_command_id: Option<u8>,
+    #[serde(default)]
+    pub idle_value: u8,
+    #[serde(default)]
+    pub work_value: u8,
+    #[serde(default)]
+    pub short_break_value: u8,
+    #[serde(default)]
+    pub long_break_value: u8,
+    #[serde(default)]
+    pub remaining_command_id: Option<u8>,
+    #[serde(default = "default_remaining_buckets")]
+    pub remaining_buckets: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardBackendConfig {
+    Auto,
+    X11,
+    Wayland,
+}
+
+fn default_clipboard_backend() -> ClipboardBackendConfig {
+    ClipboardBackendConfig::Auto
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ClipboardRuleConfig {
+    pub name: String,
+    #[serde(default)]
+    pub mime_regex: Option<String>,
+    #[serde(default)]
+    pub content_regex: Option<String>,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ClipboardConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: u8,
+    #[serde(default = "default_clipboard_backend")]
+    pub backend: ClipboardBackendConfig,
+    #[serde(default)]
+    pub rules: Vec<ClipboardRuleConfig>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NeovimConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    pub default_value: PayloadConfig,
+    #[serde(default)]
+    pub runtime_dir: Option<String>,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ShellRuleConfig {
+    #[serde(default)]
+    pub command_regex: Option<String>,
+    #[serde(default)]
+    pub cwd_regex: Option<String>,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ShellConfig {
+    pub enable: bool,
+    pub interval_seconds: u16,
+    pub default_value: u8,
+    #[serde(default)]
+    pub status_command_id: Option<u8>,
+    #[serde(default)]
+    pub ok_value: u8,
+    #[serde(default)]
+    pub failed_value: u8,
+    #[serde(default)]
+    pub running_command_id: Option<u8>,
+    #[serde(default)]
+    pub idle_value: u8,
+    #[serde(default)]
+    pub running_value: u8,
+    #[serde(default)]
+    pub rules: Vec<ShellRuleConfig>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BrowserConfig {
+    pub enable: bool,
+    pub interval_seconds: u16,
+    pub default_value: PayloadConfig,
+    #[serde(default)]
+    pub domain_command_id: Option<u8>,
+    #[serde(default)]
+    pub mappings: Vec<PayloadMapping>,
+    #[serde(default)]
+    pub audible_command_id: Option<u8>,
+    #[serde(default)]
+    pub audible_value: u8,
+    #[serde(default)]
+    pub silent_value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ActionConfig {
+    Run {
+        argv: Vec<String>,
+    },
+    Focus {
+        window_class: String,
+        #[serde(default)]
+        launch: Vec<String>,
+    },
+    TogglePomodoro,
+    ToggleMicrophone,
+    SwitchProfile {
+        #[serde(default)]
+        profile: Option<String>,
+    },
+}
+
+fn default_action_cooldown_ms() -> u64 {
+    500
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ActionBindingConfig {
+    pub command_id: u8,
+    #[serde(default)]
+    pub payload: Option<PayloadConfig>,
+    #[serde(default = "default_action_cooldown_ms")]
+    pub cooldown_ms: u64,
+    #[serde(flatten)]
+    pub action: ActionConfig,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ActionsConfig {
+    pub enable: bool,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub bindings: Vec<ActionBindingConfig>,
+}
+
+fn default_max_text_length() -> usize {
+    MAX_TEXT_LENGTH
+}
+
+fn default_replacement() -> char {
+    '?'
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct GlyphConfig {
+    pub character: char,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WindowTitleConfig {
+    pub enable: bool,
+    pub command_id: u8,
+    pub interval_seconds: u16,
+    #[serde(default = "default_max_text_length")]
+    pub max_length: usize,
+    #[serde(default = "default_replacement")]
+    pub replacement: char,
+    #[serde(default)]
+    pub glyphs: Vec<GlyphConfig>,
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    100
+}
+
+fn default_ack_retries() -> u32 {
+    2
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AckConfig {
+    pub enable: bool,
+    #[serde(default = "default_ack_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_ack_retries")]
+    pub retries: u32,
+}
+
+fn default_batch_window_ms() -> u64 {
+    20
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BatchingConfig {
+    pub enable: bool,
+    #[serde(default = "default_batch_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_via_lighting_channel() -> ViaLightingChannelConfig {
+    ViaLightingChannelConfig::RgbMatrix
+}
+
+fn default_saturation() -> u8 {
+    255
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViaLightingChannelConfig {
+    Rgblight,
+    RgbMatrix,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ViaLightingMappingConfig {
+    pub command_id: u8,
+    pub value: PayloadConfig,
+    #[serde(default)]
+    pub effect: Option<u8>,
+    #[serde(default)]
+    pub effect_speed: Option<u8>,
+    #[serde(default)]
+    pub hue: Option<u8>,
+    #[serde(default = "default_saturation")]
+    pub saturation: u8,
+    #[serde(default)]
+    pub brightness: Option<u8>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ViaLightingConfig {
+    pub enable: bool,
+    #[serde(default = "default_via_lighting_channel")]
+    pub channel: ViaLightingChannelConfig,
+    #[serde(default)]
+    pub mappings: Vec<ViaLightingMappingConfig>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -58,6 +652,8 @@ pub struct CustomCommandConfig {
     pub command: String,
     pub command_id: u8,
     pub interval_seconds: u16,
+    #[serde(default)]
+    pub text: bool,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -66,12 +662,56 @@ pub struct Config {
     pub log_level: String,
     #[serde(default = "default_debug_mode")]
     pub debug_mode: bool,
+    #[serde(default = "default_control_socket")]
+    pub control_socket: PathBuf,
+    #[serde(default)]
+    pub control_user: Option<String>,
     #[serde(default)]
     pub keyboard: Option<KeyboardConfig>,
     #[serde(default)]
     pub keyboards: Vec<KeyboardConfig>,
+    #[serde(default)]
+    pub ack: Option<AckConfig>,
+    #[serde(default)]
+    pub batching: Option<BatchingConfig>,
+    #[serde(default)]
+    pub via_lighting: Option<ViaLightingConfig>,
     pub current_program: CurrentProgramConfig,
     #[serde(default)]
+    pub workspace: Option<WorkspaceConfig>,
+    #[serde(default)]
+    pub window_state: Option<WindowStateConfig>,
+    #[serde(default)]
+    pub network: Vec<NetworkConfig>,
+    #[serde(default)]
+    pub process_presence: Option<ProcessPresenceConfig>,
+    #[serde(default)]
+    pub calendar: Option<CalendarConfig>,
+    #[serde(default)]
+    pub file_watchers: Vec<FileWatchConfig>,
+    #[serde(default)]
+    pub maildir: Option<MaildirConfig>,
+    #[serde(default)]
+    pub backlight: Option<BacklightConfig>,
+    #[serde(default)]
+    pub input_method: Option<InputMethodConfig>,
+    #[serde(default)]
+    pub systemd: Vec<SystemdConfig>,
+    #[serde(default)]
+    pub pomodoro: Option<PomodoroConfig>,
+    #[serde(default)]
+    pub clipboard: Option<ClipboardConfig>,
+    #[serde(default)]
+    pub neovim: Option<NeovimConfig>,
+    #[serde(default)]
+    pub shell: Option<ShellConfig>,
+    #[serde(default)]
+    pub browser: Option<BrowserConfig>,
+    #[serde(default)]
+    pub window_title: Option<WindowTitleConfig>,
+    #[serde(default)]
+    pub actions: Option<ActionsConfig>,
+    #[serde(default)]
     pub custom_commands: Vec<CustomCommandConfig>,
 }
 
@@ -83,9 +723,41 @@ impl Config {
         c.merge(File::with_name(&config_file_path).required(true))?;
 
         let parsed: Config = c.try_into()?;
+        parsed.validate()?;
         Ok(parsed)
     }
 
+    /// Checks the values that cannot be checked when parsing.
+    fn validate(&self) -> Result<(), ConfigError> {
+        for file_watcher in &self.file_watchers {
+            if let FileWatchModeConfig::JsonPointer { json_pointer } = &file_watcher.mode {
+                if !json_pointer.is_empty() && !json_pointer.starts_with('/') {
+                    return Err(ConfigError::Message(format!(
+                        "json_pointer {json_pointer} of the file watcher for {} must start with /",
+                        file_watcher.path
+                    )));
+                }
+            }
+        }
+        if let Some(pomodoro) = &self.pomodoro {
+            if pomodoro.remaining_command_id.is_some() && pomodoro.remaining_buckets == 0 {
+                return Err(ConfigError::Message(
+                    "pomodoro remaining_buckets must be at least 1 to send the remaining time"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(window_title) = &self.window_title {
+            if !window_title.replacement.is_ascii() {
+                return Err(ConfigError::Message(format!(
+                    "window_title replacement {} must be an ASCII character",
+                    window_title.replacement
+                )));
+            }
+        }
+        Ok(())
+    }
+
     fn get_config_file_path(cli_path: Option<String>) -> Result<String, ConfigError> {
         let path = match cli_path {
             Some(p) => {
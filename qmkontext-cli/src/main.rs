@@ -3,17 +3,37 @@ extern crate tracing;
 
 mod conf;
 mod list;
+mod native_host;
+mod shell_init;
 mod utils;
 
-use crate::conf::{Config, KeyboardConfig};
-use clap::{Parser, Subcommand};
+use crate::conf::{
+    ActionBindingConfig, ActionConfig, ClipboardBackendConfig, Config, FileWatchModeConfig,
+    FramingConfig, InputMethodBackendConfig, KeyboardConfig, NetworkCheckConfig, PayloadConfig,
+    PayloadMapping, SystemdBusConfig, ViaLightingChannelConfig, WorkspaceBackendConfig,
+};
+use crate::shell_init::Shell;
+use clap::{Parser, Subcommand, ValueEnum};
+use config::ConfigError;
 use qmkontext::{
-    chrono::Duration, CliSink, Engine, HidEventSink, UserEventConfig, UserEventSource,
-    UserEventSourceKind,
+    chrono::Duration, send_control_request, AckSettings, ActionBinding, ActionHandler,
+    BrowserOutput, BrowserState, CalendarBuckets, CliSink, ClipboardBackend, ClipboardRule,
+    ControlRequest, ControlResponse, ControlServer, DeliveryStats, Engine, EventSink,
+    FileWatchMode, Framing, HidEventSink, HostAction, InputMethodBackend, LightingSettings,
+    MaildirBucket, MappingProfiles, NetworkCheck, NetworkValues, PomodoroAction, PomodoroOutput,
+    PomodoroPhaseValues, PomodoroSettings, PomodoroTimer, ProcessRule, ReportLayout,
+    ShellCommandRule, ShellEvent, ShellEventKind, ShellOutput, ShellSessions, SystemdBus,
+    SystemdTarget, TextEncoding, UserEventConfig, UserEventSource, UserEventSourceKind,
+    ViaLighting, ViaLightingChannel, ViaLightingSink, WindowStateProperty, WorkspaceBackend,
+    CAPABILITY_ACK, CAPABILITY_BATCH, MAX_PAYLOAD_LENGTH, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    REPORT_HEADER_LENGTH, RESERVED_COMMAND_IDS, SYSTEMD_STATE_FAILED, SYSTEMD_STATE_OK,
 };
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 const RETRY_DELAY_SECONDS: u64 = 10;
+const HANDSHAKE_TIMEOUT_MILLIS: u64 = 1000;
 
 #[derive(Parser)]
 #[command(name = "QMKontext")]
@@ -28,10 +48,86 @@ struct Args {
 #[derive(Subcommand)]
 enum Subcommands {
     List,
+    /// Prints the delivery counters of the running daemon
+    Status,
+    /// Controls the pomodoro timer of the running daemon
+    Pomodoro {
+        #[arg(value_enum)]
+        action: PomodoroActionArg,
+    },
+    /// Prints the hooks that report the shell commands to the daemon.
+    /// Usage: `eval "$(qmkontext shell-init bash)"`
+    ShellInit {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Reports a shell event to the daemon. Used by the shell-init hooks.
+    #[command(hide = true)]
+    ShellEvent {
+        #[arg(long)]
+        socket: PathBuf,
+        #[arg(long)]
+        pid: u32,
+        #[arg(long)]
+        cwd: String,
+        #[arg(long, allow_hyphen_values = true)]
+        command: Option<String>,
+        #[arg(long, allow_hyphen_values = true)]
+        status: Option<i32>,
+        #[arg(value_enum)]
+        kind: ShellEventKindArg,
+    },
+    /// Runs the native-messaging host of the browser extension.
+    #[command(hide = true)]
+    NativeHost {
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Arguments added by the browser (manifest path, extension id or origin)
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        browser_args: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ShellEventKindArg {
+    Preexec,
+    Precmd,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PomodoroActionArg {
+    Start,
+    Pause,
+    Toggle,
+    Reset,
+    Status,
 }
 
-fn get_sink(keyboards: &[KeyboardConfig]) -> Option<HidEventSink> {
-    for keyboard in keyboards.iter() {
+impl From<PomodoroActionArg> for PomodoroAction {
+    fn from(action: PomodoroActionArg) -> Self {
+        match action {
+            PomodoroActionArg::Start => PomodoroAction::Start,
+            PomodoroActionArg::Pause => PomodoroAction::Pause,
+            PomodoroActionArg::Toggle => PomodoroAction::Toggle,
+            PomodoroActionArg::Reset => PomodoroAction::Reset,
+            PomodoroActionArg::Status => PomodoroAction::Status,
+        }
+    }
+}
+
+/// How the commands are delivered to the keyboards running qmkontext.c.
+struct DeliverySettings {
+    command_ids: Vec<u8>,
+    ack: Option<AckSettings>,
+    batch_window: Option<std::time::Duration>,
+    stats: DeliveryStats,
+}
+
+fn get_sink(
+    keyboards: &[(KeyboardConfig, ReportLayout)],
+    settings: &DeliverySettings,
+) -> Option<HidEventSink> {
+    for (keyboard, layout) in keyboards.iter() {
         match HidEventSink::new(
             keyboard.vendor_id,
             keyboard.product_id,
@@ -39,11 +135,16 @@ fn get_sink(keyboards: &[KeyboardConfig]) -> Option<HidEventSink> {
             keyboard.usage_page,
         ) {
             Ok(c) => {
+                let mut c = c
+                    .with_layout(layout.clone())
+                    .with_stats(settings.stats.clone());
                 info!(
                     "Connected to device: vendorid={} productid={}",
                     keyboard.vendor_id, keyboard.product_id
                 );
-                return Some(c);
+                if handshake(&mut c, settings) {
+                    return Some(c);
+                }
             }
             Err(e) => {
                 error!("Cannot connect to device: {:?}", e);
@@ -54,37 +155,245 @@ fn get_sink(keyboards: &[KeyboardConfig]) -> Option<HidEventSink> {
     None
 }
 
-fn start(
-    source: UserEventSource,
-    keyboard: Option<KeyboardConfig>,
-    keyboards: Vec<KeyboardConfig>,
-) {
-    let mut configured_keyboards = Vec::new();
-    if let Some(k) = keyboard {
-        configured_keyboards.push(k);
-    } else {
-        for keyboard in keyboards {
-            configured_keyboards.push(keyboard);
+/// Returns whether the firmware speaks a compatible protocol. Firmwares that do not answer the
+/// handshake are accepted, as they predate it.
+fn handshake(sink: &mut HidEventSink, settings: &DeliverySettings) -> bool {
+    let info = match sink.handshake(std::time::Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS)) {
+        Ok(Some(info)) => info,
+        Ok(None) => {
+            warn!("The keyboard did not answer the handshake. Please update its qmkontext.c");
+            if settings.ack.is_some() {
+                warn!("Sending commands without ack");
+            }
+            return true;
+        }
+        Err(e) => {
+            error!("Error in handshake: {:?}", e);
+            return false;
+        }
+    };
+
+    if !info.is_compatible() {
+        error!(
+            "Refusing keyboard with protocol version {} (supported: {}-{})",
+            info.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return false;
+    }
+    info!(
+        "Keyboard speaks protocol version {} with {} byte messages",
+        info.protocol_version, info.max_message_length
+    );
+    for command_id in &settings.command_ids {
+        if !info.handles(*command_id) {
+            warn!(
+                "The keyboard does not handle command_id={}, its values will be ignored",
+                command_id
+            );
         }
     }
+    if let Some(ack) = settings.ack {
+        if info.supports(CAPABILITY_ACK) {
+            info!("Waiting for the keyboard to acknowledge every command");
+            sink.enable_ack(ack);
+        } else {
+            warn!("The keyboard does not support acks, sending commands without ack");
+        }
+    }
+    if settings.batch_window.is_some() {
+        if info.supports(CAPABILITY_BATCH) {
+            sink.enable_batching();
+        } else {
+            info!("The keyboard does not support batches, sending one command per report");
+        }
+    }
+    true
+}
 
-    if configured_keyboards.is_empty() {
-        panic!("There are no configured keyboards. Please check your config");
+fn to_report_layout(keyboard: &KeyboardConfig) -> Result<ReportLayout, ConfigError> {
+    let framing = match keyboard.framing {
+        FramingConfig::Raw => Framing::Raw,
+        FramingConfig::Prefix => Framing::Prefix(keyboard.prefix),
+        FramingConfig::Via => Framing::Via {
+            channel: keyboard.via_channel,
+        },
+    };
+    if !framing.is_valid() {
+        return Err(ConfigError::Message(format!(
+            "{:?} framing would be handled by VIA. Please check your config",
+            framing
+        )));
     }
+    // The framing goes before the configured header
+    let mut header = framing.header();
+    header.extend_from_slice(&keyboard.header);
 
-    loop {
-        let sink = get_sink(&configured_keyboards);
-        match sink {
-            Some(s) => {
-                let engine = Engine::new(source.clone(), s);
-                if let Err(e) = engine.start() {
-                    warn!("Error in engine: {:?}", e);
-                }
+    // The header, the command id and at least one byte of payload
+    if keyboard.report_length < header.len() + REPORT_HEADER_LENGTH + 1 {
+        return Err(ConfigError::Message(format!(
+            "report_length={} is too short for the header {:?}. Please check your config",
+            keyboard.report_length, header
+        )));
+    }
+    Ok(ReportLayout {
+        report_length: keyboard.report_length,
+        report_id: keyboard.report_id,
+        header,
+        padding: keyboard.padding,
+    })
+}
+
+/// Fails for the command ids the firmware would take for protocol frames.
+fn check_command_ids(
+    what: &str,
+    command_ids: impl IntoIterator<Item = u8>,
+) -> Result<(), ConfigError> {
+    match command_ids
+        .into_iter()
+        .find(|id| RESERVED_COMMAND_IDS.contains(id))
+    {
+        Some(reserved) => Err(ConfigError::Message(format!(
+            "{what}: command_id={reserved} is reserved by the protocol. Please check your config"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Fails for the bindings switching to a profile missing from the config, which would only be
+/// found when the keyboard asks for it.
+fn check_profile(
+    binding: &ActionBindingConfig,
+    profiles: &MappingProfiles,
+) -> Result<(), ConfigError> {
+    match &binding.action {
+        ActionConfig::SwitchProfile {
+            profile: Some(profile),
+        } if !profiles.contains(profile) => Err(ConfigError::Message(format!(
+            "actions.bindings: command_id={} payload={:?} switches to the unknown profile {}. \
+             Please check your config",
+            binding.command_id, binding.payload, profile
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Error for the config values rejected by qmkontext.
+fn config_error(what: &str, error: qmkontext::Error) -> ConfigError {
+    ConfigError::Message(format!(
+        "Invalid {what}: {error:?}. Please check your config"
+    ))
+}
+
+/// Longest payload fitting in the reports of every keyboard.
+fn max_payload_length(keyboards: &[(KeyboardConfig, ReportLayout)]) -> usize {
+    keyboards
+        .iter()
+        .map(|(_, layout)| layout.message_length() - REPORT_HEADER_LENGTH)
+        .min()
+        .unwrap_or(MAX_PAYLOAD_LENGTH)
+}
+
+fn to_payload(payload: PayloadConfig, max_length: usize) -> Result<Vec<u8>, ConfigError> {
+    let bytes = payload.into_bytes();
+    if bytes.is_empty() || bytes.len() > max_length {
+        return Err(ConfigError::Message(format!(
+            "Payloads must contain between 1 and {} bytes: {:?}. Please check your config",
+            max_length, bytes
+        )));
+    }
+    Ok(bytes)
+}
+
+fn to_payload_mappings(
+    mappings: Vec<PayloadMapping>,
+    max_length: usize,
+) -> Result<HashMap<String, Vec<u8>>, ConfigError> {
+    mappings
+        .into_iter()
+        .map(|m| Ok((m.key, to_payload(m.value, max_length)?)))
+        .collect()
+}
+
+fn get_via_lighting_sink(
+    keyboards: &[(KeyboardConfig, ReportLayout)],
+    lighting: &ViaLighting,
+) -> Option<ViaLightingSink> {
+    for (keyboard, layout) in keyboards.iter() {
+        match ViaLightingSink::open(
+            keyboard.vendor_id,
+            keyboard.product_id,
+            keyboard.usage,
+            keyboard.usage_page,
+            lighting.clone(),
+        ) {
+            Ok(c) => {
+                info!(
+                    "Connected to VIA device: vendorid={} productid={}",
+                    keyboard.vendor_id, keyboard.product_id
+                );
+                return Some(c.with_layout(layout.clone()));
             }
-            None => {
-                info!("Cannot connect to any keyboard");
-                std::thread::sleep(std::time::Duration::from_secs(RETRY_DELAY_SECONDS));
+            Err(e) => {
+                error!("Cannot connect to device: {:?}", e);
             }
+        };
+    }
+
+    None
+}
+
+fn run_engine<Sink: EventSink>(
+    source: UserEventSource,
+    action_handler: Option<ActionHandler>,
+    batch_window: Option<std::time::Duration>,
+    sink: Sink,
+) {
+    let mut engine = Engine::new(source, sink);
+    if let Some(handler) = action_handler {
+        engine = engine.with_handler(handler);
+    }
+    if let Some(window) = batch_window {
+        engine = engine.with_batch_window(window);
+    }
+    if let Err(e) = engine.start() {
+        warn!("Error in engine: {:?}", e);
+    }
+}
+
+fn start(
+    source: UserEventSource,
+    action_handler: Option<ActionHandler>,
+    keyboards: Vec<(KeyboardConfig, ReportLayout)>,
+    settings: DeliverySettings,
+    via_lighting: Option<ViaLighting>,
+) {
+    loop {
+        // Stock VIA firmwares only understand the lighting commands
+        let connected = match &via_lighting {
+            Some(lighting) => get_via_lighting_sink(&keyboards, lighting)
+                .map(|sink| {
+                    run_engine(
+                        source.clone(),
+                        action_handler.clone(),
+                        settings.batch_window,
+                        sink,
+                    )
+                })
+                .is_some(),
+            None => get_sink(&keyboards, &settings)
+                .map(|sink| {
+                    run_engine(
+                        source.clone(),
+                        action_handler.clone(),
+                        settings.batch_window,
+                        sink,
+                    )
+                })
+                .is_some(),
+        };
+        if !connected {
+            info!("Cannot connect to any keyboard");
+            std::thread::sleep(std::time::Duration::from_secs(RETRY_DELAY_SECONDS));
         }
         std::thread::sleep(std::time::Duration::from_secs(RETRY_DELAY_SECONDS))
     }
@@ -92,52 +401,785 @@ fn start(
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let config = Config::new(args.config).expect("Error reading config");
+
+    // Run on every prompt, so it skips reading the config file and logs nothing
+    if let Some(Subcommands::ShellEvent {
+        socket,
+        pid,
+        cwd,
+        command,
+        status,
+        kind,
+    }) = args.command
+    {
+        let kind = match kind {
+            ShellEventKindArg::Preexec => ShellEventKind::Preexec,
+            ShellEventKindArg::Precmd => ShellEventKind::Precmd,
+        };
+        let request = ControlRequest::Shell {
+            event: ShellEvent {
+                kind,
+                pid,
+                cwd,
+                command,
+                exit_status: status,
+            },
+        };
+        if let Err(e) = send_control_request(&socket, &request) {
+            eprintln!("Cannot reach the daemon: {:?}", e);
+        }
+        return Ok(());
+    }
+
+    // Started by the browser, which owns stdin and stdout, so logging is not set up
+    if let Some(Subcommands::NativeHost { socket, .. }) = args.command {
+        let socket = socket
+            .or_else(|| Config::new(args.config).ok().map(|c| c.control_socket))
+            .unwrap_or_else(|| PathBuf::from(conf::DEFAULT_CONTROL_SOCKET));
+        native_host::run(&socket, &mut std::io::stdin().lock());
+        return Ok(());
+    }
+
+    let config = Config::new(args.config)?;
 
     utils::setup_logging(&config.log_level);
 
-    if let Some(Subcommands::List) = args.command {
-        if let Err(e) = list::list_hid_devices() {
-            error!("Error listing devices: {:?}", e);
+    match args.command {
+        Some(Subcommands::List) => {
+            if let Err(e) = list::list_hid_devices() {
+                error!("Error listing devices: {:?}", e);
+            }
+            return Ok(());
         }
-        return Ok(());
+        Some(Subcommands::Status) => {
+            match send_control_request(&config.control_socket, &ControlRequest::Status) {
+                Ok(ControlResponse::Status { delivery }) => println!(
+                    "sent: {} | acked: {} | retried: {} | unacked: {}",
+                    delivery.sent, delivery.acked, delivery.retried, delivery.unacked
+                ),
+                Ok(ControlResponse::Error { message }) => error!("Error from daemon: {}", message),
+                Ok(response) => error!("Unexpected response from daemon: {:?}", response),
+                Err(e) => error!("Cannot reach the daemon: {:?}", e),
+            }
+            return Ok(());
+        }
+        Some(Subcommands::Pomodoro { action }) => {
+            let request = ControlRequest::Pomodoro {
+                action: action.into(),
+            };
+            match send_control_request(&config.control_socket, &request) {
+                Ok(ControlResponse::Pomodoro { status }) => println!(
+                    "{:?} ({}) | remaining: {}:{:02} | completed work phases: {}",
+                    status.phase,
+                    if status.running { "running" } else { "paused" },
+                    status.remaining_seconds / 60,
+                    status.remaining_seconds % 60,
+                    status.completed_work_phases
+                ),
+                Ok(ControlResponse::Error { message }) => error!("Error from daemon: {}", message),
+                Ok(response) => error!("Unexpected response from daemon: {:?}", response),
+                Err(e) => error!("Cannot reach the daemon: {:?}", e),
+            }
+            return Ok(());
+        }
+        Some(Subcommands::ShellInit { shell }) => {
+            let exe = std::env::current_exe()?;
+            print!(
+                "{}",
+                shell_init::shell_init(shell, &exe, &config.control_socket)
+            );
+            return Ok(());
+        }
+        Some(Subcommands::ShellEvent { .. }) | Some(Subcommands::NativeHost { .. }) | None => {}
+    }
+
+    let delivery_stats = DeliveryStats::new();
+    let mut control_server =
+        ControlServer::new(config.control_socket.clone()).with_delivery(delivery_stats.clone());
+    if let Some(user) = config.control_user.clone() {
+        control_server = control_server.with_user(user);
+    }
+    let mut pomodoro_timer = None;
+
+    let mut keyboards = Vec::new();
+    if let Some(k) = config.keyboard {
+        keyboards.push(k);
+    } else {
+        keyboards.extend(config.keyboards);
     }
+    // Checked before connecting, so a bad layout is not found on every reconnection, and before
+    // the sources, so their payloads are checked against the layouts
+    let keyboards = keyboards
+        .into_iter()
+        .map(|keyboard| {
+            let layout = to_report_layout(&keyboard)?;
+            Ok((keyboard, layout))
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+
+    let max_payload_length = max_payload_length(&keyboards);
 
     let mut configs: Vec<UserEventConfig> = Vec::new();
+    let mut profiles = MappingProfiles::new(to_payload_mappings(
+        config.current_program.mappings,
+        max_payload_length,
+    )?);
+    for profile in config.current_program.profiles {
+        profiles = profiles.with_profile(
+            profile.name,
+            to_payload_mappings(profile.mappings, max_payload_length)?,
+        );
+    }
     if config.current_program.enable {
-        let mut mappings = HashMap::new();
-        for mapping in config.current_program.mappings {
-            mappings.insert(mapping.key, mapping.value);
-        }
-
         configs.push(UserEventConfig {
             interval: Duration::seconds(config.current_program.interval_seconds as i64),
             kind: UserEventSourceKind::CurrentProgram {
-                mappings,
-                default_value: config.current_program.default_value,
+                profiles: profiles.clone(),
+                default_value: to_payload(
+                    config.current_program.default_value,
+                    max_payload_length,
+                )?,
                 use_lowercase: config.current_program.use_lowercase,
             },
             command_id: config.current_program.command_id,
         })
     }
 
+    if let Some(workspace) = config.workspace.filter(|w| w.enable) {
+        let backend = match workspace.backend {
+            WorkspaceBackendConfig::Auto => WorkspaceBackend::Auto,
+            WorkspaceBackendConfig::X11 => WorkspaceBackend::X11,
+            WorkspaceBackendConfig::Sway => WorkspaceBackend::Sway,
+            WorkspaceBackendConfig::Hyprland => WorkspaceBackend::Hyprland,
+        };
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(workspace.interval_seconds as i64),
+            kind: UserEventSourceKind::Workspace {
+                backend,
+                mappings: to_payload_mappings(workspace.mappings, max_payload_length)?,
+                default_value: to_payload(workspace.default_value, max_payload_length)?,
+            },
+            command_id: workspace.command_id,
+        })
+    }
+
+    if let Some(window_state) = config.window_state.filter(|w| w.enable) {
+        let properties = [
+            (
+                window_state.fullscreen_command_id,
+                WindowStateProperty::Fullscreen,
+            ),
+            (window_state.urgent_command_id, WindowStateProperty::Urgent),
+            (
+                window_state.monitor_command_id,
+                WindowStateProperty::FocusedMonitor,
+            ),
+        ];
+        for (command_id, property) in properties {
+            if let Some(command_id) = command_id {
+                configs.push(UserEventConfig {
+                    interval: Duration::seconds(window_state.interval_seconds as i64),
+                    kind: UserEventSourceKind::WindowState { property },
+                    command_id,
+                })
+            }
+        }
+    }
+
+    for network in config.network {
+        let check = match network.check {
+            NetworkCheckConfig::InterfaceUp { interface } => {
+                NetworkCheck::InterfaceUp { interface }
+            }
+            NetworkCheckConfig::DefaultRoute => NetworkCheck::DefaultRoute,
+            NetworkCheckConfig::ConnectionType => NetworkCheck::ConnectionType,
+        };
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(network.interval_seconds as i64),
+            kind: UserEventSourceKind::Network {
+                check,
+                values: NetworkValues {
+                    down: network.down_value,
+                    up: network.up_value,
+                    wired: network.wired_value,
+                    wifi: network.wifi_value,
+                },
+            },
+            command_id: network.command_id,
+        })
+    }
+
+    if let Some(process_presence) = config.process_presence.filter(|p| p.enable) {
+        let rules = process_presence
+            .rules
+            .into_iter()
+            .map(|rule| {
+                ProcessRule::new(
+                    rule.name,
+                    rule.args_regex.as_deref(),
+                    rule.user.as_deref(),
+                    rule.value,
+                    rule.priority,
+                )
+                .map_err(|e| config_error("process_presence rule", e))
+            })
+            .collect::<Result<Vec<ProcessRule>, ConfigError>>()?;
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(process_presence.interval_seconds as i64),
+            kind: UserEventSourceKind::ProcessPresence {
+                rules,
+                default_value: process_presence.default_value,
+            },
+            command_id: process_presence.command_id,
+        })
+    }
+
+    if let Some(calendar) = config.calendar.filter(|c| c.enable) {
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(calendar.interval_seconds as i64),
+            kind: UserEventSourceKind::Calendar {
+                paths: calendar.paths.into_iter().map(PathBuf::from).collect(),
+                buckets: CalendarBuckets {
+                    free_value: calendar.free_value,
+                    in_meeting_value: calendar.in_meeting_value,
+                    soon_value: calendar.soon_value,
+                    soon_minutes: calendar.soon_minutes,
+                    upcoming_value: calendar.upcoming_value,
+                    upcoming_minutes: calendar.upcoming_minutes,
+                },
+            },
+            command_id: calendar.command_id,
+        })
+    }
+
+    for file_watcher in config.file_watchers {
+        let mode = match file_watcher.mode {
+            FileWatchModeConfig::Contents => FileWatchMode::Contents,
+            FileWatchModeConfig::JsonPointer { json_pointer } => {
+                FileWatchMode::JsonPointer(json_pointer)
+            }
+            FileWatchModeConfig::Exists => FileWatchMode::Exists {
+                exists_value: to_payload(file_watcher.exists_value, max_payload_length)?,
+            },
+        };
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(file_watcher.interval_seconds as i64),
+            kind: UserEventSourceKind::FileWatch {
+                path: PathBuf::from(file_watcher.path),
+                mode,
+                mappings: to_payload_mappings(file_watcher.mappings, max_payload_length)?,
+                default_value: to_payload(file_watcher.default_value, max_payload_length)?,
+            },
+            command_id: file_watcher.command_id,
+        })
+    }
+
+    if let Some(maildir) = config.maildir.filter(|m| m.enable) {
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(maildir.interval_seconds as i64),
+            kind: UserEventSourceKind::Maildir {
+                folders: maildir.folders.into_iter().map(PathBuf::from).collect(),
+                buckets: maildir
+                    .buckets
+                    .into_iter()
+                    .map(|b| MaildirBucket {
+                        min_count: b.min_count,
+                        value: b.value,
+                    })
+                    .collect(),
+                default_value: maildir.default_value,
+            },
+            command_id: maildir.command_id,
+        })
+    }
+
+    if let Some(backlight) = config.backlight.filter(|b| b.enable) {
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(backlight.interval_seconds as i64),
+            kind: UserEventSourceKind::Backlight {
+                path: PathBuf::from(backlight.path),
+            },
+            command_id: backlight.command_id,
+        })
+    }
+
+    if let Some(input_method) = config.input_method.filter(|i| i.enable) {
+        let backend = match input_method.backend {
+            InputMethodBackendConfig::Auto => InputMethodBackend::Auto,
+            InputMethodBackendConfig::Fcitx5 => InputMethodBackend::Fcitx5,
+            InputMethodBackendConfig::IBus => InputMethodBackend::IBus,
+        };
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(input_method.interval_seconds as i64),
+            kind: UserEventSourceKind::InputMethod {
+                backend,
+                mappings: to_payload_mappings(input_method.mappings, max_payload_length)?,
+                default_value: to_payload(input_method.default_value, max_payload_length)?,
+            },
+            command_id: input_method.command_id,
+        })
+    }
+
+    for systemd in config.systemd.into_iter().filter(|s| s.enable) {
+        let interval = Duration::seconds(systemd.interval_seconds as i64);
+        let bus = match systemd.bus {
+            SystemdBusConfig::System => SystemdBus::System,
+            SystemdBusConfig::User => SystemdBus::User,
+        };
+        if let Some(command_id) = systemd.failed_command_id {
+            let mappings = HashMap::from([
+                (
+                    SYSTEMD_STATE_OK.to_string(),
+                    to_payload(systemd.ok_value.clone(), max_payload_length)?,
+                ),
+                (
+                    SYSTEMD_STATE_FAILED.to_string(),
+                    to_payload(systemd.failed_value, max_payload_length)?,
+                ),
+            ]);
+            configs.push(UserEventConfig {
+                interval,
+                kind: UserEventSourceKind::Systemd {
+                    bus,
+                    target: SystemdTarget::AnyFailed,
+                    mappings,
+                    default_value: to_payload(systemd.ok_value, max_payload_length)?,
+                },
+                command_id,
+            })
+        }
+        for unit in systemd.units {
+            configs.push(UserEventConfig {
+                interval,
+                kind: UserEventSourceKind::Systemd {
+                    bus,
+                    target: SystemdTarget::Unit(unit.name),
+                    mappings: to_payload_mappings(unit.mappings, max_payload_length)?,
+                    default_value: to_payload(unit.default_value, max_payload_length)?,
+                },
+                command_id: unit.command_id,
+            })
+        }
+    }
+
+    if let Some(pomodoro) = config.pomodoro.filter(|p| p.enable) {
+        let timer = PomodoroTimer::new(PomodoroSettings {
+            work: Duration::minutes(pomodoro.work_minutes as i64),
+            short_break: Duration::minutes(pomodoro.short_break_minutes as i64),
+            long_break: Duration::minutes(pomodoro.long_break_minutes as i64),
+            work_phases_before_long_break: pomodoro.work_phases_before_long_break,
+            auto_continue: pomodoro.auto_continue,
+            state_path: Some(pomodoro.state_path),
+        });
+        let outputs = [
+            (
+                pomodoro.phase_command_id,
+                PomodoroOutput::Phase(PomodoroPhaseValues {
+                    idle: pomodoro.idle_value,
+                    work: pomodoro.work_value,
+                    short_break: pomodoro.short_break_value,
+                    long_break: pomodoro.long_break_value,
+                }),
+            ),
+            (
+                pomodoro.remaining_command_id,
+                PomodoroOutput::Remaining {
+                    buckets: pomodoro.remaining_buckets,
+                },
+            ),
+        ];
+        for (command_id, output) in outputs {
+            if let Some(command_id) = command_id {
+                configs.push(UserEventConfig {
+                    interval: Duration::seconds(pomodoro.interval_seconds as i64),
+                    kind: UserEventSourceKind::Pomodoro {
+                        timer: timer.clone(),
+                        output,
+                    },
+                    command_id,
+                })
+            }
+        }
+        control_server = control_server.with_pomodoro(timer.clone());
+        pomodoro_timer = Some(timer);
+    }
+
+    if let Some(clipboard) = config.clipboard.filter(|c| c.enable) {
+        let backend = match clipboard.backend {
+            ClipboardBackendConfig::Auto => ClipboardBackend::Auto,
+            ClipboardBackendConfig::X11 => ClipboardBackend::X11,
+            ClipboardBackendConfig::Wayland => ClipboardBackend::Wayland,
+        };
+        let rules = clipboard
+            .rules
+            .into_iter()
+            .map(|rule| {
+                ClipboardRule::new(
+                    rule.name,
+                    rule.mime_regex.as_deref(),
+                    rule.content_regex.as_deref(),
+                    rule.value,
+                )
+                .map_err(|e| config_error("clipboard rule", e))
+            })
+            .collect::<Result<Vec<ClipboardRule>, ConfigError>>()?;
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(clipboard.interval_seconds as i64),
+            kind: UserEventSourceKind::Clipboard {
+                backend,
+                rules,
+                default_value: clipboard.default_value,
+            },
+            command_id: clipboard.command_id,
+        })
+    }
+
+    if let Some(neovim) = config.neovim.filter(|n| n.enable) {
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(neovim.interval_seconds as i64),
+            kind: UserEventSourceKind::Neovim {
+                runtime_dir: neovim.runtime_dir.map(PathBuf::from),
+                mappings: to_payload_mappings(neovim.mappings, max_payload_length)?,
+                default_value: to_payload(neovim.default_value, max_payload_length)?,
+            },
+            command_id: neovim.command_id,
+        })
+    }
+
+    if let Some(shell) = config.shell.filter(|s| s.enable) {
+        let sessions = ShellSessions::new();
+        let rules = shell
+            .rules
+            .into_iter()
+            .map(|rule| {
+                ShellCommandRule::new(
+                    rule.command_regex.as_deref(),
+                    rule.cwd_regex.as_deref(),
+                    rule.value,
+                )
+                .map_err(|e| config_error("shell rule", e))
+            })
+            .collect::<Result<Vec<ShellCommandRule>, ConfigError>>()?;
+        let outputs = [
+            (
+                shell.status_command_id,
+                ShellOutput::LastStatus {
+                    ok_value: shell.ok_value,
+                    failed_value: shell.failed_value,
+                },
+            ),
+            (
+                shell.running_command_id,
+                ShellOutput::Command {
+                    rules,
+                    idle_value: shell.idle_value,
+                    running_value: shell.running_value,
+                },
+            ),
+        ];
+        for (command_id, output) in outputs {
+            if let Some(command_id) = command_id {
+                configs.push(UserEventConfig {
+                    interval: Duration::seconds(shell.interval_seconds as i64),
+                    kind: UserEventSourceKind::Shell {
+                        sessions: sessions.clone(),
+                        output,
+                        default_value: shell.default_value,
+                    },
+                    command_id,
+                })
+            }
+        }
+        control_server = control_server.with_shell(sessions);
+    }
+
+    if let Some(browser) = config.browser.filter(|b| b.enable) {
+        let state = BrowserState::new();
+        let default_value = to_payload(browser.default_value, max_payload_length)?;
+        let outputs = [
+            (
+                browser.domain_command_id,
+                BrowserOutput::Domain {
+                    mappings: to_payload_mappings(browser.mappings, max_payload_length)?,
+                },
+            ),
+            (
+                browser.audible_command_id,
+                BrowserOutput::Audible {
+                    audible_value: browser.audible_value,
+                    silent_value: browser.silent_value,
+                },
+            ),
+        ];
+        for (command_id, output) in outputs {
+            if let Some(command_id) = command_id {
+                configs.push(UserEventConfig {
+                    interval: Duration::seconds(browser.interval_seconds as i64),
+                    kind: UserEventSourceKind::Browser {
+                        state: state.clone(),
+                        output,
+                        default_value: default_value.clone(),
+                    },
+                    command_id,
+                })
+            }
+        }
+        control_server = control_server.with_browser(state);
+    }
+
+    if let Some(window_title) = config.window_title.filter(|w| w.enable) {
+        let encoding = TextEncoding {
+            max_length: window_title.max_length,
+            glyphs: window_title
+                .glyphs
+                .into_iter()
+                .map(|g| (g.character, g.value))
+                .collect(),
+            // Checked when loading the config
+            replacement: window_title.replacement as u8,
+        };
+        configs.push(UserEventConfig {
+            interval: Duration::seconds(window_title.interval_seconds as i64),
+            kind: UserEventSourceKind::WindowTitle { encoding },
+            command_id: window_title.command_id,
+        })
+    }
+
+    if let Some(actions) = &config.actions {
+        check_command_ids(
+            "actions.bindings",
+            actions.bindings.iter().map(|b| b.command_id),
+        )?;
+    }
+    let action_handler = config
+        .actions
+        .filter(|a| a.enable)
+        .map(|actions| {
+            let bindings = actions
+                .bindings
+                .into_iter()
+                .map(|binding| {
+                    check_profile(&binding, &profiles)?;
+                    let action = match binding.action {
+                        ActionConfig::Run { argv } => HostAction::Run { argv },
+                        ActionConfig::Focus {
+                            window_class,
+                            launch,
+                        } => HostAction::Focus {
+                            window_class,
+                            launch,
+                        },
+                        ActionConfig::TogglePomodoro => HostAction::TogglePomodoro,
+                        ActionConfig::ToggleMicrophone => HostAction::ToggleMicrophone,
+                        ActionConfig::SwitchProfile { profile } => {
+                            HostAction::SwitchProfile { profile }
+                        }
+                    };
+                    Ok(ActionBinding {
+                        command_id: binding.command_id,
+                        // Matches any payload when empty
+                        payload: binding
+                            .payload
+                            .map(|payload| to_payload(payload, max_payload_length))
+                            .transpose()?
+                            .unwrap_or_default(),
+                        cooldown: std::time::Duration::from_millis(binding.cooldown_ms),
+                        action,
+                    })
+                })
+                .collect::<Result<_, ConfigError>>()?;
+            let mut handler = ActionHandler::new(bindings).with_profiles(profiles.clone());
+            if let Some(user) = actions.user {
+                handler = handler.with_user(user);
+            }
+            if let Some(timer) = pomodoro_timer.clone() {
+                handler = handler.with_pomodoro(timer);
+            }
+            Ok::<_, ConfigError>(handler)
+        })
+        .transpose()?;
+
     for custom_command in config.custom_commands {
         configs.push(UserEventConfig {
             interval: Duration::seconds(custom_command.interval_seconds as i64),
             kind: UserEventSourceKind::UserDefined {
                 command: custom_command.command,
+                text: custom_command.text.then(TextEncoding::default),
+                max_payload_length,
             },
             command_id: custom_command.command_id,
         })
     }
 
+    let mut command_ids: Vec<u8> = configs.iter().map(|c| c.command_id).collect();
+    command_ids.sort_unstable();
+    command_ids.dedup();
+    check_command_ids("Sources", command_ids.iter().copied())?;
+
+    control_server
+        .start()
+        .map_err(|e| config_error("control_socket", e))?;
+
+    let ack = config.ack.filter(|a| a.enable).map(|ack| AckSettings {
+        timeout: std::time::Duration::from_millis(ack.timeout_ms),
+        retries: ack.retries,
+    });
+    let batch_window = config
+        .batching
+        .filter(|b| b.enable)
+        .map(|b| std::time::Duration::from_millis(b.window_ms));
+    if let Some(via_lighting) = &config.via_lighting {
+        check_command_ids(
+            "via_lighting.mappings",
+            via_lighting.mappings.iter().map(|m| m.command_id),
+        )?;
+    }
+    let via_lighting = config
+        .via_lighting
+        .filter(|v| v.enable)
+        .map(|via_lighting| {
+            let channel = match via_lighting.channel {
+                ViaLightingChannelConfig::Rgblight => ViaLightingChannel::Rgblight,
+                ViaLightingChannelConfig::RgbMatrix => ViaLightingChannel::RgbMatrix,
+            };
+            let mappings = via_lighting
+                .mappings
+                .into_iter()
+                .map(|m| {
+                    let settings = LightingSettings {
+                        effect: m.effect,
+                        effect_speed: m.effect_speed,
+                        color: m.hue.map(|hue| (hue, m.saturation)),
+                        brightness: m.brightness,
+                    };
+                    Ok((
+                        (m.command_id, to_payload(m.value, max_payload_length)?),
+                        settings,
+                    ))
+                })
+                .collect::<Result<_, ConfigError>>()?;
+            Ok::<_, ConfigError>(ViaLighting {
+                channel,
+                mappings: Arc::new(mappings),
+            })
+        })
+        .transpose()?;
+
     let source = UserEventSource::new(configs, 10);
     if config.debug_mode {
         let engine = Engine::new(source, CliSink);
         engine.start().expect("Error in loop");
     } else {
-        start(source, config.keyboard, config.keyboards);
+        if keyboards.is_empty() {
+            return Err(ConfigError::Message(
+                "There are no configured keyboards. Please check your config".to_string(),
+            )
+            .into());
+        }
+        let settings = DeliverySettings {
+            command_ids,
+            ack,
+            batch_window,
+            stats: delivery_stats,
+        };
+        start(source, action_handler, keyboards, settings, via_lighting);
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard(report_length: usize, framing: FramingConfig) -> (KeyboardConfig, ReportLayout) {
+        let keyboard = KeyboardConfig {
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            usage: 0x61,
+            usage_page: 0xFF60,
+            report_length,
+            report_id: 0,
+            header: Vec::new(),
+            padding: 0,
+            framing,
+            prefix: 0x51,
+            via_channel: 0x51,
+        };
+        let layout = to_report_layout(&keyboard).unwrap();
+        (keyboard, layout)
+    }
+
+    fn payload(length: usize) -> PayloadConfig {
+        PayloadConfig::Bytes(vec![1; length])
+    }
+
+    #[test]
+    fn default_layout() {
+        let max_length = max_payload_length(&[keyboard(32, FramingConfig::Raw)]);
+        assert_eq!(max_length, MAX_PAYLOAD_LENGTH);
+        assert_eq!(max_payload_length(&[]), MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(to_payload(payload(31), max_length).unwrap().len(), 31);
+        assert!(to_payload(payload(32), max_length).is_err());
+        assert!(to_payload(payload(0), max_length).is_err());
+    }
+
+    #[test]
+    fn long_reports() {
+        let max_length = max_payload_length(&[keyboard(64, FramingConfig::Raw)]);
+        assert_eq!(max_length, 63);
+
+        assert_eq!(to_payload(payload(63), max_length).unwrap().len(), 63);
+        assert!(to_payload(payload(64), max_length).is_err());
+    }
+
+    #[test]
+    fn via_framing() {
+        // The VIA command id and the channel go before the command id
+        let max_length = max_payload_length(&[keyboard(32, FramingConfig::Via)]);
+        assert_eq!(max_length, 29);
+
+        assert_eq!(to_payload(payload(29), max_length).unwrap().len(), 29);
+        let error = to_payload(payload(31), max_length).unwrap_err();
+        assert!(error.to_string().contains("between 1 and 29 bytes"));
+
+        let mappings = vec![PayloadMapping {
+            key: "firefox".to_string(),
+            value: payload(30),
+        }];
+        assert!(to_payload_mappings(mappings, max_length).is_err());
+    }
+
+    #[test]
+    fn shortest_messages_of_the_keyboards() {
+        let keyboards = [
+            keyboard(64, FramingConfig::Raw),
+            keyboard(32, FramingConfig::Prefix),
+        ];
+        assert_eq!(max_payload_length(&keyboards), 30);
+    }
+
+    #[test]
+    fn switch_profile_bindings() {
+        let profiles =
+            MappingProfiles::new(HashMap::new()).with_profile("gaming".to_string(), HashMap::new());
+        let binding = |profile: Option<&str>| ActionBindingConfig {
+            command_id: 40,
+            payload: Some(PayloadConfig::Byte(2)),
+            cooldown_ms: 500,
+            action: ActionConfig::SwitchProfile {
+                profile: profile.map(str::to_string),
+            },
+        };
+
+        assert!(check_profile(&binding(Some("gaming")), &profiles).is_ok());
+        assert!(check_profile(&binding(Some("default")), &profiles).is_ok());
+        // Switches to the next profile
+        assert!(check_profile(&binding(None), &profiles).is_ok());
+
+        let error = check_profile(&binding(Some("gamming")), &profiles).unwrap_err();
+        assert!(error.to_string().contains("command_id=40"));
+        assert!(error.to_string().contains("unknown profile gamming"));
+    }
+}
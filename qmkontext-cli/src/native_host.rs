@@ -0,0 +1,120 @@
+use qmkontext::{send_control_request, BrowserTab, ControlRequest};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// Browsers may send messages of up to 64 MiB, but the tab state is only a few hundred bytes.
+const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// Reads one native-messaging message: a 32-bit length in native byte order followed by the
+/// JSON payload. Returns `None` once the browser closes stdin.
+pub fn read_message(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_ne_bytes(length) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("message too long ({length} bytes)"),
+        ));
+    }
+
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+/// Runs the native-messaging host: forwards every tab reported by the extension to the
+/// daemon. stdout belongs to the browser, so errors are reported on stderr.
+pub fn run(socket: &Path, reader: &mut impl Read) {
+    loop {
+        let tab = match read_message(reader) {
+            Ok(Some(message)) => match serde_json::from_slice::<BrowserTab>(&message) {
+                Ok(tab) => Some(tab),
+                Err(e) => {
+                    eprintln!("Ignoring invalid browser message: {}", e);
+                    continue;
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Cannot read browser message: {}", e);
+                None
+            }
+        };
+
+        let closed = tab.is_none();
+        if let Err(e) = send_control_request(socket, &ControlRequest::Browser { tab }) {
+            eprintln!("Cannot reach the daemon: {:?}", e);
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut message = (payload.len() as u32).to_ne_bytes().to_vec();
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn reads_consecutive_messages() {
+        let mut input = framed(br#"{"domain":"github.com","audible":false}"#);
+        input.extend(framed(b"{}"));
+        let mut reader = Cursor::new(input);
+        assert_eq!(
+            read_message(&mut reader).unwrap().as_deref(),
+            Some(&br#"{"domain":"github.com","audible":false}"#[..])
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap().as_deref(),
+            Some(&b"{}"[..])
+        );
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut reader = Cursor::new(framed(b""));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn eof_is_the_end_of_the_messages() {
+        assert_eq!(read_message(&mut Cursor::new(Vec::new())).unwrap(), None);
+        // The browser closed stdin while writing the length
+        assert_eq!(read_message(&mut Cursor::new(vec![3, 0])).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_message() {
+        let mut input = framed(b"{\"domain\":");
+        input.truncate(input.len() - 3);
+        let error = read_message(&mut Cursor::new(input)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_message() {
+        let mut input = ((MAX_MESSAGE_LENGTH + 1) as u32).to_ne_bytes().to_vec();
+        input.extend_from_slice(b"{}");
+        let error = read_message(&mut Cursor::new(input)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let mut reader = Cursor::new(framed(&vec![b' '; MAX_MESSAGE_LENGTH]));
+        assert_eq!(
+            read_message(&mut reader).unwrap().map(|m| m.len()),
+            Some(MAX_MESSAGE_LENGTH)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use clap::ValueEnum;
+use std::path::Path;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// bash has no preexec hook, so it is emulated with the DEBUG trap. The trap only reports
+/// the first command run after the prompt, and `__qmkontext_arm` runs last in PROMPT_COMMAND
+/// so the other prompt commands are not reported.
+const BASH_SNIPPET: &str = r#"__qmkontext_armed=
+__qmkontext_preexec() {
+    [ -n "$__qmkontext_armed" ] || return
+    [ -n "$COMP_LINE" ] && return
+    [ "$BASH_COMMAND" = "__qmkontext_precmd" ] && return
+    __qmkontext_armed=
+    {EXE} shell-event --socket {SOCKET} --pid "$$" --cwd="$PWD" --command="$BASH_COMMAND" preexec >/dev/null 2>&1
+}
+__qmkontext_precmd() {
+    local exit_status=$?
+    __qmkontext_armed=
+    {EXE} shell-event --socket {SOCKET} --pid "$$" --cwd="$PWD" --status "$exit_status" precmd >/dev/null 2>&1
+}
+__qmkontext_arm() {
+    __qmkontext_armed=1
+}
+trap '__qmkontext_preexec' DEBUG
+PROMPT_COMMAND="__qmkontext_precmd"$'\n'"${PROMPT_COMMAND:+$PROMPT_COMMAND$'\n'}__qmkontext_arm"
+"#;
+
+const ZSH_SNIPPET: &str = r#"__qmkontext_preexec() {
+    {EXE} shell-event --socket {SOCKET} --pid "$$" --cwd="$PWD" --command="$1" preexec >/dev/null 2>&1
+}
+__qmkontext_precmd() {
+    local exit_status=$?
+    {EXE} shell-event --socket {SOCKET} --pid "$$" --cwd="$PWD" --status "$exit_status" precmd >/dev/null 2>&1
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __qmkontext_preexec
+add-zsh-hook precmd __qmkontext_precmd
+"#;
+
+const FISH_SNIPPET: &str = r#"function __qmkontext_preexec --on-event fish_preexec
+    {EXE} shell-event --socket {SOCKET} --pid $fish_pid --cwd=$PWD --command=$argv[1] preexec >/dev/null 2>&1
+end
+function __qmkontext_postexec --on-event fish_postexec
+    set -l last_status $status
+    {EXE} shell-event --socket {SOCKET} --pid $fish_pid --cwd=$PWD --status $last_status precmd >/dev/null 2>&1
+end
+"#;
+
+/// Returns the snippet to be evaluated by the shell, e.g. `eval "$(qmkontext shell-init bash)"`.
+/// The events are sent synchronously, so they reach the daemon in order.
+pub fn shell_init(shell: Shell, exe: &Path, socket: &Path) -> String {
+    let (snippet, quote): (&str, fn(&str) -> String) = match shell {
+        Shell::Bash => (BASH_SNIPPET, quote_posix),
+        Shell::Zsh => (ZSH_SNIPPET, quote_posix),
+        Shell::Fish => (FISH_SNIPPET, quote_fish),
+    };
+    snippet
+        .replace("{EXE}", &quote(&exe.to_string_lossy()))
+        .replace("{SOCKET}", &quote(&socket.to_string_lossy()))
+}
+
+fn quote_posix(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn quote_fish(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
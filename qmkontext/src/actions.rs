@@ -0,0 +1,282 @@
+use crate::{Error, EventHandler, MappingProfiles, PomodoroAction, PomodoroTimer, Result};
+use std::collections::HashMap;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+const PASSWD_PATH: &str = "/etc/passwd";
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const LOGIND_SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// Action run on the host when the keyboard sends a report.
+#[derive(Clone, Debug)]
+pub enum HostAction {
+    /// Runs the program with its arguments.
+    Run {
+        argv: Vec<String>,
+    },
+    /// Focuses a window of the class, or runs `launch` when there is none.
+    Focus {
+        window_class: String,
+        launch: Vec<String>,
+    },
+    TogglePomodoro,
+    /// Toggles the mute of the default audio source.
+    ToggleMicrophone,
+    /// Switches to the profile, or to the next one when `None`.
+    SwitchProfile {
+        profile: Option<String>,
+    },
+}
+
+/// Binds the reports starting with `payload` (any payload when empty) to an action.
+#[derive(Clone, Debug)]
+pub struct ActionBinding {
+    pub command_id: u8,
+    pub payload: Vec<u8>,
+    /// Reports arriving before the cooldown has elapsed since the last run are ignored.
+    pub cooldown: Duration,
+    pub action: HostAction,
+}
+
+/// Runs the actions bound to the reports sent by the keyboard. The programs run as the session
+/// user: the configured one, or the owner of the active logind session.
+#[derive(Clone)]
+pub struct ActionHandler {
+    bindings: Arc<Vec<ActionBinding>>,
+    last_runs: Arc<Mutex<HashMap<usize, Instant>>>,
+    user: Option<String>,
+    pomodoro: Option<PomodoroTimer>,
+    profiles: Option<MappingProfiles>,
+}
+
+struct SessionUser {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: PathBuf,
+}
+
+impl ActionHandler {
+    pub fn new(bindings: Vec<ActionBinding>) -> Self {
+        Self {
+            bindings: Arc::new(bindings),
+            last_runs: Arc::new(Mutex::new(HashMap::new())),
+            user: None,
+            pomodoro: None,
+            profiles: None,
+        }
+    }
+
+    pub fn with_user(mut self, user: String) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn with_pomodoro(mut self, pomodoro: PomodoroTimer) -> Self {
+        self.pomodoro = Some(pomodoro);
+        self
+    }
+
+    pub fn with_profiles(mut self, profiles: MappingProfiles) -> Self {
+        self.profiles = Some(profiles);
+        self
+    }
+
+    fn run_action(&self, action: &HostAction) -> Result<()> {
+        match action {
+            HostAction::Run { argv } => {
+                let mut command = self.user_command(argv)?;
+                Self::spawn(&mut command)
+            }
+            HostAction::Focus {
+                window_class,
+                launch,
+            } => {
+                let mut focus = self.user_command(&[
+                    "xdotool".to_string(),
+                    "search".to_string(),
+                    "--onlyvisible".to_string(),
+                    "--class".to_string(),
+                    window_class.to_string(),
+                    "windowactivate".to_string(),
+                ])?;
+                let mut launch = if launch.is_empty() {
+                    None
+                } else {
+                    Some(self.user_command(launch)?)
+                };
+                // xdotool fails when there is no window of the class
+                std::thread::spawn(move || {
+                    let focused = focus
+                        .stdout(Stdio::null())
+                        .stderr(Stdio::null())
+                        .status()
+                        .is_ok_and(|status| status.success());
+                    if let (false, Some(launch)) = (focused, launch.as_mut()) {
+                        if let Err(e) = Self::spawn(launch) {
+                            error!("Cannot launch application: {:?}", e);
+                        }
+                    }
+                });
+                Ok(())
+            }
+            HostAction::TogglePomodoro => {
+                let pomodoro = self.pomodoro.as_ref().ok_or_else(|| {
+                    Error::UserConfigExecutionError("the pomodoro is not enabled".to_string())
+                })?;
+                let status = pomodoro.apply(PomodoroAction::Toggle);
+                info!(
+                    "Pomodoro toggled: {:?} ({})",
+                    status.phase,
+                    if status.running { "running" } else { "paused" }
+                );
+                Ok(())
+            }
+            HostAction::ToggleMicrophone => {
+                let mut command = self.user_command(&[
+                    "pactl".to_string(),
+                    "set-source-mute".to_string(),
+                    "@DEFAULT_SOURCE@".to_string(),
+                    "toggle".to_string(),
+                ])?;
+                Self::spawn(&mut command)
+            }
+            HostAction::SwitchProfile { profile } => {
+                let profiles = self.profiles.as_ref().ok_or_else(|| {
+                    Error::UserConfigExecutionError("there are no mapping profiles".to_string())
+                })?;
+                profiles.switch(profile.as_deref()).map(|_| ())
+            }
+        }
+    }
+
+    /// Builds the command for `argv`, dropping the privileges to the session user when the
+    /// daemon runs as root.
+    fn user_command(&self, argv: &[String]) -> Result<Command> {
+        let (program, args) = argv.split_first().ok_or_else(|| {
+            Error::UserConfigExecutionError("the action has no program to run".to_string())
+        })?;
+        let mut command = Command::new(program);
+        command.args(args).stdin(Stdio::null());
+
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(command);
+        }
+        let user = self.session_user()?;
+        let runtime_dir = format!("/run/user/{}", user.uid);
+        if user.home.is_dir() {
+            command.current_dir(&user.home);
+        }
+        command
+            .uid(user.uid)
+            .gid(user.gid)
+            .env("HOME", &user.home)
+            .env("USER", &user.name)
+            .env("LOGNAME", &user.name)
+            .env(
+                "DBUS_SESSION_BUS_ADDRESS",
+                format!("unix:path={runtime_dir}/bus"),
+            )
+            .env("XDG_RUNTIME_DIR", runtime_dir);
+        Ok(command)
+    }
+
+    fn session_user(&self) -> Result<SessionUser> {
+        let user = match &self.user {
+            Some(user) => user.clone(),
+            None => Self::active_session_uid()?.to_string(),
+        };
+
+        // name:password:uid:gid:gecos:home:shell
+        std::fs::read_to_string(PASSWD_PATH)?
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<&str>>())
+            .filter(|fields| fields.len() > 5)
+            .find(|fields| fields[0] == user || fields[2] == user)
+            .and_then(|fields| {
+                Some(SessionUser {
+                    name: fields[0].to_string(),
+                    uid: fields[2].parse().ok()?,
+                    gid: fields[3].parse().ok()?,
+                    home: PathBuf::from(fields[5]),
+                })
+            })
+            .ok_or_else(|| Error::UserConfigExecutionError(format!("cannot find user {user}")))
+    }
+
+    /// Owner of the active session of a seat, i.e. the user in front of the keyboard.
+    pub(crate) fn active_session_uid() -> Result<u32> {
+        let connection = Connection::system()?;
+        let manager = Proxy::new(
+            &connection,
+            LOGIND_SERVICE,
+            LOGIND_PATH,
+            LOGIND_MANAGER_INTERFACE,
+        )?;
+        // (session id, uid, user name, seat, session path)
+        let sessions: Vec<(String, u32, String, String, OwnedObjectPath)> =
+            manager.call("ListSessions", &())?;
+        for (_, uid, _, seat, path) in sessions {
+            if seat.is_empty() {
+                continue;
+            }
+            let session = Proxy::new(&connection, LOGIND_SERVICE, path, LOGIND_SESSION_INTERFACE)?;
+            if session.get_property::<bool>("Active")? {
+                return Ok(uid);
+            }
+        }
+        Err(Error::UserConfigExecutionError(
+            "cannot find the active session".to_string(),
+        ))
+    }
+
+    fn spawn(command: &mut Command) -> Result<()> {
+        let mut child = command.spawn()?;
+        // Reap the child so it does not become a zombie
+        std::thread::spawn(move || child.wait());
+        Ok(())
+    }
+}
+
+impl EventHandler for ActionHandler {
+    fn handle(&self, command_id: u8, data: &[u8]) {
+        let found = self.bindings.iter().enumerate().find(|(_, binding)| {
+            binding.command_id == command_id && data.starts_with(&binding.payload)
+        });
+        let (index, binding) = match found {
+            Some(found) => found,
+            None => {
+                debug!("No action for command_id={}", command_id);
+                return;
+            }
+        };
+
+        {
+            let mut last_runs = self.last_runs.lock().unwrap();
+            let now = Instant::now();
+            if last_runs
+                .get(&index)
+                .is_some_and(|last_run| now.duration_since(*last_run) < binding.cooldown)
+            {
+                debug!("Skipping action for command_id={} (cooldown)", command_id);
+                return;
+            }
+            last_runs.insert(index, now);
+        }
+
+        info!("Running action for command_id={}", command_id);
+        if let Err(e) = self.run_action(&binding.action) {
+            error!(
+                "Error running action for command_id={}: {:?}",
+                command_id, e
+            );
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::{Arc, Mutex};
+
+/// Active tab reported by the browser extension through the native-messaging host.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BrowserTab {
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub audible: bool,
+    /// Whether a browser window has the focus.
+    #[serde(default = "default_focused")]
+    pub focused: bool,
+}
+
+fn default_focused() -> bool {
+    true
+}
+
+/// Last active tab, shared between the control socket and the browser sources.
+#[derive(Clone, Default)]
+pub struct BrowserState {
+    tab: Arc<Mutex<Option<BrowserTab>>>,
+}
+
+impl BrowserState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` means the browser (or its native-messaging host) was closed.
+    pub fn update(&self, tab: Option<BrowserTab>) {
+        // The URL may contain private data, so only the domain is logged
+        debug!(
+            "Browser active tab: {}",
+            tab.as_ref().map(|t| t.domain.as_str()).unwrap_or("none")
+        );
+        *self.tab.lock().unwrap() = tab;
+    }
+
+    pub fn tab(&self) -> Option<BrowserTab> {
+        self.tab.lock().unwrap().clone()
+    }
+}
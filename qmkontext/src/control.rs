@@ -0,0 +1,353 @@
+use crate::event_source::resolve_uid;
+use crate::{
+    ActionHandler, BrowserState, BrowserTab, DeliveryStats, DeliveryStatus, Error, PomodoroAction,
+    PomodoroStatus, PomodoroTimer, Result, ShellEvent, ShellSessions,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Requests accepted by the control socket, sent as a single JSON line.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Pomodoro {
+        action: PomodoroAction,
+    },
+    Shell {
+        event: ShellEvent,
+    },
+    /// Active tab forwarded by the native-messaging host, `None` when the browser is closed.
+    Browser {
+        tab: Option<BrowserTab>,
+    },
+    Status,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Pomodoro { status: PomodoroStatus },
+    Status { delivery: DeliveryStatus },
+    Ok,
+    Error { message: String },
+}
+
+/// Unix socket that allows other processes (the CLI, the keyboard actions...) to drive the daemon.
+/// Only root, the user running the daemon and the session user are allowed to use it.
+pub struct ControlServer {
+    path: PathBuf,
+    user: Option<String>,
+    pomodoro: Option<PomodoroTimer>,
+    shell: Option<ShellSessions>,
+    browser: Option<BrowserState>,
+    delivery: DeliveryStats,
+}
+
+impl ControlServer {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            user: None,
+            pomodoro: None,
+            shell: None,
+            browser: None,
+            delivery: DeliveryStats::new(),
+        }
+    }
+
+    /// Sets the session user (name or uid), instead of the owner of the active logind session.
+    pub fn with_user(mut self, user: String) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn with_pomodoro(mut self, pomodoro: PomodoroTimer) -> Self {
+        self.pomodoro = Some(pomodoro);
+        self
+    }
+
+    pub fn with_shell(mut self, shell: ShellSessions) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    pub fn with_browser(mut self, browser: BrowserState) -> Self {
+        self.browser = Some(browser);
+        self
+    }
+
+    pub fn with_delivery(mut self, delivery: DeliveryStats) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Binds the socket and serves it in a background thread.
+    pub fn start(self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            Self::check_socket_directory(parent)?;
+        }
+        // A socket left behind by a previous run would make the bind fail. Only the daemon can
+        // write to the directory, so it cannot be another program's socket
+        if UnixStream::connect(&self.path).is_err() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        if let Some(user) = &self.user {
+            if resolve_uid(user).is_none() {
+                return Err(Error::UserConfigExecutionError(format!(
+                    "unknown control user {user}"
+                )));
+            }
+        }
+        let listener = UnixListener::bind(&self.path)?;
+        // The daemon usually runs as root, while the clients run as the session user. Every
+        // connection is checked against the session user, as it changes when switching users.
+        std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o666))?;
+        info!("Listening for control requests on {}", self.path.display());
+
+        let server = Arc::new(self);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        // A slow client must not block the others
+                        let server = server.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = server.handle_connection(stream) {
+                                warn!("Error in control connection: {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Error accepting control connection: {:?}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Creates the directory of the socket if needed, and refuses directories other users can
+    /// write to, where they could bind the socket before the daemon and receive the requests.
+    fn check_socket_directory(directory: &Path) -> Result<()> {
+        if !directory.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(directory)?;
+        }
+        let metadata = std::fs::metadata(directory)?;
+        let euid = unsafe { libc::geteuid() };
+        if metadata.uid() != euid {
+            return Err(Error::IoError(format!(
+                "the control socket directory {} is not owned by uid {}",
+                directory.display(),
+                euid
+            )));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(Error::IoError(format!(
+                "the control socket directory {} is writable by other users",
+                directory.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the user is root, the user running the daemon or the session user.
+    fn is_allowed(&self, uid: u32) -> bool {
+        if uid == 0 || uid == unsafe { libc::geteuid() } {
+            return true;
+        }
+        let session_uid = match &self.user {
+            Some(user) => resolve_uid(user),
+            None => match ActionHandler::active_session_uid() {
+                Ok(uid) => Some(uid),
+                Err(e) => {
+                    warn!("Cannot get the session user: {:?}", e);
+                    None
+                }
+            },
+        };
+        session_uid == Some(uid)
+    }
+
+    fn peer_uid(stream: &UnixStream) -> Result<u32> {
+        let mut credentials = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: the buffer and its length match the option, and the fd is owned by the stream
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(credentials.uid)
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let uid = Self::peer_uid(&stream)?;
+        if !self.is_allowed(uid) {
+            warn!("Refusing control connection from uid {}", uid);
+            return Self::write_message(
+                &stream,
+                &ControlResponse::Error {
+                    message: "permission denied".to_string(),
+                },
+            );
+        }
+
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                debug!("Received control request");
+                self.handle_request(request)
+            }
+            Err(e) => ControlResponse::Error {
+                message: format!("invalid request: {}", e),
+            },
+        };
+        Self::write_message(&stream, &response)
+    }
+
+    fn handle_request(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Pomodoro { action } => match &self.pomodoro {
+                Some(pomodoro) => ControlResponse::Pomodoro {
+                    status: pomodoro.apply(action),
+                },
+                None => ControlResponse::Error {
+                    message: "the pomodoro is not enabled".to_string(),
+                },
+            },
+            ControlRequest::Shell { event } => match &self.shell {
+                Some(shell) => {
+                    shell.handle(event);
+                    ControlResponse::Ok
+                }
+                None => ControlResponse::Error {
+                    message: "the shell integration is not enabled".to_string(),
+                },
+            },
+            ControlRequest::Browser { tab } => match &self.browser {
+                Some(browser) => {
+                    browser.update(tab);
+                    ControlResponse::Ok
+                }
+                None => ControlResponse::Error {
+                    message: "the browser integration is not enabled".to_string(),
+                },
+            },
+            ControlRequest::Status => ControlResponse::Status {
+                delivery: self.delivery.status(),
+            },
+        }
+    }
+
+    fn write_message<T: serde::Serialize>(mut stream: &UnixStream, message: &T) -> Result<()> {
+        let mut line =
+            serde_json::to_string(message).map_err(|e| Error::IoError(format!("{:?}", e)))?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Sends a request to the daemon listening on `path` and waits for its response.
+pub fn send_control_request(path: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+    ControlServer::write_message(&stream, request)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| Error::IoError(format!("invalid response: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("qmkontext-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn creates_the_socket_directory() {
+        let directory = socket_directory("create");
+        ControlServer::new(directory.join("run/qmkontext.sock"))
+            .start()
+            .unwrap();
+
+        let mode = std::fs::metadata(directory.join("run")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn refuses_directories_writable_by_others() {
+        let directory = socket_directory("writable");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::set_permissions(&directory, std::fs::Permissions::from_mode(0o777)).unwrap();
+
+        let result = ControlServer::new(directory.join("qmkontext.sock")).start();
+
+        assert!(result.is_err());
+        assert!(!directory.join("qmkontext.sock").exists());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn refuses_directories_of_other_users() {
+        let directory = if unsafe { libc::geteuid() } == 0 {
+            let directory = socket_directory("owner");
+            std::fs::create_dir_all(&directory).unwrap();
+            let path = std::ffi::CString::new(directory.as_os_str().as_encoded_bytes()).unwrap();
+            // SAFETY: path is a valid C string
+            assert_eq!(unsafe { libc::chown(path.as_ptr(), 65534, 65534) }, 0);
+            directory
+        } else {
+            PathBuf::from("/")
+        };
+
+        let result = ControlServer::new(directory.join("qmkontext.sock")).start();
+
+        assert!(result.is_err());
+        if directory != Path::new("/") {
+            let _ = std::fs::remove_dir_all(&directory);
+        }
+    }
+
+    #[test]
+    fn slow_clients_do_not_block_the_others() {
+        let directory = socket_directory("slow");
+        let path = directory.join("qmkontext.sock");
+        ControlServer::new(path.clone()).start().unwrap();
+
+        // Connected without sending its request
+        let _idle = UnixStream::connect(&path).unwrap();
+        let started = std::time::Instant::now();
+        let response = send_control_request(&path, &ControlRequest::Status).unwrap();
+        assert!(matches!(response, ControlResponse::Status { .. }));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
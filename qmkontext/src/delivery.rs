@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Settings of the ack mode, where the firmware acknowledges every command.
+#[derive(Clone, Copy, Debug)]
+pub struct AckSettings {
+    /// Time to wait for the ack of each attempt.
+    pub timeout: Duration,
+    /// Attempts after the first one before giving up on a command.
+    pub retries: u32,
+}
+
+/// Delivery counters, shared across reconnections and reported by the control socket.
+#[derive(Clone, Default)]
+pub struct DeliveryStats {
+    counters: Arc<DeliveryCounters>,
+}
+
+#[derive(Default)]
+struct DeliveryCounters {
+    sent: AtomicU64,
+    acked: AtomicU64,
+    retried: AtomicU64,
+    unacked: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DeliveryStatus {
+    pub sent: u64,
+    pub acked: u64,
+    pub retried: u64,
+    /// Commands that were not acknowledged after all the retries.
+    pub unacked: u64,
+}
+
+impl DeliveryStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self) -> DeliveryStatus {
+        DeliveryStatus {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            acked: self.counters.acked.load(Ordering::Relaxed),
+            retried: self.counters.retried.load(Ordering::Relaxed),
+            unacked: self.counters.unacked.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_sent(&self) {
+        self.counters.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_acked(&self) {
+        self.counters.acked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_retry(&self) {
+        self.counters.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the total of unacked commands.
+    pub(crate) fn record_unacked(&self) -> u64 {
+        self.counters.unacked.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
@@ -1,4 +1,11 @@
-use crate::{Event, EventSink, EventSource, Result, SendData};
+use crate::{text_chunks, Event, EventSink, EventSource, Result, SendData};
+use crossbeam_channel::{at, never, select, unbounded};
+use std::time::{Duration, Instant};
+
+/// Handles the reports sent by the keyboard to the host.
+pub trait EventHandler {
+    fn handle(&self, command_id: u8, data: &[u8]);
+}
 
 pub struct Engine<Source, Sink>
 where
@@ -7,6 +14,8 @@ where
 {
     source: Source,
     sink: Sink,
+    handler: Option<Box<dyn EventHandler>>,
+    batch_window: Option<Duration>,
 }
 
 impl<Source, Sink> Engine<Source, Sink>
@@ -15,30 +24,260 @@ where
     Sink: EventSink,
 {
     pub fn new(source: Source, sink: Sink) -> Self {
-        Self { source, sink }
+        Self {
+            source,
+            sink,
+            handler: None,
+            batch_window: None,
+        }
+    }
+
+    pub fn with_handler(mut self, handler: impl EventHandler + 'static) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Waits for the commands arriving within the window after the first one, so they are sent
+    /// together. Only the last value of every command id is kept.
+    pub fn with_batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
     }
 
-    pub fn start(self) -> Result<()> {
+    pub fn start(mut self) -> Result<()> {
         let channel = self.source.events();
         let source = self.source;
         std::thread::spawn(|| {
             source.start();
         });
-        while let Some(evt) = channel.iter().next() {
-            match evt {
-                Event::Send {
-                    command_id,
-                    command_data,
-                } => {
-                    let payload = SendData {
+
+        let (received_sender, mut received) = unbounded();
+        self.sink.start_receiving(received_sender)?;
+
+        let mut pending: Vec<SendData> = Vec::new();
+        let mut flush_at = never();
+        loop {
+            let evt = select! {
+                recv(channel) -> evt => match evt {
+                    Ok(evt) => evt,
+                    Err(_) => break,
+                },
+                recv(received) -> evt => match evt {
+                    Ok(evt) => evt,
+                    // The device stopped being read, but the commands may still be sent
+                    Err(_) => {
+                        received = never();
+                        continue;
+                    }
+                },
+                recv(flush_at) -> _ => {
+                    self.sink.send_batch(&pending)?;
+                    pending.clear();
+                    flush_at = never();
+                    continue;
+                },
+            };
+
+            match (evt, self.batch_window) {
+                (
+                    Event::Send {
                         command_id,
-                        data: command_data,
-                    };
-                    self.sink.send(&payload)?;
+                        command_data,
+                    },
+                    Some(window),
+                ) => {
+                    if pending.is_empty() {
+                        flush_at = at(Instant::now() + window);
+                    }
+                    match pending.iter_mut().find(|p| p.command_id == command_id) {
+                        Some(previous) => previous.data = command_data,
+                        None => pending.push(SendData {
+                            command_id,
+                            data: command_data,
+                        }),
+                    }
+                }
+                (evt, _) => {
+                    // Keeps the commands in order
+                    if matches!(evt, Event::SendText { .. }) && !pending.is_empty() {
+                        self.sink.send_batch(&pending)?;
+                        pending.clear();
+                        flush_at = never();
+                    }
+                    Self::handle_event(&self.sink, self.handler.as_deref(), evt)?;
                 }
             }
         }
 
+        self.sink.send_batch(&pending)?;
+        Ok(())
+    }
+
+    fn handle_event(sink: &Sink, handler: Option<&dyn EventHandler>, evt: Event) -> Result<()> {
+        match evt {
+            Event::Send {
+                command_id,
+                command_data,
+            } => {
+                let payload = SendData {
+                    command_id,
+                    data: command_data,
+                };
+                sink.send(&payload)?;
+            }
+            Event::SendText { command_id, text } => {
+                for payload in text_chunks(command_id, &text, sink.max_payload_length()) {
+                    sink.send(&payload)?;
+                }
+            }
+            Event::Received { command_id, data } => match handler {
+                Some(handler) => handler.handle(command_id, &data),
+                None => debug!(
+                    "Ignoring report from the keyboard: command_id={}",
+                    command_id
+                ),
+            },
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::{Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+
+    /// Source whose events are sent by the test.
+    struct FakeSource {
+        events: Receiver<Event>,
+    }
+
+    impl EventSource for FakeSource {
+        fn events(&self) -> Receiver<Event> {
+            self.events.clone()
+        }
+
+        fn start(self) {}
+    }
+
+    /// What the engine asked the sink to send, as `(command_id, data)`.
+    #[derive(Debug, PartialEq)]
+    enum Sent {
+        One(u8, Vec<u8>),
+        Batch(Vec<(u8, Vec<u8>)>),
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        sent: Arc<Mutex<Vec<Sent>>>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn send(&self, data: &SendData) -> Result<()> {
+            let sent = Sent::One(data.command_id, data.data.clone());
+            self.sent.lock().unwrap().push(sent);
+            Ok(())
+        }
+
+        fn send_batch(&self, commands: &[SendData]) -> Result<()> {
+            // The engine flushes the empty pending commands when it stops
+            if !commands.is_empty() {
+                let commands = commands
+                    .iter()
+                    .map(|c| (c.command_id, c.data.clone()))
+                    .collect();
+                self.sent.lock().unwrap().push(Sent::Batch(commands));
+            }
+            Ok(())
+        }
+    }
+
+    fn send(command_id: u8, value: u8) -> Event {
+        Event::Send {
+            command_id,
+            command_data: vec![value],
+        }
+    }
+
+    /// Runs the engine until `send_events` returns and the source is closed.
+    fn run(batch_window: Option<Duration>, send_events: impl FnOnce(&Sender<Event>)) -> Vec<Sent> {
+        let (sender, events) = unbounded();
+        let sink = RecordingSink::default();
+        let engine_sink = sink.clone();
+        let engine = std::thread::spawn(move || {
+            let mut engine = Engine::new(FakeSource { events }, engine_sink);
+            if let Some(window) = batch_window {
+                engine = engine.with_batch_window(window);
+            }
+            engine.start()
+        });
+
+        send_events(&sender);
+        drop(sender);
+        engine.join().unwrap().unwrap();
+
+        Arc::try_unwrap(sink.sent).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn sends_every_command_without_window() {
+        let sent = run(None, |sender| {
+            sender.send(send(1, 1)).unwrap();
+            sender.send(send(1, 2)).unwrap();
+        });
+
+        assert_eq!(sent, vec![Sent::One(1, vec![1]), Sent::One(1, vec![2])]);
+    }
+
+    #[test]
+    fn keeps_the_last_value_of_every_command() {
+        let sent = run(Some(Duration::from_secs(10)), |sender| {
+            sender.send(send(1, 1)).unwrap();
+            sender.send(send(2, 5)).unwrap();
+            sender.send(send(1, 2)).unwrap();
+        });
+
+        assert_eq!(sent, vec![Sent::Batch(vec![(1, vec![2]), (2, vec![5])])]);
+    }
+
+    #[test]
+    fn flushes_when_the_window_ends() {
+        let sent = run(Some(Duration::from_millis(50)), |sender| {
+            sender.send(send(1, 1)).unwrap();
+            sender.send(send(2, 5)).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            sender.send(send(1, 2)).unwrap();
+        });
+
+        assert_eq!(
+            sent,
+            vec![
+                Sent::Batch(vec![(1, vec![1]), (2, vec![5])]),
+                Sent::Batch(vec![(1, vec![2])]),
+            ]
+        );
+    }
+
+    #[test]
+    fn texts_are_sent_after_the_pending_commands() {
+        let sent = run(Some(Duration::from_secs(10)), |sender| {
+            sender.send(send(1, 1)).unwrap();
+            let text = Event::SendText {
+                command_id: 3,
+                text: b"hi".to_vec(),
+            };
+            sender.send(text).unwrap();
+            sender.send(send(1, 2)).unwrap();
+        });
+
+        assert_eq!(
+            sent,
+            vec![
+                Sent::Batch(vec![(1, vec![1])]),
+                Sent::One(3, vec![0, 2, b'h', b'i']),
+                Sent::Batch(vec![(1, vec![2])]),
+            ]
+        );
+    }
+}
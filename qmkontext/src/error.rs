@@ -1,9 +1,15 @@
 #[derive(Clone, Debug)]
 pub enum Error {
     CannotGetCurrentProgram,
+    CannotGetWorkspace(String),
     UserConfigExecutionError(String),
     SendError(String),
     HidError(String),
+    X11Error(String),
+    IoError(String),
+    DbusError(String),
+    RpcError(String),
+    ProtocolError(String),
 }
 
 impl From<hidapi::HidError> for Error {
@@ -11,3 +17,57 @@ impl From<hidapi::HidError> for Error {
         Error::SendError(format!("hidapi error: {}", value))
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::IoError(format!("io error: {}", value))
+    }
+}
+
+impl From<x11rb::errors::ConnectError> for Error {
+    fn from(value: x11rb::errors::ConnectError) -> Self {
+        Error::X11Error(format!("cannot connect to X11 server: {}", value))
+    }
+}
+
+impl From<x11rb::errors::ConnectionError> for Error {
+    fn from(value: x11rb::errors::ConnectionError) -> Self {
+        Error::X11Error(format!("X11 connection error: {}", value))
+    }
+}
+
+impl From<x11rb::errors::ReplyError> for Error {
+    fn from(value: x11rb::errors::ReplyError) -> Self {
+        Error::X11Error(format!("X11 reply error: {}", value))
+    }
+}
+
+impl From<x11rb::errors::ReplyOrIdError> for Error {
+    fn from(value: x11rb::errors::ReplyOrIdError) -> Self {
+        Error::X11Error(format!("X11 reply error: {}", value))
+    }
+}
+
+impl From<zbus::Error> for Error {
+    fn from(value: zbus::Error) -> Self {
+        Error::DbusError(format!("dbus error: {}", value))
+    }
+}
+
+impl From<zbus::fdo::Error> for Error {
+    fn from(value: zbus::fdo::Error) -> Self {
+        Error::DbusError(format!("dbus error: {}", value))
+    }
+}
+
+impl From<rmpv::encode::Error> for Error {
+    fn from(value: rmpv::encode::Error) -> Self {
+        Error::RpcError(format!("msgpack encode error: {}", value))
+    }
+}
+
+impl From<rmpv::decode::Error> for Error {
+    fn from(value: rmpv::decode::Error) -> Self {
+        Error::RpcError(format!("msgpack decode error: {}", value))
+    }
+}
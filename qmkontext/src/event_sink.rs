@@ -1,65 +1,460 @@
-use crate::{Error, Result};
+use crate::protocol::{batch, hello_report, HelloReplies};
+use crate::{
+    AckSettings, DeliveryStats, DeviceInfo, Error, Event, Result, ACK_COMMAND_ID,
+    ACK_HEADER_LENGTH, HELLO_COMMAND_ID,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use hidapi::{HidApi, HidDevice};
+use std::cell::{Cell, OnceCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the reader thread polls the device, and checks whether the sink has been dropped.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Default length of the raw HID reports, without the report id.
+pub const REPORT_LENGTH: usize = 32;
+/// Bytes of the report used by the command id.
+pub const REPORT_HEADER_LENGTH: usize = 1;
+/// Maximum length of the payload sent along a command id with the default reports.
+pub const MAX_PAYLOAD_LENGTH: usize = REPORT_LENGTH - REPORT_HEADER_LENGTH;
+
+/// How the messages (the command id followed by its payload) are laid out in the raw HID reports
+/// of a device.
+#[derive(Clone, Debug)]
+pub struct ReportLayout {
+    /// Length of the raw HID reports, without the report id.
+    pub report_length: usize,
+    /// Report id written before every report. 0 for devices without numbered reports.
+    pub report_id: u8,
+    /// Bytes sent before the command id, e.g. to share the interface with other raw HID features.
+    /// The reports sent by the keyboard must start with them too, the rest are ignored.
+    pub header: Vec<u8>,
+    /// Value of the unused bytes at the end of the reports.
+    pub padding: u8,
+}
+
+impl Default for ReportLayout {
+    fn default() -> Self {
+        Self {
+            report_length: REPORT_LENGTH,
+            report_id: 0,
+            header: Vec::new(),
+            padding: 0,
+        }
+    }
+}
+
+impl ReportLayout {
+    /// Length of the messages fitting in a report.
+    pub fn message_length(&self) -> usize {
+        self.report_length.saturating_sub(self.header.len())
+    }
+
+    /// Builds the buffer written to the device: the report id, the header, the message and the
+    /// padding.
+    pub(crate) fn report(&self, message: &[u8]) -> Vec<u8> {
+        let mut report = Vec::with_capacity(1 + self.report_length);
+        report.push(self.report_id);
+        report.extend_from_slice(&self.header);
+        report.extend_from_slice(message);
+        report.resize(1 + self.report_length, self.padding);
+        report
+    }
+
+    /// Returns the message of a report read from the device, or `None` when the report does not
+    /// belong to qmkontext.
+    pub(crate) fn message<'a>(&self, report: &'a [u8]) -> Option<&'a [u8]> {
+        // Numbered reports are read along their report id
+        let report = match self.report_id {
+            0 => report,
+            report_id => report.strip_prefix(&[report_id])?,
+        };
+        report.strip_prefix(self.header.as_slice())
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct SendData {
     pub command_id: u8,
-    pub data: u8,
+    pub data: Vec<u8>,
 }
 
 pub trait EventSink {
     fn send(&self, data: &SendData) -> Result<()>;
+
+    /// Longest payload accepted by `send`.
+    fn max_payload_length(&self) -> usize {
+        MAX_PAYLOAD_LENGTH
+    }
+
+    /// Sends several commands at once. Sinks that cannot batch them send them one by one.
+    fn send_batch(&self, commands: &[SendData]) -> Result<()> {
+        for command in commands {
+            self.send(command)?;
+        }
+        Ok(())
+    }
+
+    /// Starts forwarding the reports sent by the keyboard as [`Event::Received`]. Sinks that
+    /// cannot receive anything do nothing.
+    fn start_receiving(&mut self, _sender: Sender<Event>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Raw HID device written by the sinks, so they can be driven by a fake device.
+pub trait RawHidDevice {
+    fn write(&self, report: &[u8]) -> Result<usize>;
+    /// Returns 0 when nothing was read before the timeout.
+    fn read_timeout(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize>;
+}
+
+impl RawHidDevice for HidDevice {
+    fn write(&self, report: &[u8]) -> Result<usize> {
+        Ok(HidDevice::write(self, report)?)
+    }
+
+    fn read_timeout(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        Ok(HidDevice::read_timeout(self, report, timeout_millis)?)
+    }
+}
+
+/// Opens the interface of the keyboard matching the usage and usage page.
+pub(crate) fn open_device(
+    api: &HidApi,
+    vid: u16,
+    pid: u16,
+    usage: u16,
+    usage_page: u16,
+) -> Result<HidDevice> {
+    for device in api.device_list() {
+        if device.vendor_id() == vid
+            && device.product_id() == pid
+            && usage == device.usage()
+            && device.usage_page() == usage_page
+        {
+            let open_device = device.open_device(api)?;
+            return Ok(open_device);
+        }
+    }
+    Err(Error::HidError(format!(
+        "Cannot find device vid={vid} pid={pid} usage={usage} usage_page={usage_page}"
+    )))
 }
 
-pub struct HidEventSink {
-    hid_device: HidDevice,
+pub struct HidEventSink<Device: RawHidDevice + Send + 'static = HidDevice> {
+    /// Single handle of the device, written by the sink and read by the reader thread.
+    device: Arc<Mutex<Device>>,
+    /// Handshake replies and acks forwarded by the reader thread, started on first use.
+    replies: OnceCell<Receiver<Vec<u8>>>,
+    /// Where the reader thread forwards the other reports, once receiving has started.
+    received: Arc<Mutex<Option<Sender<Event>>>>,
+    stopped: Arc<AtomicBool>,
+    layout: ReportLayout,
+    max_payload_length: usize,
+    ack: Option<AckSettings>,
+    batching: bool,
+    stats: DeliveryStats,
+    next_sequence: Cell<u8>,
 }
 
-impl HidEventSink {
+impl HidEventSink<HidDevice> {
     pub fn new(vid: u16, pid: u16, usage: u16, usage_page: u16) -> Result<Self> {
         let api = HidApi::new()?;
-        let device = Self::get_device(&api, vid, pid, usage, usage_page)?;
-        Ok(Self { hid_device: device })
-    }
-
-    fn get_device(
-        api: &HidApi,
-        vid: u16,
-        pid: u16,
-        usage: u16,
-        usage_page: u16,
-    ) -> Result<HidDevice> {
-        for device in api.device_list() {
-            if device.vendor_id() == vid
-                && device.product_id() == pid
-                && usage == device.usage()
-                && device.usage_page() == usage_page
-            {
-                let open_device = device.open_device(api)?;
-                return Ok(open_device);
+        let device = open_device(&api, vid, pid, usage, usage_page)?;
+        Ok(Self::from_device(device))
+    }
+}
+
+impl<Device: RawHidDevice + Send + 'static> HidEventSink<Device> {
+    pub fn from_device(device: Device) -> Self {
+        Self {
+            device: Arc::new(Mutex::new(device)),
+            replies: OnceCell::new(),
+            received: Arc::new(Mutex::new(None)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            layout: ReportLayout::default(),
+            max_payload_length: MAX_PAYLOAD_LENGTH,
+            ack: None,
+            batching: false,
+            stats: DeliveryStats::new(),
+            next_sequence: Cell::new(1),
+        }
+    }
+
+    /// Must be called before the handshake, as the reader thread keeps the layout it starts with.
+    pub fn with_layout(mut self, layout: ReportLayout) -> Self {
+        self.max_payload_length = layout.message_length().saturating_sub(REPORT_HEADER_LENGTH);
+        self.layout = layout;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: DeliveryStats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Waits for the firmware to acknowledge every command, retrying the unacknowledged ones.
+    /// Only for firmwares reporting `CAPABILITY_ACK` in the handshake.
+    pub fn enable_ack(&mut self, settings: AckSettings) {
+        self.ack = Some(settings);
+    }
+
+    /// Packs several commands in a single report. Only for firmwares reporting
+    /// `CAPABILITY_BATCH` in the handshake.
+    pub fn enable_batching(&mut self) {
+        self.batching = true;
+    }
+
+    fn write(&self, report: &[u8]) -> Result<usize> {
+        self.device.lock().unwrap().write(report)
+    }
+
+    /// Replies received since the reader thread started, which is started on the first call.
+    fn replies(&self) -> &Receiver<Vec<u8>> {
+        self.replies.get_or_init(|| {
+            let (replies_sender, replies) = crossbeam_channel::unbounded();
+            let device = self.device.clone();
+            let layout = self.layout.clone();
+            let received = self.received.clone();
+            let stopped = self.stopped.clone();
+            std::thread::spawn(move || {
+                Self::read_loop(device, layout, stopped, replies_sender, received)
+            });
+            replies
+        })
+    }
+
+    /// Waits for the next reply forwarded by the reader thread until the deadline.
+    fn next_reply(&self, deadline: Instant) -> Result<Option<Vec<u8>>> {
+        match self.replies().recv_deadline(deadline) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::HidError(
+                "the device is not being read anymore".to_string(),
+            )),
+        }
+    }
+
+    /// Drops the replies left by previous commands, such as acks arriving after their timeout.
+    fn drop_stale_replies(&self) {
+        for reply in self.replies().try_iter() {
+            debug!("Dropping stale reply {:?}", reply);
+        }
+    }
+
+    fn send_acked(&self, data: &SendData, settings: AckSettings) -> Result<()> {
+        // 0 is skipped, so a zeroed report is never taken for an ack
+        let sequence = self.next_sequence.get();
+        self.next_sequence.set(sequence.checked_add(1).unwrap_or(1));
+
+        let mut message = vec![ACK_COMMAND_ID, sequence, data.command_id];
+        message.extend_from_slice(&data.data);
+        let buff = self.layout.report(&message);
+
+        self.drop_stale_replies();
+        for attempt in 0..=settings.retries {
+            if attempt > 0 {
+                debug!(
+                    "Retrying command_id={} (attempt {})",
+                    data.command_id,
+                    attempt + 1
+                );
+                self.stats.record_retry();
+            }
+            self.write(&buff)?;
+            if self.wait_ack(sequence, settings.timeout)? {
+                self.stats.record_acked();
+                return Ok(());
+            }
+        }
+
+        let unacked = self.stats.record_unacked();
+        warn!(
+            "command_id={} was not acknowledged after {} attempts ({} unacknowledged commands)",
+            data.command_id,
+            settings.retries + 1,
+            unacked
+        );
+        Ok(())
+    }
+
+    /// Waits for the reader thread to forward the ack of `sequence`. The acks of other
+    /// sequences, sent late for previous attempts, are dropped.
+    fn wait_ack(&self, sequence: u8, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while let Some(reply) = self.next_reply(deadline)? {
+            if let [ACK_COMMAND_ID, acked, ..] = reply.as_slice() {
+                if *acked == sequence {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reads every report of the device. Handshake replies and acks go to the sink, the other
+    /// messages are forwarded as [`Event::Received`] once receiving has started.
+    fn read_loop(
+        device: Arc<Mutex<Device>>,
+        layout: ReportLayout,
+        stopped: Arc<AtomicBool>,
+        replies: Sender<Vec<u8>>,
+        received: Arc<Mutex<Option<Sender<Event>>>>,
+    ) {
+        let mut report = vec![0u8; 1 + layout.report_length];
+        while !stopped.load(Ordering::Relaxed) {
+            // Polled without blocking, so the device is never locked while waiting for reports
+            let read = match device.lock().unwrap().read_timeout(&mut report, 0) {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Error reading from device: {:?}", e);
+                    return;
+                }
+            };
+            if read == 0 {
+                std::thread::sleep(READ_POLL_INTERVAL);
+                continue;
+            }
+            let message = match layout.message(&report[..read]) {
+                Some(message) if !message.is_empty() => message,
+                _ => continue,
+            };
+            if message[0] == HELLO_COMMAND_ID || message[0] == ACK_COMMAND_ID {
+                if replies.send(message.to_vec()).is_err() {
+                    return;
+                }
+                continue;
+            }
+            if message.len() <= REPORT_HEADER_LENGTH {
+                continue;
+            }
+            let received = received.lock().unwrap();
+            let sender = match received.as_ref() {
+                Some(sender) => sender,
+                None => {
+                    debug!(
+                        "Dropping command_id={} received before starting",
+                        message[0]
+                    );
+                    continue;
+                }
+            };
+            debug!(
+                "Received command_id={} | data={:?}",
+                message[0],
+                &message[REPORT_HEADER_LENGTH..]
+            );
+            let event = Event::Received {
+                command_id: message[0],
+                data: message[REPORT_HEADER_LENGTH..].to_vec(),
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Sends the hello and waits for the firmware to describe itself. Returns `None` when the
+    /// firmware does not answer before the timeout, as firmwares predating the handshake do.
+    pub fn handshake(&mut self, timeout: Duration) -> Result<Option<DeviceInfo>> {
+        self.drop_stale_replies();
+        self.write(&self.layout.report(&hello_report()))?;
+
+        let deadline = Instant::now() + timeout;
+        let mut replies = HelloReplies::default();
+        while let Some(reply) = self.next_reply(deadline)? {
+            if !replies.push(&reply)? {
+                continue;
+            }
+            if let Some(info) = replies.device_info() {
+                let message_length =
+                    (info.max_message_length as usize).min(self.layout.message_length());
+                self.max_payload_length = message_length.saturating_sub(REPORT_HEADER_LENGTH);
+                return Ok(Some(info));
             }
         }
-        Err(Error::HidError(format!(
-            "Cannot find device vid={vid} pid={pid} usage={usage} usage_page={usage_page}"
-        )))
+        Ok(None)
     }
 }
 
-impl EventSink for HidEventSink {
+impl<Device: RawHidDevice + Send + 'static> EventSink for HidEventSink<Device> {
     fn send(&self, data: &SendData) -> Result<()> {
-        let mut buff = [0u8; 33];
-        buff[1] = data.command_id;
-        buff[2] = data.data;
+        let max_payload_length = self.max_payload_length();
+        if data.data.is_empty() || data.data.len() > max_payload_length {
+            // Not a device error, so the connection is kept
+            error!(
+                "Invalid payload length {} for command_id={} (max {})",
+                data.data.len(),
+                data.command_id,
+                max_payload_length
+            );
+            return Ok(());
+        }
+        self.stats.record_sent();
+        if let Some(settings) = self.ack {
+            debug!(
+                "Sending command_id={} | data={:?} (acked)",
+                data.command_id, data.data
+            );
+            return self.send_acked(data, settings);
+        }
+
+        let mut message = Vec::with_capacity(REPORT_HEADER_LENGTH + data.data.len());
+        message.push(data.command_id);
+        message.extend_from_slice(&data.data);
+        let buff = self.layout.report(&message);
 
         debug!(
-            "Sending command_id={} | data={}",
+            "Sending command_id={} | data={:?}",
             data.command_id, data.data
         );
-        self.hid_device.write(&buff)?;
+        self.write(&buff)?;
+        Ok(())
+    }
+
+    fn send_batch(&self, commands: &[SendData]) -> Result<()> {
+        if !self.batching {
+            for command in commands {
+                self.send(command)?;
+            }
+            return Ok(());
+        }
+        for send in batch(commands, self.max_payload_length()) {
+            self.send(&send)?;
+        }
+        Ok(())
+    }
+
+    fn max_payload_length(&self) -> usize {
+        match self.ack {
+            Some(_) => self.max_payload_length.saturating_sub(ACK_HEADER_LENGTH),
+            None => self.max_payload_length,
+        }
+    }
+
+    fn start_receiving(&mut self, sender: Sender<Event>) -> Result<()> {
+        let mut received = self.received.lock().unwrap();
+        if received.is_some() {
+            return Err(Error::HidError(
+                "the device is already being read".to_string(),
+            ));
+        }
+        *received = Some(sender);
+        drop(received);
+        self.replies();
         Ok(())
     }
 }
 
+impl<Device: RawHidDevice + Send + 'static> Drop for HidEventSink<Device> {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
 pub struct CliSink;
 
 impl EventSink for CliSink {
@@ -68,3 +463,250 @@ impl EventSink for CliSink {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CAPABILITY_ACK, PROTOCOL_VERSION};
+    use std::collections::VecDeque;
+
+    type Responder = dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+    /// Device queuing the reports returned by `respond` for every message written.
+    #[derive(Clone)]
+    struct FakeDevice {
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        reports: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        respond: Arc<Responder>,
+    }
+
+    impl FakeDevice {
+        fn new(respond: impl Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static) -> Self {
+            Self {
+                written: Arc::new(Mutex::new(Vec::new())),
+                reports: Arc::new(Mutex::new(VecDeque::new())),
+                respond: Arc::new(respond),
+            }
+        }
+    }
+
+    impl RawHidDevice for FakeDevice {
+        fn write(&self, report: &[u8]) -> Result<usize> {
+            self.written.lock().unwrap().push(report.to_vec());
+            // Without the report id
+            let replies = (self.respond)(&report[1..]);
+            self.reports.lock().unwrap().extend(replies);
+            Ok(report.len())
+        }
+
+        fn read_timeout(&self, report: &mut [u8], _timeout_millis: i32) -> Result<usize> {
+            match self.reports.lock().unwrap().pop_front() {
+                Some(reply) => {
+                    report[..reply.len()].copy_from_slice(&reply);
+                    Ok(reply.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn ack_settings() -> AckSettings {
+        AckSettings {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+        }
+    }
+
+    fn hello_reply(page: u8, bitmap: [u8; 16], capabilities: u8) -> Vec<u8> {
+        let mut reply = vec![HELLO_COMMAND_ID, b'Q', b'K', PROTOCOL_VERSION, 32, page];
+        reply.extend_from_slice(&bitmap);
+        reply.push(capabilities);
+        reply.resize(REPORT_LENGTH, 0);
+        reply
+    }
+
+    #[test]
+    fn default_report() {
+        let layout = ReportLayout::default();
+
+        let report = layout.report(&[3, 1, 2]);
+
+        assert_eq!(report.len(), 1 + REPORT_LENGTH);
+        assert_eq!(&report[..4], &[0, 3, 1, 2]);
+        assert!(report[4..].iter().all(|b| *b == 0));
+        assert_eq!(layout.message_length(), REPORT_LENGTH);
+    }
+
+    #[test]
+    fn report_with_id_header_and_padding() {
+        let layout = ReportLayout {
+            report_length: 64,
+            report_id: 2,
+            header: vec![0x51, 0x4B],
+            padding: 0xFF,
+        };
+
+        let report = layout.report(&[3, 1]);
+
+        assert_eq!(report.len(), 65);
+        assert_eq!(&report[..5], &[2, 0x51, 0x4B, 3, 1]);
+        assert!(report[5..].iter().all(|b| *b == 0xFF));
+        assert_eq!(layout.message_length(), 62);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let layout = ReportLayout {
+            header: vec![0x51, 0x4B],
+            ..ReportLayout::default()
+        };
+        let report = layout.report(&[3, 1, 2]);
+
+        // Reports of devices without numbered reports are read without the report id
+        let message = layout.message(&report[1..]).unwrap();
+
+        assert_eq!(message.len(), layout.message_length());
+        assert_eq!(&message[..3], &[3, 1, 2]);
+    }
+
+    #[test]
+    fn message_of_numbered_reports() {
+        let layout = ReportLayout {
+            report_id: 2,
+            header: vec![0x51],
+            ..ReportLayout::default()
+        };
+        let report = layout.report(&[3, 1]);
+
+        assert_eq!(&layout.message(&report).unwrap()[..2], &[3, 1]);
+        // Other report ids
+        assert!(layout.message(&[1, 0x51, 3, 1]).is_none());
+    }
+
+    #[test]
+    fn message_of_other_programs() {
+        let layout = ReportLayout {
+            header: vec![0x51, 0x4B],
+            ..ReportLayout::default()
+        };
+
+        assert!(layout.message(&[0x51, 0x00, 3, 1]).is_none());
+        assert!(layout.message(&[0x51]).is_none());
+        assert_eq!(layout.message(&[0x51, 0x4B]), Some(&[][..]));
+    }
+
+    #[test]
+    fn acks_are_routed_to_the_writer() {
+        let device = FakeDevice::new(|message| match message {
+            [ACK_COMMAND_ID, sequence, ..] => vec![vec![5, 42], vec![ACK_COMMAND_ID, *sequence, 1]],
+            _ => Vec::new(),
+        });
+        let stats = DeliveryStats::new();
+        let mut sink = HidEventSink::from_device(device.clone()).with_stats(stats.clone());
+        sink.enable_ack(ack_settings());
+        let (sender, received) = crossbeam_channel::unbounded();
+        sink.start_receiving(sender).unwrap();
+
+        sink.send(&SendData {
+            command_id: 1,
+            data: vec![7],
+        })
+        .unwrap();
+
+        let status = stats.status();
+        assert_eq!((status.acked, status.retried, status.unacked), (1, 0, 0));
+        assert_eq!(device.written.lock().unwrap().len(), 1);
+        // The other reports still reach the engine
+        match received.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Event::Received { command_id, data } => {
+                assert_eq!(command_id, 5);
+                assert_eq!(data[0], 42);
+            }
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[test]
+    fn stale_acks_are_dropped_and_the_command_is_retried() {
+        let attempts = Arc::new(Mutex::new(0));
+        let device = FakeDevice::new(move |message| {
+            let mut attempts = attempts.lock().unwrap();
+            *attempts += 1;
+            match message {
+                // The first attempt gets the late ack of an older command
+                [ACK_COMMAND_ID, sequence, ..] if *attempts == 1 => {
+                    vec![vec![ACK_COMMAND_ID, sequence.wrapping_sub(1), 1]]
+                }
+                [ACK_COMMAND_ID, sequence, ..] => vec![vec![ACK_COMMAND_ID, *sequence, 1]],
+                _ => Vec::new(),
+            }
+        });
+        let stats = DeliveryStats::new();
+        let mut sink = HidEventSink::from_device(device.clone()).with_stats(stats.clone());
+        sink.enable_ack(ack_settings());
+
+        sink.send(&SendData {
+            command_id: 1,
+            data: vec![7],
+        })
+        .unwrap();
+
+        let status = stats.status();
+        assert_eq!((status.acked, status.retried, status.unacked), (1, 1, 0));
+        assert_eq!(device.written.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unacked_commands_are_given_up() {
+        let stats = DeliveryStats::new();
+        let mut sink =
+            HidEventSink::from_device(FakeDevice::new(|_| Vec::new())).with_stats(stats.clone());
+        sink.enable_ack(ack_settings());
+
+        sink.send(&SendData {
+            command_id: 1,
+            data: vec![7],
+        })
+        .unwrap();
+
+        let status = stats.status();
+        assert_eq!((status.acked, status.retried, status.unacked), (0, 2, 1));
+    }
+
+    #[test]
+    fn handshake_reads_the_replies() {
+        let device = FakeDevice::new(|message| match message {
+            [HELLO_COMMAND_ID, ..] => {
+                let mut bitmap = [0; 16];
+                bitmap[0] = 1 << 3;
+                vec![
+                    vec![9, 9],
+                    hello_reply(0, bitmap, CAPABILITY_ACK),
+                    hello_reply(1, [0; 16], CAPABILITY_ACK),
+                ]
+            }
+            _ => Vec::new(),
+        });
+        let mut sink = HidEventSink::from_device(device);
+
+        let info = sink
+            .handshake(Duration::from_secs(1))
+            .unwrap()
+            .expect("the firmware answered");
+
+        assert!(info.is_compatible());
+        assert!(info.handles(3));
+        assert!(!info.handles(4));
+        assert!(info.supports(CAPABILITY_ACK));
+        assert_eq!(sink.max_payload_length(), MAX_PAYLOAD_LENGTH);
+    }
+
+    #[test]
+    fn handshake_without_replies() {
+        let mut sink = HidEventSink::from_device(FakeDevice::new(|_| Vec::new()));
+
+        let info = sink.handshake(Duration::from_millis(50)).unwrap();
+
+        assert!(info.is_none());
+    }
+}
@@ -1,9 +1,45 @@
-use crate::{Error, Event, Result};
+use crate::{
+    BrowserState, Error, Event, MappingProfiles, PomodoroTimer, Result, ShellSessions, TextEncoding,
+};
 use chrono::Duration;
 use crossbeam_channel::{Receiver, Sender};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::{Command, Output};
 
+mod backlight;
+mod browser;
+mod calendar;
+mod clipboard;
+mod file_watch;
+mod input_method;
+mod maildir;
+mod neovim;
+mod network;
+mod pomodoro;
+mod process;
+mod shell;
+mod systemd;
+mod window_state;
+mod window_title;
+mod workspace;
+mod x11;
+
+pub use browser::BrowserOutput;
+pub use calendar::CalendarBuckets;
+pub use clipboard::{ClipboardBackend, ClipboardRule};
+pub use file_watch::FileWatchMode;
+pub use input_method::InputMethodBackend;
+pub use maildir::MaildirBucket;
+pub use network::{NetworkCheck, NetworkValues};
+pub use pomodoro::{PomodoroOutput, PomodoroPhaseValues};
+pub(crate) use process::resolve_uid;
+pub use process::ProcessRule;
+pub use shell::{ShellCommandRule, ShellOutput};
+pub use systemd::{SystemdBus, SystemdTarget, SYSTEMD_STATE_FAILED, SYSTEMD_STATE_OK};
+pub use window_state::WindowStateProperty;
+pub use workspace::WorkspaceBackend;
+
 pub trait EventSource {
     fn events(&self) -> Receiver<Event>;
     fn start(self);
@@ -12,12 +48,89 @@ pub trait EventSource {
 #[derive(Clone)]
 pub enum UserEventSourceKind {
     CurrentProgram {
-        mappings: HashMap<String, u8>,
-        default_value: u8,
+        profiles: MappingProfiles,
+        default_value: Vec<u8>,
         use_lowercase: bool,
     },
     UserDefined {
         command: String,
+        /// When set, the output is sent as text instead of numbers.
+        text: Option<TextEncoding>,
+        /// Most numbers accepted in the output, the longest payload fitting in the reports.
+        max_payload_length: usize,
+    },
+    Workspace {
+        backend: WorkspaceBackend,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+    },
+    WindowState {
+        property: WindowStateProperty,
+    },
+    Network {
+        check: NetworkCheck,
+        values: NetworkValues,
+    },
+    ProcessPresence {
+        rules: Vec<ProcessRule>,
+        default_value: u8,
+    },
+    Calendar {
+        paths: Vec<PathBuf>,
+        buckets: CalendarBuckets,
+    },
+    FileWatch {
+        path: PathBuf,
+        mode: FileWatchMode,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+    },
+    Maildir {
+        folders: Vec<PathBuf>,
+        buckets: Vec<MaildirBucket>,
+        /// Sent when no bucket is reached.
+        default_value: u8,
+    },
+    Backlight {
+        path: PathBuf,
+    },
+    InputMethod {
+        backend: InputMethodBackend,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+    },
+    Systemd {
+        bus: SystemdBus,
+        target: SystemdTarget,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+    },
+    Pomodoro {
+        timer: PomodoroTimer,
+        output: PomodoroOutput,
+    },
+    Clipboard {
+        backend: ClipboardBackend,
+        rules: Vec<ClipboardRule>,
+        default_value: u8,
+    },
+    Neovim {
+        runtime_dir: Option<PathBuf>,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+    },
+    Shell {
+        sessions: ShellSessions,
+        output: ShellOutput,
+        default_value: u8,
+    },
+    Browser {
+        state: BrowserState,
+        output: BrowserOutput,
+        default_value: Vec<u8>,
+    },
+    WindowTitle {
+        encoding: TextEncoding,
     },
 }
 
@@ -54,12 +167,81 @@ impl UserEventSource {
         let kind = source.kind.clone();
         match kind {
             UserEventSourceKind::CurrentProgram {
-                mappings,
+                profiles,
                 default_value,
                 use_lowercase,
-            } => Self::loop_current_program(mappings, default_value, use_lowercase, source, sender),
-            UserEventSourceKind::UserDefined { command } => {
-                Self::loop_user_defined(command, source, sender)
+            } => Self::loop_current_program(profiles, default_value, use_lowercase, source, sender),
+            UserEventSourceKind::UserDefined {
+                command,
+                text,
+                max_payload_length,
+            } => Self::loop_user_defined(command, text, max_payload_length, source, sender),
+            UserEventSourceKind::Workspace {
+                backend,
+                mappings,
+                default_value,
+            } => Self::loop_workspace(backend, mappings, default_value, source, sender),
+            UserEventSourceKind::WindowState { property } => {
+                Self::loop_window_state(property, source, sender)
+            }
+            UserEventSourceKind::Network { check, values } => {
+                Self::loop_network(check, values, source, sender)
+            }
+            UserEventSourceKind::ProcessPresence {
+                rules,
+                default_value,
+            } => Self::loop_process_presence(rules, default_value, source, sender),
+            UserEventSourceKind::Calendar { paths, buckets } => {
+                Self::loop_calendar(paths, buckets, source, sender)
+            }
+            UserEventSourceKind::FileWatch {
+                path,
+                mode,
+                mappings,
+                default_value,
+            } => Self::loop_file_watch(path, mode, mappings, default_value, source, sender),
+            UserEventSourceKind::Maildir {
+                folders,
+                buckets,
+                default_value,
+            } => Self::loop_maildir(folders, buckets, default_value, source, sender),
+            UserEventSourceKind::Backlight { path } => Self::loop_backlight(path, source, sender),
+            UserEventSourceKind::InputMethod {
+                backend,
+                mappings,
+                default_value,
+            } => Self::loop_input_method(backend, mappings, default_value, source, sender),
+            UserEventSourceKind::Systemd {
+                bus,
+                target,
+                mappings,
+                default_value,
+            } => Self::loop_systemd(bus, target, mappings, default_value, source, sender),
+            UserEventSourceKind::Pomodoro { timer, output } => {
+                Self::loop_pomodoro(timer, output, source, sender)
+            }
+            UserEventSourceKind::Clipboard {
+                backend,
+                rules,
+                default_value,
+            } => Self::loop_clipboard(backend, rules, default_value, source, sender),
+            UserEventSourceKind::Neovim {
+                runtime_dir,
+                mappings,
+                default_value,
+            } => Self::loop_neovim(runtime_dir, mappings, default_value, source, sender),
+            UserEventSourceKind::Shell {
+                sessions,
+                output,
+                default_value,
+            } => Self::loop_shell(sessions, output, default_value, source, sender),
+            UserEventSourceKind::Browser {
+                state,
+                output,
+                default_value,
+            } => Self::loop_browser(state, output, default_value, source, sender),
+            UserEventSourceKind::WindowTitle { encoding } => {
+                Self::loop_window_title(encoding, source, sender)
             }
         }
     }
@@ -67,16 +249,16 @@ impl UserEventSource {
 
 impl UserEventSource {
     fn loop_current_program(
-        mappings: HashMap<String, u8>,
-        default_value: u8,
+        profiles: MappingProfiles,
+        default_value: Vec<u8>,
         use_lowercase: bool,
         source: UserEventConfig,
         sender: Sender<Event>,
     ) {
         loop {
             if let Err(e) = Self::step_current_program(
-                &mappings,
-                default_value,
+                &profiles.mappings(),
+                &default_value,
                 use_lowercase,
                 &source,
                 &sender,
@@ -89,8 +271,8 @@ impl UserEventSource {
     }
 
     fn step_current_program(
-        mappings: &HashMap<String, u8>,
-        default_value: u8,
+        mappings: &HashMap<String, Vec<u8>>,
+        default_value: &[u8],
         use_lowercase: bool,
         source: &UserEventConfig,
         sender: &Sender<Event>,
@@ -122,7 +304,7 @@ impl UserEventSource {
                 info!("Found program {program_name}");
                 let event = Event::Send {
                     command_id: source.command_id,
-                    command_data: *command_data,
+                    command_data: command_data.clone(),
                 };
                 let _ = sender.send(event);
                 return Ok(());
@@ -136,7 +318,7 @@ impl UserEventSource {
                 info!("Found program {program_name}");
                 let event = Event::Send {
                     command_id: source.command_id,
-                    command_data: *command_data,
+                    command_data: command_data.clone(),
                 };
                 let _ = sender.send(event);
                 return Ok(());
@@ -146,7 +328,7 @@ impl UserEventSource {
         debug!("Did not find the current program in mappings, sending default value");
         let default_event = Event::Send {
             command_id: source.command_id,
-            command_data: default_value,
+            command_data: default_value.to_vec(),
         };
         let _ = sender.send(default_event);
         Ok(())
@@ -227,9 +409,21 @@ impl UserEventSource {
 }
 
 impl UserEventSource {
-    fn loop_user_defined(command: String, source: UserEventConfig, sender: Sender<Event>) {
+    fn loop_user_defined(
+        command: String,
+        text: Option<TextEncoding>,
+        max_payload_length: usize,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
         loop {
-            if let Err(e) = Self::step_user_defined(&command, &source, &sender) {
+            if let Err(e) = Self::step_user_defined(
+                &command,
+                text.as_ref(),
+                max_payload_length,
+                &source,
+                &sender,
+            ) {
                 error!("error in user defined [command={}]: {:?}", command, e);
             }
 
@@ -239,6 +433,8 @@ impl UserEventSource {
 
     fn step_user_defined(
         command: &str,
+        text: Option<&TextEncoding>,
+        max_payload_length: usize,
         source: &UserEventConfig,
         sender: &Sender<Event>,
     ) -> Result<()> {
@@ -249,16 +445,37 @@ impl UserEventSource {
             .unwrap_or_else(|_| panic!("Failed to execute custom command [{command}]"));
         let value = Self::output_to_string(output)?;
 
-        let output_number = value.parse::<u8>().map_err(|e| {
-            Error::UserConfigExecutionError(format!(
-                "Error parsing output into u8: output={}: {:?}",
-                value, e
-            ))
-        })?;
+        if let Some(encoding) = text {
+            let event = Event::SendText {
+                command_id: source.command_id,
+                text: encoding.encode(&value),
+            };
+            let _ = sender.send(event);
+            return Ok(());
+        }
+
+        // A single number keeps working as before, several numbers send a multi-byte payload
+        let output_numbers = value
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|number| !number.is_empty())
+            .map(|number| number.parse::<u8>())
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|e| {
+                Error::UserConfigExecutionError(format!(
+                    "Error parsing output into u8: output={}: {:?}",
+                    value, e
+                ))
+            })?;
+        if output_numbers.is_empty() || output_numbers.len() > max_payload_length {
+            return Err(Error::UserConfigExecutionError(format!(
+                "Output must contain between 1 and {} numbers: output={}",
+                max_payload_length, value
+            )));
+        }
 
         let event = Event::Send {
             command_id: source.command_id,
-            command_data: output_number,
+            command_data: output_numbers,
         };
         let _ = sender.send(event);
         Ok(())
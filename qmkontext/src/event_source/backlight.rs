@@ -0,0 +1,295 @@
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use inotify::{Inotify, WatchMask};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const INOTIFY_BUFFER_SIZE: usize = 1024;
+
+impl UserEventSource {
+    pub(super) fn loop_backlight(path: PathBuf, source: UserEventConfig, sender: Sender<Event>) {
+        loop {
+            if let Err(e) = Self::watch_backlight(&path, &source, &sender) {
+                error!("error in backlight [path={}]: {:?}", path.display(), e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_backlight(
+        path: &Path,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let device = Self::find_backlight_device(path)?;
+        let max_brightness =
+            Self::read_brightness_file(&mut File::open(device.join("max_brightness"))?)?;
+        if max_brightness == 0 {
+            return Err(Error::IoError(format!(
+                "max_brightness of {} is 0",
+                device.display()
+            )));
+        }
+        info!("Using backlight device {}", device.display());
+
+        // sysfs notifies changes of actual_brightness through poll(), while regular
+        // directories (such as a fake sysfs tree) notify writes through inotify
+        let brightness_path = if device.join("actual_brightness").exists() {
+            device.join("actual_brightness")
+        } else {
+            device.join("brightness")
+        };
+        let mut brightness_file = File::open(&brightness_path)?;
+        let mut inotify = Inotify::init()?;
+        inotify
+            .watches()
+            .add(&device, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+
+        let timeout_ms = source.interval.num_milliseconds().clamp(0, i32::MAX as i64) as i32;
+        let mut buffer = [0u8; INOTIFY_BUFFER_SIZE];
+        let mut last_value = None;
+        loop {
+            let brightness = Self::read_brightness_file(&mut brightness_file)?;
+            let value = Self::scale_brightness(brightness, max_brightness);
+            if last_value != Some(value) {
+                debug!(
+                    "Backlight brightness {}/{} (sending {})",
+                    brightness, max_brightness, value
+                );
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            let mut fds = [
+                libc::pollfd {
+                    fd: inotify.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: brightness_file.as_raw_fd(),
+                    events: libc::POLLPRI | libc::POLLERR,
+                    revents: 0,
+                },
+            ];
+            // SAFETY: fds is a valid array of pollfd that outlives the call
+            let result =
+                unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+            if result < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                // The events themselves do not matter, the brightness is read again
+                match inotify.read_events(&mut buffer) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+                // Writers may replace the file, so it is opened again
+                brightness_file = File::open(&brightness_path)?;
+            }
+        }
+    }
+
+    /// Accepts either a backlight device directory or a directory containing devices,
+    /// such as `/sys/class/backlight`, in which case the first device is used.
+    fn find_backlight_device(path: &Path) -> Result<PathBuf> {
+        if path.join("brightness").exists() {
+            return Ok(path.to_path_buf());
+        }
+
+        let mut devices = std::fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.join("brightness").exists())
+            .collect::<Vec<PathBuf>>();
+        devices.sort();
+        devices.into_iter().next().ok_or_else(|| {
+            Error::IoError(format!(
+                "cannot find a backlight device in {}",
+                path.display()
+            ))
+        })
+    }
+
+    /// Scales the brightness to 0-255, rounding to the nearest value. `max_brightness` is not 0.
+    fn scale_brightness(brightness: u64, max_brightness: u64) -> u8 {
+        let value =
+            (brightness.min(max_brightness) * u8::MAX as u64 + max_brightness / 2) / max_brightness;
+        value as u8
+    }
+
+    fn read_brightness_file(file: &mut File) -> Result<u64> {
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut contents)?;
+        contents
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| Error::IoError(format!("invalid brightness {}: {:?}", contents.trim(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::time::Duration as StdDuration;
+
+    /// Fake sysfs directory, removed when dropped.
+    struct FakeSysfs {
+        path: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "qmkontext-backlight-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+
+        fn add_device(&self, name: &str, brightness: u64, max_brightness: u64) -> PathBuf {
+            let device = self.path.join(name);
+            std::fs::create_dir_all(&device).unwrap();
+            std::fs::write(device.join("brightness"), format!("{brightness}\n")).unwrap();
+            std::fs::write(device.join("max_brightness"), format!("{max_brightness}\n")).unwrap();
+            device
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn source() -> UserEventConfig {
+        UserEventConfig {
+            interval: Duration::milliseconds(100),
+            kind: crate::UserEventSourceKind::Backlight {
+                path: PathBuf::new(),
+            },
+            command_id: 9,
+        }
+    }
+
+    fn next_value(events: &crossbeam_channel::Receiver<Event>) -> u8 {
+        match events.recv_timeout(StdDuration::from_secs(5)).unwrap() {
+            Event::Send {
+                command_id,
+                command_data,
+            } => {
+                assert_eq!(command_id, 9);
+                assert_eq!(command_data.len(), 1);
+                command_data[0]
+            }
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[test]
+    fn no_device() {
+        let sysfs = FakeSysfs::new("none");
+
+        assert!(UserEventSource::find_backlight_device(&sysfs.path).is_err());
+        assert!(UserEventSource::find_backlight_device(&sysfs.path.join("missing")).is_err());
+    }
+
+    #[test]
+    fn one_device() {
+        let sysfs = FakeSysfs::new("one");
+        let device = sysfs.add_device("intel_backlight", 10, 100);
+
+        assert_eq!(
+            UserEventSource::find_backlight_device(&sysfs.path).unwrap(),
+            device
+        );
+        // The device directory itself
+        assert_eq!(
+            UserEventSource::find_backlight_device(&device).unwrap(),
+            device
+        );
+    }
+
+    #[test]
+    fn several_devices() {
+        let sysfs = FakeSysfs::new("several");
+        sysfs.add_device("nvidia_0", 10, 100);
+        let first = sysfs.add_device("acpi_video0", 10, 100);
+        // Not a backlight device
+        std::fs::create_dir_all(sysfs.path.join("aaa")).unwrap();
+
+        assert_eq!(
+            UserEventSource::find_backlight_device(&sysfs.path).unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(UserEventSource::scale_brightness(0, 100), 0);
+        assert_eq!(UserEventSource::scale_brightness(50, 100), 128);
+        assert_eq!(UserEventSource::scale_brightness(100, 100), 255);
+        assert_eq!(UserEventSource::scale_brightness(255, 255), 255);
+        assert_eq!(UserEventSource::scale_brightness(7, 1), 255);
+        // Brightness above the max
+        assert_eq!(UserEventSource::scale_brightness(150, 100), 255);
+    }
+
+    #[test]
+    fn rounding_at_the_ends() {
+        let max_brightness = 120000;
+        // Less than half a step rounds to 0, more than half a step to 1
+        assert_eq!(UserEventSource::scale_brightness(235, max_brightness), 0);
+        assert_eq!(UserEventSource::scale_brightness(236, max_brightness), 1);
+        // Only the max brightness rounds to 255
+        assert_eq!(
+            UserEventSource::scale_brightness(max_brightness - 236, max_brightness),
+            254
+        );
+        assert_eq!(
+            UserEventSource::scale_brightness(max_brightness - 235, max_brightness),
+            255
+        );
+    }
+
+    #[test]
+    fn zero_max_brightness() {
+        let sysfs = FakeSysfs::new("zero");
+        sysfs.add_device("intel_backlight", 0, 0);
+        let (sender, _events) = crossbeam_channel::unbounded();
+
+        assert!(UserEventSource::watch_backlight(&sysfs.path, &source(), &sender).is_err());
+    }
+
+    #[test]
+    fn sends_the_changes() {
+        let sysfs = FakeSysfs::new("changes");
+        let device = sysfs.add_device("intel_backlight", 40, 80);
+        std::fs::write(device.join("actual_brightness"), "20\n").unwrap();
+        let (sender, events) = crossbeam_channel::unbounded();
+        let path = sysfs.path.clone();
+        std::thread::spawn(move || UserEventSource::watch_backlight(&path, &source(), &sender));
+
+        // actual_brightness is preferred
+        assert_eq!(next_value(&events), 64);
+
+        std::fs::write(device.join("actual_brightness"), "80\n").unwrap();
+        assert_eq!(next_value(&events), 255);
+    }
+}
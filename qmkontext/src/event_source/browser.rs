@@ -0,0 +1,68 @@
+use crate::{BrowserState, BrowserTab, Event, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub enum BrowserOutput {
+    /// Sends the value mapped to the domain of the active tab while the browser is focused.
+    /// A mapping for `example.com` also matches its subdomains, and the longest one wins.
+    Domain { mappings: HashMap<String, Vec<u8>> },
+    /// Sends whether the active tab is playing audio.
+    Audible { audible_value: u8, silent_value: u8 },
+}
+
+impl UserEventSource {
+    pub(super) fn loop_browser(
+        state: BrowserState,
+        output: BrowserOutput,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let mut last_value = None;
+        loop {
+            let value = match state.tab() {
+                Some(tab) => {
+                    Self::browser_value(&tab, &output).unwrap_or_else(|| default_value.clone())
+                }
+                None => default_value.clone(),
+            };
+            if last_value.as_ref() != Some(&value) {
+                info!("Browser tab changed (sending {:?})", value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: value.clone(),
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn browser_value(tab: &BrowserTab, output: &BrowserOutput) -> Option<Vec<u8>> {
+        match output {
+            BrowserOutput::Domain { .. } if !tab.focused => None,
+            BrowserOutput::Domain { mappings } => {
+                let domain = tab.domain.trim_end_matches('.').to_lowercase();
+                mappings
+                    .iter()
+                    .filter(|(mapped, _)| {
+                        let mapped = mapped.to_lowercase();
+                        domain == mapped || domain.ends_with(&format!(".{mapped}"))
+                    })
+                    .max_by_key(|(mapped, _)| mapped.len())
+                    .map(|(_, value)| value.clone())
+            }
+            BrowserOutput::Audible {
+                audible_value,
+                silent_value,
+            } => Some(vec![if tab.audible {
+                *audible_value
+            } else {
+                *silent_value
+            }]),
+        }
+    }
+}
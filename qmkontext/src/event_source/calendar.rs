@@ -0,0 +1,986 @@
+use crate::{Event, Result, UserEventConfig, UserEventSource};
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use crossbeam_channel::Sender;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Upper bound of recurrence periods expanded per event, to protect against malformed rules.
+const MAX_RECURRENCE_PERIODS: u32 = 100_000;
+
+/// Values sent depending on how close the next meeting is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CalendarBuckets {
+    pub free_value: u8,
+    pub in_meeting_value: u8,
+    pub soon_value: u8,
+    pub soon_minutes: u16,
+    pub upcoming_value: u8,
+    pub upcoming_minutes: u16,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Clone, Debug)]
+struct RecurrenceRule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    /// Floating and date-only values are in the timezone of the event.
+    until: Option<IcsDateTime>,
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+}
+
+/// Timezone of the date-times, taken from their TZID.
+#[derive(Clone, Debug)]
+enum IcsZone {
+    Utc,
+    /// Floating times, and times with an unknown TZID.
+    Local,
+    Named(Tz),
+    /// Timezone defined in the file, for TZIDs missing from the tz database (such as the Windows
+    /// names written by Outlook).
+    Defined(Arc<VTimezone>),
+}
+
+impl IcsZone {
+    fn to_utc(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            IcsZone::Utc => Some(Utc.from_utc_datetime(&naive)),
+            IcsZone::Local => Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|d| d.with_timezone(&Utc)),
+            IcsZone::Named(tz) => tz
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|d| d.with_timezone(&Utc)),
+            IcsZone::Defined(timezone) => {
+                let offset = Duration::seconds(timezone.offset_at(naive) as i64);
+                Some(Utc.from_utc_datetime(&(naive - offset)))
+            }
+        }
+    }
+}
+
+/// Date-time as written in the file, along with its timezone.
+#[derive(Clone, Debug)]
+struct IcsDateTime {
+    naive: NaiveDateTime,
+    zone: IcsZone,
+    is_date: bool,
+}
+
+impl IcsDateTime {
+    fn to_utc(&self) -> Option<DateTime<Utc>> {
+        self.zone.to_utc(self.naive)
+    }
+}
+
+/// STANDARD or DAYLIGHT component of a VTIMEZONE.
+#[derive(Clone, Debug, Default)]
+struct Observance {
+    /// Local time of the first onset.
+    start: NaiveDateTime,
+    /// Offset from UTC in seconds once it starts.
+    offset: i32,
+    rrule: Option<RecurrenceRule>,
+}
+
+impl Observance {
+    /// Latest onset at or before `naive`. VTIMEZONE rules are yearly, so the onsets of the
+    /// previous year are enough.
+    fn last_onset(&self, naive: NaiveDateTime) -> Option<NaiveDateTime> {
+        if self.start > naive {
+            return None;
+        }
+        let rrule = match &self.rrule {
+            Some(rrule) if rrule.frequency == Frequency::Yearly => rrule,
+            _ => return Some(self.start),
+        };
+        let until = rrule.until.as_ref().map(|u| u.naive);
+        [naive.year() - 1, naive.year()]
+            .into_iter()
+            .flat_map(|year| UserEventSource::yearly_dates(year, self.start.date(), rrule))
+            .map(|date| date.and_time(self.start.time()))
+            .filter(|onset| *onset >= self.start && *onset <= naive)
+            .filter(|onset| until.is_none_or(|until| *onset <= until))
+            .max()
+    }
+}
+
+#[derive(Debug, Default)]
+struct VTimezone {
+    observances: Vec<Observance>,
+}
+
+impl VTimezone {
+    /// Offset in effect at a local time, from the observance with the latest onset before it.
+    fn offset_at(&self, naive: NaiveDateTime) -> i32 {
+        self.observances
+            .iter()
+            .filter_map(|o| o.last_onset(naive).map(|onset| (onset, o.offset)))
+            .max_by_key(|(onset, _)| *onset)
+            .or_else(|| {
+                // Times before every onset use the earliest observance
+                self.observances
+                    .iter()
+                    .map(|o| (o.start, o.offset))
+                    .min_by_key(|(start, _)| *start)
+            })
+            .map(|(_, offset)| offset)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct CalendarEvent {
+    uid: String,
+    start: Option<IcsDateTime>,
+    end: Option<IcsDateTime>,
+    duration: Option<Duration>,
+    rrule: Option<RecurrenceRule>,
+    exdates: Vec<DateTime<Utc>>,
+    recurrence_id: Option<DateTime<Utc>>,
+    skip: bool,
+}
+
+/// `(NAME, value)` of the parameters of a property.
+type IcsParams = Vec<(String, String)>;
+
+struct CachedCalendarFile {
+    modified: SystemTime,
+    events: Vec<CalendarEvent>,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_calendar(
+        paths: Vec<PathBuf>,
+        buckets: CalendarBuckets,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let mut cache = HashMap::new();
+        loop {
+            if let Err(e) = Self::step_calendar(&paths, &buckets, &mut cache, &source, &sender) {
+                error!("error in calendar : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn step_calendar(
+        paths: &[PathBuf],
+        buckets: &CalendarBuckets,
+        cache: &mut HashMap<PathBuf, CachedCalendarFile>,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let mut files = Vec::new();
+        for path in paths {
+            Self::collect_ics_files(path, &mut files)?;
+        }
+        cache.retain(|path, _| files.contains(path));
+
+        for file in &files {
+            // Files may be removed or replaced while syncing, so they are read on the next step
+            if let Err(e) = Self::load_calendar_file(file, cache) {
+                warn!("Skipping calendar file {}: {:?}", file.display(), e);
+                cache.remove(file);
+            }
+        }
+
+        let events = cache
+            .values()
+            .flat_map(|c| c.events.iter())
+            .collect::<Vec<&CalendarEvent>>();
+        let command_data = Self::calendar_value(&events, buckets, Utc::now());
+        let event = Event::Send {
+            command_id: source.command_id,
+            command_data: vec![command_data],
+        };
+        let _ = sender.send(event);
+        Ok(())
+    }
+
+    fn load_calendar_file(
+        file: &Path,
+        cache: &mut HashMap<PathBuf, CachedCalendarFile>,
+    ) -> Result<()> {
+        let modified = std::fs::metadata(file)?.modified()?;
+        let is_fresh = cache
+            .get(file)
+            .map(|c| c.modified == modified)
+            .unwrap_or(false);
+        if !is_fresh {
+            let contents = std::fs::read_to_string(file)?;
+            let events = Self::parse_ics(&contents);
+            cache.insert(file.to_path_buf(), CachedCalendarFile { modified, events });
+        }
+        Ok(())
+    }
+
+    fn collect_ics_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                Self::collect_ics_files(&entry?.path(), files)?;
+            }
+        } else if path.extension().map(|e| e == "ics").unwrap_or(false) {
+            files.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn calendar_value(
+        events: &[&CalendarEvent],
+        buckets: &CalendarBuckets,
+        now: DateTime<Utc>,
+    ) -> u8 {
+        let horizon =
+            now + Duration::minutes(buckets.upcoming_minutes.max(buckets.soon_minutes) as i64);
+
+        // Occurrences moved or cancelled through RECURRENCE-ID replace the original ones
+        let overrides = events
+            .iter()
+            .filter_map(|e| e.recurrence_id.map(|r| (e.uid.as_str(), r)))
+            .collect::<HashSet<(&str, DateTime<Utc>)>>();
+
+        let mut in_meeting = false;
+        let mut next_start: Option<DateTime<Utc>> = None;
+        for event in events {
+            if event.skip {
+                continue;
+            }
+            let (start, duration) = match Self::event_start_and_duration(event) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let starts = match (&event.rrule, event.recurrence_id) {
+                (Some(rrule), None) => {
+                    Self::expand_recurrence(&start, rrule, now - duration, horizon)
+                        .into_iter()
+                        .filter(|s| !event.exdates.contains(s))
+                        .filter(|s| !overrides.contains(&(event.uid.as_str(), *s)))
+                        .collect()
+                }
+                _ => start.to_utc().into_iter().collect::<Vec<DateTime<Utc>>>(),
+            };
+            for occurrence_start in starts {
+                if occurrence_start <= now && now < occurrence_start + duration {
+                    in_meeting = true;
+                } else if occurrence_start > now {
+                    next_start = Some(match next_start {
+                        Some(n) => n.min(occurrence_start),
+                        None => occurrence_start,
+                    });
+                }
+            }
+        }
+
+        if in_meeting {
+            debug!("Currently in a meeting");
+            return buckets.in_meeting_value;
+        }
+        let minutes_left = match next_start {
+            Some(start) => (start - now).num_minutes(),
+            None => return buckets.free_value,
+        };
+        debug!("Next meeting starts in {} minutes", minutes_left);
+        if minutes_left < buckets.soon_minutes as i64 {
+            buckets.soon_value
+        } else if minutes_left < buckets.upcoming_minutes as i64 {
+            buckets.upcoming_value
+        } else {
+            buckets.free_value
+        }
+    }
+
+    fn event_start_and_duration(event: &CalendarEvent) -> Option<(IcsDateTime, Duration)> {
+        let start = event.start.clone()?;
+        // All-day events do not count as meetings
+        if start.is_date {
+            return None;
+        }
+        let duration = match (&event.end, event.duration) {
+            (Some(end), _) => end.to_utc()? - start.to_utc()?,
+            (None, Some(duration)) => duration,
+            (None, None) => Duration::zero(),
+        };
+        Some((start, duration))
+    }
+
+    /// Expands the occurrences of a recurring event that start before `until`.
+    /// Occurrences are computed on wall-clock time, so they keep their hour across DST changes.
+    fn expand_recurrence(
+        start: &IcsDateTime,
+        rrule: &RecurrenceRule,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        let first = start.naive;
+        let time = first.time();
+        let interval = rrule.interval.max(1);
+        let last = rrule.until.as_ref().and_then(|until| match until.zone {
+            IcsZone::Utc => until.to_utc(),
+            _ if until.is_date => {
+                // A date-only UNTIL includes the whole day
+                start
+                    .zone
+                    .to_utc(until.naive + Duration::days(1) - Duration::seconds(1))
+            }
+            _ => start.zone.to_utc(until.naive),
+        });
+        let mut occurrences = Vec::new();
+        let mut emitted = 0;
+
+        for period in 0..MAX_RECURRENCE_PERIODS {
+            let step = period * interval;
+            let mut dates = match rrule.frequency {
+                Frequency::Daily => vec![first.date() + Duration::days(step as i64)],
+                Frequency::Weekly => {
+                    let week_start = first.date()
+                        - Duration::days(first.weekday().num_days_from_monday() as i64)
+                        + Duration::weeks(step as i64);
+                    if rrule.by_day.is_empty() {
+                        vec![
+                            week_start
+                                + Duration::days(first.weekday().num_days_from_monday() as i64),
+                        ]
+                    } else {
+                        rrule
+                            .by_day
+                            .iter()
+                            .map(|(_, day)| {
+                                week_start + Duration::days(day.num_days_from_monday() as i64)
+                            })
+                            .collect()
+                    }
+                }
+                Frequency::Monthly => {
+                    let months = first.month0() as i64 + step as i64;
+                    let year = first.year() + (months / 12) as i32;
+                    let month = (months % 12) as u32 + 1;
+                    Self::monthly_dates(year, month, first.day(), rrule)
+                }
+                Frequency::Yearly => {
+                    Self::yearly_dates(first.year() + step as i32, first.date(), rrule)
+                }
+            };
+            dates.sort();
+            dates.dedup();
+
+            for date in dates {
+                let occurrence = date.and_time(time);
+                if occurrence < first {
+                    continue;
+                }
+                if rrule.count.map(|c| emitted >= c).unwrap_or(false) {
+                    return occurrences;
+                }
+                let occurrence_utc = match start.zone.to_utc(occurrence) {
+                    Some(o) => o,
+                    None => continue,
+                };
+                if occurrence_utc > until || last.is_some_and(|last| occurrence_utc > last) {
+                    return occurrences;
+                }
+                emitted += 1;
+                if occurrence_utc >= from {
+                    occurrences.push(occurrence_utc);
+                }
+            }
+        }
+        occurrences
+    }
+
+    /// Dates of a year, in the months of `BYMONTH` or the month of the first occurrence.
+    fn yearly_dates(year: i32, first: NaiveDate, rrule: &RecurrenceRule) -> Vec<NaiveDate> {
+        if rrule.by_month.is_empty() {
+            return NaiveDate::from_ymd_opt(year, first.month(), first.day())
+                .into_iter()
+                .collect();
+        }
+        rrule
+            .by_month
+            .iter()
+            .flat_map(|month| Self::monthly_dates(year, *month, first.day(), rrule))
+            .collect()
+    }
+
+    fn monthly_dates(
+        year: i32,
+        month: u32,
+        default_day: u32,
+        rrule: &RecurrenceRule,
+    ) -> Vec<NaiveDate> {
+        let days_in_month = (28..=31)
+            .rev()
+            .find(|d| NaiveDate::from_ymd_opt(year, month, *d).is_some())
+            .unwrap_or(28) as i32;
+
+        if !rrule.by_month_day.is_empty() {
+            return rrule
+                .by_month_day
+                .iter()
+                .map(|d| if *d < 0 { days_in_month + d + 1 } else { *d })
+                .filter_map(|d| NaiveDate::from_ymd_opt(year, month, d as u32))
+                .collect();
+        }
+
+        if !rrule.by_day.is_empty() {
+            let mut dates = Vec::new();
+            for (ordinal, weekday) in &rrule.by_day {
+                let matching = (1..=days_in_month as u32)
+                    .filter_map(|d| NaiveDate::from_ymd_opt(year, month, d))
+                    .filter(|d| d.weekday() == *weekday)
+                    .collect::<Vec<NaiveDate>>();
+                match ordinal {
+                    Some(n) if *n > 0 => dates.extend(matching.get(*n as usize - 1)),
+                    Some(n) if *n < 0 => {
+                        let index = matching.len() as i32 + n;
+                        if index >= 0 {
+                            dates.push(matching[index as usize]);
+                        }
+                    }
+                    _ => dates.extend(matching),
+                }
+            }
+            return dates;
+        }
+
+        NaiveDate::from_ymd_opt(year, month, default_day)
+            .into_iter()
+            .collect()
+    }
+}
+
+impl UserEventSource {
+    fn parse_ics(contents: &str) -> Vec<CalendarEvent> {
+        // Long lines are folded by starting the next line with a space or a tab
+        let mut lines: Vec<String> = Vec::new();
+        for line in contents.lines() {
+            match (
+                line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+                lines.last_mut(),
+            ) {
+                (Some(continuation), Some(last)) => last.push_str(continuation),
+                _ => lines.push(line.to_string()),
+            }
+        }
+
+        let timezones = Self::parse_vtimezones(&lines);
+        let mut unknown_timezones = HashSet::new();
+        let mut events = Vec::new();
+        let mut components: Vec<String> = Vec::new();
+        let mut current = CalendarEvent::default();
+        for line in lines {
+            let (name, params, value) = match Self::split_ics_line(&line) {
+                Some(l) => l,
+                None => continue,
+            };
+            match name.as_str() {
+                "BEGIN" => {
+                    if value == "VEVENT" {
+                        current = CalendarEvent::default();
+                    }
+                    components.push(value.to_string());
+                    continue;
+                }
+                "END" => {
+                    if components.pop().as_deref() == Some("VEVENT") {
+                        events.push(std::mem::take(&mut current));
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            // Skip properties of nested components such as VALARM
+            if components.last().map(|c| c.as_str()) != Some("VEVENT") {
+                continue;
+            }
+
+            let is_date = params
+                .iter()
+                .any(|(key, value)| key == "VALUE" && value.eq_ignore_ascii_case("DATE"));
+            let zone = Self::ics_zone(&params, &timezones, &mut unknown_timezones);
+            match name.as_str() {
+                "UID" => current.uid = value.to_string(),
+                "DTSTART" => current.start = Self::parse_ics_datetime(value, is_date, &zone),
+                "DTEND" => current.end = Self::parse_ics_datetime(value, is_date, &zone),
+                "DURATION" => current.duration = Self::parse_ics_duration(value),
+                "RRULE" => current.rrule = Self::parse_rrule(value),
+                "EXDATE" => current.exdates.extend(
+                    value
+                        .split(',')
+                        .filter_map(|v| Self::parse_ics_datetime(v, is_date, &zone))
+                        .filter_map(|d| d.to_utc()),
+                ),
+                "RECURRENCE-ID" => {
+                    current.recurrence_id =
+                        Self::parse_ics_datetime(value, is_date, &zone).and_then(|d| d.to_utc())
+                }
+                "STATUS" => current.skip |= value == "CANCELLED",
+                "TRANSP" => current.skip |= value == "TRANSPARENT",
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Parses the VTIMEZONE components of the file, by TZID.
+    fn parse_vtimezones(lines: &[String]) -> HashMap<String, Arc<VTimezone>> {
+        let mut timezones = HashMap::new();
+        let mut tzid = None;
+        let mut timezone = VTimezone::default();
+        let mut observance: Option<Observance> = None;
+        for line in lines {
+            let (name, _, value) = match Self::split_ics_line(line) {
+                Some(l) => l,
+                None => continue,
+            };
+            match (name.as_str(), value) {
+                ("BEGIN", "VTIMEZONE") => {
+                    tzid = None;
+                    timezone = VTimezone::default();
+                }
+                ("BEGIN", "STANDARD") | ("BEGIN", "DAYLIGHT") => {
+                    observance = Some(Observance::default())
+                }
+                ("END", "STANDARD") | ("END", "DAYLIGHT") => {
+                    timezone.observances.extend(observance.take())
+                }
+                ("END", "VTIMEZONE") => {
+                    if let Some(tzid) = tzid.take() {
+                        timezones.insert(tzid, Arc::new(std::mem::take(&mut timezone)));
+                    }
+                }
+                ("TZID", tz) => tzid = Some(tz.to_string()),
+                (property, value) => {
+                    let observance = match observance.as_mut() {
+                        Some(o) => o,
+                        None => continue,
+                    };
+                    match property {
+                        "DTSTART" => {
+                            if let Some(start) =
+                                Self::parse_ics_datetime(value, false, &IcsZone::Local)
+                            {
+                                observance.start = start.naive;
+                            }
+                        }
+                        "TZOFFSETTO" => {
+                            observance.offset = Self::parse_ics_offset(value).unwrap_or_default()
+                        }
+                        "RRULE" => observance.rrule = Self::parse_rrule(value),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        timezones
+    }
+
+    /// Returns the timezone named by the TZID parameter, if any.
+    fn ics_zone(
+        params: &[(String, String)],
+        timezones: &HashMap<String, Arc<VTimezone>>,
+        unknown_timezones: &mut HashSet<String>,
+    ) -> IcsZone {
+        let tzid = match params.iter().find(|(key, _)| key == "TZID") {
+            Some((_, tzid)) => tzid.trim_matches('"'),
+            None => return IcsZone::Local,
+        };
+        // Some clients prefix the names of the tz database, as in /mozilla.org/20050126_1/Europe/Berlin
+        let named = std::iter::once(tzid)
+            .chain(tzid.match_indices('/').map(|(i, _)| &tzid[i + 1..]))
+            .find_map(|name| name.parse::<Tz>().ok());
+        if let Some(tz) = named {
+            return IcsZone::Named(tz);
+        }
+        if let Some(timezone) = timezones.get(tzid) {
+            return IcsZone::Defined(timezone.clone());
+        }
+        if unknown_timezones.insert(tzid.to_string()) {
+            warn!("Unknown calendar timezone {tzid}, using the local timezone");
+        }
+        IcsZone::Local
+    }
+
+    /// Splits `NAME;PARAM=A;PARAM=B:VALUE` into its parts. Names are uppercased, as they are case
+    /// insensitive, but parameter values are kept as they are.
+    fn split_ics_line(line: &str) -> Option<(String, IcsParams, &str)> {
+        let mut in_quotes = false;
+        let separator = line.char_indices().find(|(_, c)| {
+            if *c == '"' {
+                in_quotes = !in_quotes;
+            }
+            *c == ':' && !in_quotes
+        })?;
+        let (head, value) = (&line[..separator.0], &line[separator.0 + 1..]);
+        let mut parts = head.split(';');
+        let name = parts.next()?.to_uppercase();
+        let params = parts
+            .map(|p| match p.split_once('=') {
+                Some((key, value)) => (key.to_uppercase(), value.to_string()),
+                None => (p.to_uppercase(), String::new()),
+            })
+            .collect();
+        Some((name, params, value.trim()))
+    }
+
+    /// Values ending with `Z` are in UTC, the others in `zone`.
+    fn parse_ics_datetime(value: &str, is_date: bool, zone: &IcsZone) -> Option<IcsDateTime> {
+        let value = value.trim();
+        if is_date || value.len() == 8 {
+            let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+            return Some(IcsDateTime {
+                naive: date.and_hms_opt(0, 0, 0)?,
+                zone: zone.clone(),
+                is_date: true,
+            });
+        }
+
+        let zone = if value.ends_with('Z') {
+            IcsZone::Utc
+        } else {
+            zone.clone()
+        };
+        let naive =
+            NaiveDateTime::parse_from_str(value.trim_end_matches('Z'), "%Y%m%dT%H%M%S").ok()?;
+        Some(IcsDateTime {
+            naive,
+            zone,
+            is_date: false,
+        })
+    }
+
+    /// Parses UTC offsets such as `+0100`, `-0500` or `+053000`, in seconds.
+    fn parse_ics_offset(value: &str) -> Option<i32> {
+        let (sign, digits) = match value.split_at_checked(1)? {
+            ("+", digits) => (1, digits),
+            ("-", digits) => (-1, digits),
+            _ => return None,
+        };
+        if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        let hours: i32 = digits[0..2].parse().ok()?;
+        let minutes: i32 = digits[2..4].parse().ok()?;
+        let seconds: i32 = digits.get(4..6).map_or(Ok(0), str::parse).ok()?;
+        Some(sign * (hours * 3600 + minutes * 60 + seconds))
+    }
+
+    /// Parses durations such as `PT1H30M`, `P1D` or `-PT15M`.
+    fn parse_ics_duration(value: &str) -> Option<Duration> {
+        let (negative, value) = match value.strip_prefix('-') {
+            Some(v) => (true, v),
+            None => (false, value.trim_start_matches('+')),
+        };
+        let value = value.strip_prefix('P')?;
+
+        let mut total = Duration::zero();
+        let mut number = String::new();
+        for c in value.chars() {
+            match c {
+                '0'..='9' => number.push(c),
+                'T' => {}
+                unit => {
+                    let amount = number.parse::<i64>().ok()?;
+                    number.clear();
+                    total = total
+                        + match unit {
+                            'W' => Duration::weeks(amount),
+                            'D' => Duration::days(amount),
+                            'H' => Duration::hours(amount),
+                            'M' => Duration::minutes(amount),
+                            'S' => Duration::seconds(amount),
+                            _ => return None,
+                        };
+                }
+            }
+        }
+        Some(if negative { -total } else { total })
+    }
+
+    fn parse_rrule(value: &str) -> Option<RecurrenceRule> {
+        let mut rule = RecurrenceRule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+        };
+        let mut frequency = None;
+        for part in value.split(';') {
+            let (key, value) = match part.split_once('=') {
+                Some(p) => p,
+                None => continue,
+            };
+            match key.to_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = match value.to_uppercase().as_str() {
+                        "DAILY" => Some(Frequency::Daily),
+                        "WEEKLY" => Some(Frequency::Weekly),
+                        "MONTHLY" => Some(Frequency::Monthly),
+                        "YEARLY" => Some(Frequency::Yearly),
+                        other => {
+                            warn!("Unsupported calendar recurrence frequency {other}");
+                            None
+                        }
+                    }
+                }
+                "INTERVAL" => rule.interval = value.parse().ok()?,
+                "COUNT" => rule.count = value.parse().ok(),
+                "UNTIL" => rule.until = Self::parse_ics_datetime(value, false, &IcsZone::Local),
+                "BYDAY" => rule.by_day = value.split(',').filter_map(Self::parse_by_day).collect(),
+                "BYMONTHDAY" => {
+                    rule.by_month_day = value.split(',').filter_map(|d| d.parse().ok()).collect()
+                }
+                "BYMONTH" => {
+                    rule.by_month = value
+                        .split(',')
+                        .filter_map(|m| m.parse().ok())
+                        .filter(|m| (1..=12).contains(m))
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        rule.frequency = frequency?;
+        Some(rule)
+    }
+
+    /// Parses `BYDAY` entries such as `MO`, `2TU` or `-1FR`.
+    fn parse_by_day(value: &str) -> Option<(Option<i32>, Weekday)> {
+        let value = value.trim().to_uppercase();
+        if value.len() < 2 {
+            return None;
+        }
+        let (ordinal, day) = value.split_at(value.len() - 2);
+        let weekday = match day {
+            "MO" => Weekday::Mon,
+            "TU" => Weekday::Tue,
+            "WE" => Weekday::Wed,
+            "TH" => Weekday::Thu,
+            "FR" => Weekday::Fri,
+            "SA" => Weekday::Sat,
+            "SU" => Weekday::Sun,
+            _ => return None,
+        };
+        let ordinal = if ordinal.is_empty() {
+            None
+        } else {
+            Some(ordinal.trim_start_matches('+').parse::<i32>().ok()?)
+        };
+        Some((ordinal, weekday))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UserEventSourceKind;
+
+    const BUCKETS: CalendarBuckets = CalendarBuckets {
+        free_value: 0,
+        in_meeting_value: 1,
+        soon_value: 2,
+        soon_minutes: 5,
+        upcoming_value: 3,
+        upcoming_minutes: 15,
+    };
+
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/calendar")
+            .join(name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap();
+        Utc.from_utc_datetime(&naive)
+    }
+
+    /// Starts of the occurrences of the first event of the fixture between `from` and `until`.
+    fn occurrences(name: &str, from: &str, until: &str) -> Vec<DateTime<Utc>> {
+        let events = UserEventSource::parse_ics(&fixture(name));
+        let (start, _) = UserEventSource::event_start_and_duration(&events[0]).unwrap();
+        let rrule = events[0].rrule.as_ref().unwrap();
+        UserEventSource::expand_recurrence(&start, rrule, utc(from), utc(until))
+    }
+
+    fn value_at(name: &str, now: &str) -> u8 {
+        let events = UserEventSource::parse_ics(&fixture(name));
+        let events = events.iter().collect::<Vec<&CalendarEvent>>();
+        UserEventSource::calendar_value(&events, &BUCKETS, utc(now))
+    }
+
+    #[test]
+    fn weekly_by_day() {
+        assert_eq!(
+            occurrences("byday.ics", "2024-01-01 00:00", "2024-01-14 00:00"),
+            vec![
+                utc("2024-01-02 09:00"),
+                utc("2024-01-04 09:00"),
+                utc("2024-01-09 09:00"),
+                utc("2024-01-11 09:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn monthly_by_day_from_the_end() {
+        assert_eq!(
+            occurrences("monthly_byday.ics", "2024-01-01 00:00", "2024-04-01 00:00"),
+            vec![
+                utc("2024-01-26 16:00"),
+                utc("2024-02-23 16:00"),
+                utc("2024-03-29 16:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn monthly_by_month_day() {
+        assert_eq!(
+            occurrences("bymonthday.ics", "2024-01-01 00:00", "2024-03-20 00:00"),
+            vec![
+                utc("2024-01-15 10:00"),
+                utc("2024-01-31 10:00"),
+                utc("2024-02-15 10:00"),
+                utc("2024-02-29 10:00"),
+                utc("2024-03-15 10:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn count_limits_the_occurrences() {
+        assert_eq!(
+            occurrences("count.ics", "2024-01-01 00:00", "2025-01-01 00:00"),
+            vec![
+                utc("2024-01-01 09:00"),
+                utc("2024-01-02 09:00"),
+                utc("2024-01-03 09:00"),
+            ]
+        );
+        // Occurrences before the range still count
+        assert_eq!(
+            occurrences("count.ics", "2024-01-02 12:00", "2025-01-01 00:00"),
+            vec![utc("2024-01-03 09:00")]
+        );
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        assert_eq!(
+            occurrences("until.ics", "2024-01-01 00:00", "2025-01-01 00:00"),
+            vec![
+                utc("2024-01-01 09:00"),
+                utc("2024-01-03 09:00"),
+                utc("2024-01-05 09:00"),
+                utc("2024-01-07 09:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn exdate_removes_occurrences() {
+        assert_eq!(value_at("exdate.ics", "2024-01-01 09:10"), 1);
+        assert_eq!(value_at("exdate.ics", "2024-01-02 08:58"), 0);
+        assert_eq!(value_at("exdate.ics", "2024-01-03 09:10"), 0);
+        assert_eq!(value_at("exdate.ics", "2024-01-04 08:58"), 2);
+    }
+
+    #[test]
+    fn recurrence_id_moves_and_cancels_occurrences() {
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-01 09:10"), 1);
+        // Moved to 14:00
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-08 09:10"), 0);
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-08 13:50"), 3);
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-08 14:10"), 1);
+        // Cancelled
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-15 09:10"), 0);
+        assert_eq!(value_at("recurrence_id.ics", "2024-01-22 09:10"), 1);
+    }
+
+    #[test]
+    fn tzid_from_the_tz_database() {
+        // New York switches to daylight saving time on 2024-03-10
+        assert_eq!(
+            occurrences("tzid.ics", "2024-01-01 00:00", "2025-01-01 00:00"),
+            vec![
+                utc("2024-03-06 14:00"),
+                utc("2024-03-13 13:00"),
+                utc("2024-03-20 13:00"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tzid_from_vtimezone() {
+        // Daylight saving time starts on the last Sunday of March, 2024-03-31
+        assert_eq!(
+            occurrences("vtimezone.ics", "2024-01-01 00:00", "2025-01-01 00:00"),
+            vec![utc("2024-03-27 08:00"), utc("2024-04-03 07:00")]
+        );
+        let events = UserEventSource::parse_ics(&fixture("vtimezone.ics"));
+        assert_eq!(
+            events[0].end.as_ref().and_then(|e| e.to_utc()),
+            Some(utc("2024-03-27 09:00"))
+        );
+    }
+
+    #[test]
+    fn unknown_tzid_is_local_time() {
+        let events = UserEventSource::parse_ics(
+            "BEGIN:VEVENT\r\nUID:unknown\r\nDTSTART;TZID=Nowhere/Unknown:20240110T090000\r\nEND:VEVENT\r\n",
+        );
+        let start = events[0].start.as_ref().unwrap();
+        let local = NaiveDate::from_ymd_opt(2024, 1, 10)
+            .and_then(|d| d.and_hms_opt(9, 0, 0))
+            .and_then(|d| Local.from_local_datetime(&d).earliest())
+            .map(|d| d.with_timezone(&Utc));
+        assert_eq!(start.to_utc(), local);
+    }
+
+    #[test]
+    fn vanished_files_are_skipped() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/calendar");
+        let paths = vec![dir.join("count.ics"), dir.join("missing.ics")];
+        let source = UserEventConfig {
+            interval: Duration::seconds(30),
+            kind: UserEventSourceKind::Calendar {
+                paths: paths.clone(),
+                buckets: BUCKETS,
+            },
+            command_id: 10,
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut cache = HashMap::new();
+        UserEventSource::step_calendar(&paths, &BUCKETS, &mut cache, &source, &sender).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(Event::Send { command_id: 10, .. })
+        ));
+    }
+}
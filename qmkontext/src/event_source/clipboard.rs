@@ -0,0 +1,357 @@
+use super::x11::X11Session;
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use x11rb::connection::Connection;
+use x11rb::protocol::xfixes::{ConnectionExt as XfixesConnectionExt, SelectionEventMask};
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ConnectionExt, CreateWindowAux, Window, WindowClass,
+};
+use x11rb::protocol::Event as X11Event;
+use x11rb::{COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE};
+
+/// Maximum amount of 32-bit words read from the clipboard (1 MiB).
+const MAX_CLIPBOARD_LENGTH: u32 = 256 * 1024;
+const SELECTION_TIMEOUT_MS: u128 = 2000;
+const SELECTION_POLL_INTERVAL_MS: u64 = 10;
+const TEXT_MIME_TYPES: [&str; 4] = [
+    "UTF8_STRING",
+    "text/plain;charset=utf-8",
+    "text/plain",
+    "STRING",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClipboardBackend {
+    /// Use Wayland if `$WAYLAND_DISPLAY` is set, X11 otherwise.
+    Auto,
+    X11,
+    /// Uses `wl-paste` from wl-clipboard.
+    Wayland,
+}
+
+/// Rule classifying the clipboard. All the regexes that are set must match.
+#[derive(Clone, Debug)]
+pub struct ClipboardRule {
+    name: String,
+    mime_regex: Option<Regex>,
+    content_regex: Option<Regex>,
+    value: u8,
+}
+
+impl ClipboardRule {
+    /// - `name` is the name of the class, only used for logging.
+    /// - `mime_regex` is matched against each of the MIME types offered by the clipboard owner.
+    /// - `content_regex` is matched against the text contents of the clipboard.
+    ///
+    /// Fails when a regex is invalid.
+    pub fn new(
+        name: String,
+        mime_regex: Option<&str>,
+        content_regex: Option<&str>,
+        value: u8,
+    ) -> Result<Self> {
+        let compile = |regex: Option<&str>| match regex {
+            Some(regex) => Regex::new(regex).map(Some).map_err(|e| {
+                Error::UserConfigExecutionError(format!(
+                    "invalid regex {regex} in clipboard rule {name}: {e}"
+                ))
+            }),
+            None => Ok(None),
+        };
+        let mime_regex = compile(mime_regex)?;
+        let content_regex = compile(content_regex)?;
+        Ok(Self {
+            name,
+            mime_regex,
+            content_regex,
+            value,
+        })
+    }
+}
+
+/// Contents of the clipboard. It must never be logged.
+#[derive(Default)]
+struct ClipboardContent {
+    mime_types: Vec<String>,
+    text: Option<String>,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_clipboard(
+        backend: ClipboardBackend,
+        rules: Vec<ClipboardRule>,
+        default_value: u8,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            let mut last_value = None;
+            let mut on_change = |content: ClipboardContent| {
+                let (class, value) = Self::classify_clipboard(&rules, &content)
+                    .map(|rule| (rule.name.as_str(), rule.value))
+                    .unwrap_or(("default", default_value));
+                if last_value != Some(value) {
+                    info!("Clipboard classified as {} ({})", class, value);
+                    let event = Event::Send {
+                        command_id: source.command_id,
+                        command_data: vec![value],
+                    };
+                    let _ = sender.send(event);
+                    last_value = Some(value);
+                }
+            };
+            let use_wayland = match backend {
+                ClipboardBackend::Auto => std::env::var_os("WAYLAND_DISPLAY").is_some(),
+                ClipboardBackend::X11 => false,
+                ClipboardBackend::Wayland => true,
+            };
+            let result = if use_wayland {
+                Self::watch_clipboard_wayland(&mut on_change)
+            } else {
+                Self::watch_clipboard_x11(&mut on_change)
+            };
+            if let Err(e) = result {
+                error!("error in clipboard : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    /// Returns the first rule matching the clipboard, if any.
+    fn classify_clipboard<'a>(
+        rules: &'a [ClipboardRule],
+        content: &ClipboardContent,
+    ) -> Option<&'a ClipboardRule> {
+        if content.mime_types.is_empty() {
+            return None;
+        }
+        rules.iter().find(|rule| {
+            let mime_matches = rule
+                .mime_regex
+                .as_ref()
+                .is_none_or(|regex| content.mime_types.iter().any(|m| regex.is_match(m)));
+            let content_matches = rule
+                .content_regex
+                .as_ref()
+                .is_none_or(|regex| content.text.as_ref().is_some_and(|t| regex.is_match(t)));
+            mime_matches && content_matches
+        })
+    }
+
+    fn watch_clipboard_x11(on_change: &mut dyn FnMut(ClipboardContent)) -> Result<()> {
+        let session = X11Session::connect()?;
+        session.conn.xfixes_query_version(5, 0)?.reply()?;
+
+        // The selection owner writes the contents to a property of the requestor window
+        let window = session.conn.generate_id()?;
+        session
+            .conn
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                session.root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                COPY_FROM_PARENT,
+                &CreateWindowAux::new(),
+            )?
+            .check()?;
+        let clipboard = session.atom("CLIPBOARD")?;
+        session
+            .conn
+            .xfixes_select_selection_input(
+                window,
+                clipboard,
+                SelectionEventMask::SET_SELECTION_OWNER
+                    | SelectionEventMask::SELECTION_WINDOW_DESTROY
+                    | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+            )?
+            .check()?;
+
+        // Events received while waiting for a conversion, handled afterwards
+        let mut pending = VecDeque::new();
+        loop {
+            let content = Self::read_clipboard_x11(&session, window, clipboard, &mut pending)?;
+            on_change(content);
+
+            loop {
+                let event = match pending.pop_front() {
+                    Some(event) => event,
+                    None => session.conn.wait_for_event()?,
+                };
+                if let X11Event::XfixesSelectionNotify(_) = event {
+                    break;
+                }
+            }
+            // The contents are read after the last change, so one read is enough
+            pending.retain(|event| !matches!(event, X11Event::XfixesSelectionNotify(_)));
+        }
+    }
+
+    fn read_clipboard_x11(
+        session: &X11Session,
+        window: Window,
+        clipboard: Atom,
+        pending: &mut VecDeque<X11Event>,
+    ) -> Result<ClipboardContent> {
+        let owner = session.conn.get_selection_owner(clipboard)?.reply()?.owner;
+        if owner == NONE {
+            return Ok(ClipboardContent::default());
+        }
+
+        let targets = session.atom("TARGETS")?;
+        let target_atoms =
+            match Self::convert_selection_x11(session, window, clipboard, targets, pending)? {
+                Some(value) => value
+                    .chunks_exact(4)
+                    .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect::<Vec<Atom>>(),
+                None => return Ok(ClipboardContent::default()),
+            };
+        let cookies = target_atoms
+            .iter()
+            .map(|atom| session.conn.get_atom_name(*atom))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut mime_types = Vec::new();
+        for cookie in cookies {
+            mime_types.push(String::from_utf8_lossy(&cookie.reply()?.name).to_string());
+        }
+
+        let text_target = TEXT_MIME_TYPES
+            .iter()
+            .find(|t| mime_types.iter().any(|m| m == *t));
+        let text = match text_target {
+            Some(target) => {
+                let target = session.atom(target)?;
+                Self::convert_selection_x11(session, window, clipboard, target, pending)?
+                    .map(|value| String::from_utf8_lossy(&value).to_string())
+            }
+            None => None,
+        };
+        Ok(ClipboardContent { mime_types, text })
+    }
+
+    /// Asks the selection owner for the given target and waits for the conversion. The other
+    /// events are queued in `pending`.
+    fn convert_selection_x11(
+        session: &X11Session,
+        window: Window,
+        clipboard: Atom,
+        target: Atom,
+        pending: &mut VecDeque<X11Event>,
+    ) -> Result<Option<Vec<u8>>> {
+        let property = session.atom("QMKONTEXT_CLIPBOARD")?;
+        session
+            .conn
+            .convert_selection(window, clipboard, target, property, CURRENT_TIME)?
+            .check()?;
+
+        let start = Instant::now();
+        let notify = loop {
+            match session.conn.poll_for_event()? {
+                Some(X11Event::SelectionNotify(e)) if e.requestor == window => {
+                    // Answers to conversions that timed out are dropped
+                    if e.target == target {
+                        break e;
+                    }
+                }
+                Some(event) => pending.push_back(event),
+                None if start.elapsed().as_millis() > SELECTION_TIMEOUT_MS => {
+                    warn!("The clipboard owner did not answer in time");
+                    return Ok(None);
+                }
+                None => {
+                    std::thread::sleep(std::time::Duration::from_millis(SELECTION_POLL_INTERVAL_MS))
+                }
+            }
+        };
+        if notify.property == NONE {
+            return Ok(None);
+        }
+
+        let reply = session
+            .conn
+            .get_property(
+                true,
+                window,
+                property,
+                AtomEnum::ANY,
+                0,
+                MAX_CLIPBOARD_LENGTH,
+            )?
+            .reply()?;
+        // Contents bigger than the maximum request size are sent incrementally, which
+        // is not worth supporting just for classifying them
+        if reply.type_ == session.atom("INCR")? {
+            debug!("The clipboard contents are too big to be read");
+            return Ok(None);
+        }
+        Ok(Some(reply.value))
+    }
+
+    fn watch_clipboard_wayland(on_change: &mut dyn FnMut(ClipboardContent)) -> Result<()> {
+        // wl-paste runs the command every time the clipboard changes, the output is
+        // only used as a notification and the contents are read afterwards
+        let mut child = Command::new("wl-paste")
+            .args(["--watch", "echo"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::IoError("cannot read wl-paste output".to_string()))?;
+
+        let result = (|| {
+            on_change(Self::read_clipboard_wayland()?);
+            for line in BufReader::new(stdout).lines() {
+                line?;
+                on_change(Self::read_clipboard_wayland()?);
+            }
+            Err(Error::IoError("wl-paste --watch finished".to_string()))
+        })();
+        let _ = child.kill();
+        let _ = child.wait();
+        result
+    }
+
+    fn read_clipboard_wayland() -> Result<ClipboardContent> {
+        let output = Command::new("wl-paste").arg("--list-types").output()?;
+        if !output.status.success() {
+            // wl-paste fails when the clipboard is empty
+            return Ok(ClipboardContent::default());
+        }
+        let mime_types = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<String>>();
+
+        let text_type = TEXT_MIME_TYPES
+            .iter()
+            .find(|t| mime_types.iter().any(|m| m == *t));
+        let text = match text_type {
+            Some(mime_type) => {
+                let output = Command::new("wl-paste")
+                    .args(["--no-newline", "--type", mime_type])
+                    .output()?;
+                output
+                    .status
+                    .success()
+                    .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+            }
+            None => None,
+        };
+        Ok(ClipboardContent { mime_types, text })
+    }
+}
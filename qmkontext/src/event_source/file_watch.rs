@@ -0,0 +1,154 @@
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use inotify::{Inotify, WatchMask};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const INOTIFY_BUFFER_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FileWatchMode {
+    /// Sends the contents of the file.
+    Contents,
+    /// Parses the file as JSON and sends the value found at the given pointer (`/build/status`).
+    JsonPointer(String),
+    /// Sends `exists_value` if the file exists, `default_value` otherwise.
+    Exists { exists_value: Vec<u8> },
+}
+
+impl UserEventSource {
+    pub(super) fn loop_file_watch(
+        path: PathBuf,
+        mode: FileWatchMode,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) =
+                Self::watch_file(&path, &mode, &mappings, &default_value, &source, &sender)
+            {
+                error!("error in file watch [path={}]: {:?}", path.display(), e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_file(
+        path: &Path,
+        mode: &FileWatchMode,
+        mappings: &HashMap<String, Vec<u8>>,
+        default_value: &[u8],
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let (directory, file_name) = match (path.parent(), path.file_name()) {
+            (Some(d), Some(f)) => (d, f),
+            _ => {
+                return Err(Error::UserConfigExecutionError(format!(
+                    "invalid file path {}",
+                    path.display()
+                )))
+            }
+        };
+
+        // Watching the directory allows detecting creations, deletions and atomic renames.
+        // Creations are notified through CLOSE_WRITE, so half-written files are not read.
+        let mut inotify = Inotify::init()?;
+        inotify.watches().add(
+            directory,
+            WatchMask::CLOSE_WRITE
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::DELETE_SELF
+                | WatchMask::MOVE_SELF,
+        )?;
+
+        let mut buffer = [0u8; INOTIFY_BUFFER_SIZE];
+        let mut last_value = None;
+        loop {
+            let value = Self::read_watched_file(path, mode, mappings, default_value);
+            if last_value.as_ref() != Some(&value) {
+                debug!("File {} changed, sending {:?}", path.display(), value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: value.clone(),
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            let mut changed = false;
+            while !changed {
+                for event in inotify.read_events_blocking(&mut buffer)? {
+                    if event.mask.contains(inotify::EventMask::IGNORED) {
+                        return Err(Error::IoError(format!(
+                            "directory {} is not watched anymore",
+                            directory.display()
+                        )));
+                    }
+                    changed |= event.name == Some(file_name);
+                }
+            }
+        }
+    }
+
+    fn read_watched_file(
+        path: &Path,
+        mode: &FileWatchMode,
+        mappings: &HashMap<String, Vec<u8>>,
+        default_value: &[u8],
+    ) -> Vec<u8> {
+        if let FileWatchMode::Exists { exists_value } = mode {
+            return if path.exists() {
+                exists_value.clone()
+            } else {
+                default_value.to_vec()
+            };
+        }
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("Cannot read {}: {:?}", path.display(), e);
+                return default_value.to_vec();
+            }
+        };
+
+        let value = match mode {
+            FileWatchMode::JsonPointer(pointer) => {
+                let json = match serde_json::from_str::<serde_json::Value>(&contents) {
+                    Ok(j) => j,
+                    Err(e) => {
+                        warn!("File {} is not valid JSON: {:?}", path.display(), e);
+                        return default_value.to_vec();
+                    }
+                };
+                match json.pointer(pointer) {
+                    Some(serde_json::Value::String(s)) => s.to_string(),
+                    Some(serde_json::Value::Bool(b)) => (*b as u8).to_string(),
+                    Some(other) => other.to_string(),
+                    None => {
+                        debug!("Pointer {} not found in {}", pointer, path.display());
+                        return default_value.to_vec();
+                    }
+                }
+            }
+            _ => contents.trim().to_string(),
+        };
+
+        match mappings.get(&value) {
+            Some(v) => v.clone(),
+            None => value.parse::<u8>().map(|v| vec![v]).unwrap_or_else(|_| {
+                warn!(
+                    "Value of {} has no mapping and is not a number between 0 and 255",
+                    path.display()
+                );
+                default_value.to_vec()
+            }),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+use std::process::Command;
+use zbus::blocking::{Connection, ConnectionBuilder, Proxy};
+use zbus::zvariant::{OwnedValue, Value};
+
+const FCITX5_SERVICE: &str = "org.fcitx.Fcitx5";
+const FCITX5_PATH: &str = "/controller";
+const FCITX5_INTERFACE: &str = "org.fcitx.Fcitx.Controller1";
+
+const IBUS_SERVICE: &str = "org.freedesktop.IBus";
+const IBUS_PATH: &str = "/org/freedesktop/IBus";
+const IBUS_INTERFACE: &str = "org.freedesktop.IBus";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputMethodBackend {
+    /// Use fcitx5 if it is running, IBus otherwise.
+    Auto,
+    Fcitx5,
+    IBus,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_input_method(
+        backend: InputMethodBackend,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            let mut on_change = |engine: String| {
+                let command_data = match mappings.get(&engine) {
+                    Some(value) => value.clone(),
+                    None => {
+                        debug!("Input method {engine} has no mapping, sending default value");
+                        default_value.clone()
+                    }
+                };
+                info!("Input method changed to {engine} ({command_data:?})");
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data,
+                };
+                let _ = sender.send(event);
+            };
+            let result = match backend {
+                InputMethodBackend::Fcitx5 => Self::fcitx5_proxy()
+                    .and_then(|proxy| Self::watch_fcitx5(proxy, &source, &mut on_change)),
+                InputMethodBackend::IBus => Self::watch_ibus(&mut on_change),
+                InputMethodBackend::Auto => match Self::fcitx5_proxy() {
+                    Ok(proxy) => Self::watch_fcitx5(proxy, &source, &mut on_change),
+                    Err(e) => {
+                        debug!("fcitx5 is not available, trying IBus: {:?}", e);
+                        Self::watch_ibus(&mut on_change)
+                    }
+                },
+            };
+            if let Err(e) = result {
+                error!("error in input_method : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn fcitx5_proxy() -> Result<Proxy<'static>> {
+        let connection = Connection::session()?;
+        let proxy = Proxy::new_owned(connection, FCITX5_SERVICE, FCITX5_PATH, FCITX5_INTERFACE)?;
+        // Fail early if fcitx5 is not running
+        proxy.call::<_, _, String>("CurrentInputMethod", &())?;
+        Ok(proxy)
+    }
+
+    /// fcitx5 does not emit a signal when the input method changes, so it is queried every interval.
+    fn watch_fcitx5(
+        proxy: Proxy,
+        source: &UserEventConfig,
+        on_change: &mut dyn FnMut(String),
+    ) -> Result<()> {
+        let mut last_engine = None;
+        loop {
+            let engine: String = proxy.call("CurrentInputMethod", &())?;
+            if last_engine.as_ref() != Some(&engine) {
+                on_change(engine.clone());
+                last_engine = Some(engine);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_ibus(on_change: &mut dyn FnMut(String)) -> Result<()> {
+        let address = Self::ibus_address()?;
+        let connection = ConnectionBuilder::address(address.as_str())?.build()?;
+        let proxy = Proxy::new(&connection, IBUS_SERVICE, IBUS_PATH, IBUS_INTERFACE)?;
+        let signals = proxy.receive_signal("GlobalEngineChanged")?;
+
+        let engine = proxy.get_property::<OwnedValue>("GlobalEngine")?;
+        match Self::ibus_engine_name(&engine) {
+            Some(name) => on_change(name),
+            None => warn!("Cannot read the current IBus engine"),
+        }
+        for signal in signals {
+            let engine: String = signal.body()?;
+            on_change(engine);
+        }
+
+        Err(Error::DbusError("IBus signal stream finished".to_string()))
+    }
+
+    /// IBus runs its own bus, whose address is not the session bus one.
+    fn ibus_address() -> Result<String> {
+        if let Ok(address) = std::env::var("IBUS_ADDRESS") {
+            return Ok(address);
+        }
+
+        let output = Command::new("ibus").arg("address").output()?;
+        let address = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || address.is_empty() || address == "(null)" {
+            return Err(Error::DbusError("cannot get the IBus address".to_string()));
+        }
+        Ok(address)
+    }
+
+    /// The engine is serialized as an IBusEngineDesc structure, whose third field is the name.
+    fn ibus_engine_name(value: &Value) -> Option<String> {
+        match value {
+            Value::Value(inner) => Self::ibus_engine_name(inner),
+            Value::Structure(structure) => match structure.fields().get(2)? {
+                Value::Str(name) => Some(name.to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use inotify::{Inotify, WatchMask};
+use std::path::{Path, PathBuf};
+
+const INOTIFY_BUFFER_SIZE: usize = 4096;
+
+/// Value sent when the unread count reaches `min_count`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MaildirBucket {
+    pub min_count: u32,
+    pub value: u8,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_maildir(
+        folders: Vec<PathBuf>,
+        buckets: Vec<MaildirBucket>,
+        default_value: u8,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) = Self::watch_maildir(&folders, &buckets, default_value, &source, &sender)
+            {
+                error!("error in maildir : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_maildir(
+        folders: &[PathBuf],
+        buckets: &[MaildirBucket],
+        default_value: u8,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        // Delivering a message or changing its flags is always a rename or a creation
+        let mut inotify = Inotify::init()?;
+        for folder in folders {
+            for subdirectory in ["new", "cur"] {
+                inotify.watches().add(
+                    folder.join(subdirectory),
+                    WatchMask::CREATE
+                        | WatchMask::DELETE
+                        | WatchMask::MOVED_FROM
+                        | WatchMask::MOVED_TO,
+                )?;
+            }
+        }
+
+        let mut buffer = [0u8; INOTIFY_BUFFER_SIZE];
+        let mut last_value = None;
+        loop {
+            let mut unread = 0;
+            for folder in folders {
+                unread += Self::count_unread(folder)?;
+            }
+            let value = Self::maildir_value(unread, buckets, default_value);
+            if last_value != Some(value) {
+                info!("Unread messages: {} (sending {})", unread, value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            inotify.read_events_blocking(&mut buffer)?;
+        }
+    }
+
+    /// Counts the messages in `new/` plus the messages in `cur/` without the Seen or Trashed flags.
+    fn count_unread(folder: &Path) -> Result<u32> {
+        let mut count = 0;
+        for subdirectory in ["new", "cur"] {
+            for entry in std::fs::read_dir(folder.join(subdirectory))? {
+                let file_name = entry?.file_name().to_string_lossy().to_string();
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                // unique:2,FLAGS where flags are sorted letters such as "FRS"
+                let flags = file_name
+                    .rsplit_once(":2,")
+                    .map(|(_, flags)| flags)
+                    .unwrap_or_default();
+                if subdirectory == "new" || !(flags.contains('S') || flags.contains('T')) {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn maildir_value(unread: u32, buckets: &[MaildirBucket], default_value: u8) -> u8 {
+        if buckets.is_empty() {
+            return unread.min(u8::MAX as u32) as u8;
+        }
+        buckets
+            .iter()
+            .filter(|b| unread >= b.min_count)
+            .max_by_key(|b| b.min_count)
+            .map(|b| b.value)
+            .unwrap_or(default_value)
+    }
+}
@@ -0,0 +1,536 @@
+use super::x11::FocusTracker;
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use rmpv::Value;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+const MSGPACK_RPC_REQUEST: u64 = 0;
+const MSGPACK_RPC_RESPONSE: u64 = 1;
+const MSGPACK_RPC_NOTIFICATION: u64 = 2;
+const MODE_NOTIFICATION: &str = "qmkontext_mode";
+
+/// Registers an autocmd that notifies every mode change to the calling channel.
+/// The autocmd deletes itself once the channel is closed.
+const SUBSCRIBE_LUA: &str = r#"
+local channel = ...
+local group = vim.api.nvim_create_augroup("qmkontext_" .. channel, { clear = true })
+vim.api.nvim_create_autocmd("ModeChanged", {
+    group = group,
+    callback = function()
+        local ok = pcall(vim.rpcnotify, channel, "qmkontext_mode", vim.api.nvim_get_mode().mode)
+        if not ok then
+            vim.api.nvim_del_augroup_by_id(group)
+        end
+    end,
+})
+"#;
+
+enum NeovimUpdate {
+    Connected { socket: PathBuf, pid: u32 },
+    Mode { socket: PathBuf, mode: String },
+    Closed { socket: PathBuf },
+}
+
+struct NeovimInstance {
+    pid: u32,
+    mode: Option<String>,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_neovim(
+        runtime_dir: Option<PathBuf>,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let runtime_dir = match runtime_dir
+            .or_else(|| std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from))
+        {
+            Some(dir) => dir,
+            None => {
+                error!("error in neovim : cannot find the runtime dir, XDG_RUNTIME_DIR is not set");
+                return;
+            }
+        };
+
+        let (updates_sender, updates) = crossbeam_channel::unbounded();
+        let mut instances: HashMap<PathBuf, NeovimInstance> = HashMap::new();
+        let mut connecting: HashSet<PathBuf> = HashSet::new();
+        let mut focus = FocusTracker::new();
+        let mut last_value = None;
+        loop {
+            for socket in Self::find_neovim_sockets(&runtime_dir) {
+                if instances.contains_key(&socket) || connecting.contains(&socket) {
+                    continue;
+                }
+                debug!("Found neovim socket {}", socket.display());
+                connecting.insert(socket.clone());
+                let updates_sender = updates_sender.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = Self::watch_neovim(&socket, &updates_sender) {
+                        debug!("neovim socket {} closed: {:?}", socket.display(), e);
+                    }
+                    let _ = updates_sender.send(NeovimUpdate::Closed { socket });
+                });
+            }
+
+            match updates.recv_timeout(source.interval.to_std().unwrap()) {
+                Ok(update) => {
+                    for update in std::iter::once(update).chain(updates.try_iter()) {
+                        match update {
+                            NeovimUpdate::Connected { socket, pid } => {
+                                instances.insert(socket, NeovimInstance { pid, mode: None });
+                            }
+                            NeovimUpdate::Mode { socket, mode } => {
+                                if let Some(instance) = instances.get_mut(&socket) {
+                                    instance.mode = Some(mode);
+                                }
+                            }
+                            NeovimUpdate::Closed { socket } => {
+                                connecting.remove(&socket);
+                                instances.remove(&socket);
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let focused_pid = focus.focused_pid();
+
+            let focused_mode = focused_pid.and_then(|focused_pid| {
+                instances
+                    .values()
+                    .find(|i| Self::is_descendant(i.pid, focused_pid))
+                    .and_then(|i| i.mode.as_deref())
+            });
+            let value = match focused_mode {
+                Some(mode) => mappings
+                    .get(mode)
+                    .or_else(|| mappings.get(Self::neovim_mode_name(mode)))
+                    .unwrap_or(&default_value),
+                None => &default_value,
+            };
+            if last_value.as_ref() != Some(value) {
+                info!(
+                    "Neovim mode {} (sending {:?})",
+                    focused_mode.unwrap_or("unfocused"),
+                    value
+                );
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: value.clone(),
+                };
+                let _ = sender.send(event);
+                last_value = Some(value.clone());
+            }
+        }
+    }
+
+    /// Finds the sockets nvim creates by default: `nvim.PID.N` in the runtime dir,
+    /// or inside a `nvim.USER` directory in older versions.
+    fn find_neovim_sockets(runtime_dir: &Path) -> Vec<PathBuf> {
+        let mut sockets = Vec::new();
+        let mut directories = vec![runtime_dir.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let entries = match std::fs::read_dir(&directory) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let file_type = match entry.file_type() {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                let name = entry.file_name().to_string_lossy().to_string();
+                let nested = directory != runtime_dir;
+                if file_type.is_socket() && (nested || name.starts_with("nvim")) {
+                    sockets.push(entry.path());
+                } else if file_type.is_dir() && (nested || name.starts_with("nvim")) {
+                    directories.push(entry.path());
+                }
+            }
+        }
+        sockets
+    }
+
+    fn watch_neovim(socket: &Path, updates: &Sender<NeovimUpdate>) -> Result<()> {
+        let stream = UnixStream::connect(socket)?;
+        let mut client = NeovimClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+        };
+
+        let api_info = client.request("nvim_get_api_info", vec![])?;
+        let channel = api_info[0]
+            .as_u64()
+            .ok_or_else(|| Error::RpcError("invalid channel id".to_string()))?;
+        let pid = client
+            .request(
+                "nvim_call_function",
+                vec![Value::from("getpid"), Value::Array(vec![])],
+            )?
+            .as_u64()
+            .ok_or_else(|| Error::RpcError("invalid pid".to_string()))?;
+        let _ = updates.send(NeovimUpdate::Connected {
+            socket: socket.to_path_buf(),
+            pid: pid as u32,
+        });
+
+        client.request(
+            "nvim_exec_lua",
+            vec![
+                Value::from(SUBSCRIBE_LUA),
+                Value::Array(vec![Value::from(channel)]),
+            ],
+        )?;
+        let mode = client.request("nvim_get_mode", vec![])?;
+        if let Some(mode) = Self::map_get(&mode, "mode").and_then(|m| m.as_str()) {
+            let _ = updates.send(NeovimUpdate::Mode {
+                socket: socket.to_path_buf(),
+                mode: mode.to_string(),
+            });
+        }
+
+        loop {
+            if let Some(mode) = client.read_mode_notification()? {
+                let _ = updates.send(NeovimUpdate::Mode {
+                    socket: socket.to_path_buf(),
+                    mode,
+                });
+            }
+        }
+    }
+
+    /// Name of the mode as documented in `:help mode()`, used when there is no mapping for the mode itself.
+    fn neovim_mode_name(mode: &str) -> &'static str {
+        if mode.starts_with("no") {
+            return "operator-pending";
+        }
+        match mode.chars().next() {
+            Some('n') => "normal",
+            Some('v') | Some('V') | Some('\x16') => "visual",
+            Some('s') | Some('S') | Some('\x13') => "select",
+            Some('i') => "insert",
+            Some('R') => "replace",
+            Some('c') => "command",
+            Some('r') => "prompt",
+            Some('!') => "shell",
+            Some('t') => "terminal",
+            _ => "unknown",
+        }
+    }
+
+    fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+        value
+            .as_map()?
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+    }
+}
+
+/// Minimal msgpack-RPC client, enough for subscribing to the mode changes.
+struct NeovimClient {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+    next_id: u64,
+}
+
+impl NeovimClient {
+    fn request(&mut self, method: &str, params: Vec<Value>) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = Value::Array(vec![
+            Value::from(MSGPACK_RPC_REQUEST),
+            Value::from(id),
+            Value::from(method),
+            Value::Array(params),
+        ]);
+        rmpv::encode::write_value(&mut self.writer, &message)?;
+        self.writer.flush()?;
+
+        loop {
+            let message = rmpv::decode::read_value(&mut self.reader)?;
+            let fields = message.as_array().map(|a| a.as_slice()).unwrap_or_default();
+            match fields {
+                [kind, response_id, error, result]
+                    if kind.as_u64() == Some(MSGPACK_RPC_RESPONSE)
+                        && response_id.as_u64() == Some(id) =>
+                {
+                    if !error.is_nil() {
+                        return Err(Error::RpcError(format!("{method} failed: {error}")));
+                    }
+                    return Ok(result.clone());
+                }
+                _ => trace!("Ignoring neovim message while waiting for {method}"),
+            }
+        }
+    }
+
+    fn read_mode_notification(&mut self) -> Result<Option<String>> {
+        let message = rmpv::decode::read_value(&mut self.reader)?;
+        let fields = message.as_array().map(|a| a.as_slice()).unwrap_or_default();
+        match fields {
+            [kind, method, params]
+                if kind.as_u64() == Some(MSGPACK_RPC_NOTIFICATION)
+                    && method.as_str() == Some(MODE_NOTIFICATION) =>
+            {
+                Ok(params
+                    .as_array()
+                    .and_then(|p| p.first())
+                    .and_then(|mode| mode.as_str())
+                    .map(|mode| mode.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn client(stream: UnixStream) -> NeovimClient {
+        NeovimClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+        }
+    }
+
+    fn write(stream: &mut UnixStream, fields: Vec<Value>) {
+        rmpv::encode::write_value(stream, &Value::Array(fields)).unwrap();
+    }
+
+    fn response(id: u64, error: Value, result: Value) -> Vec<Value> {
+        vec![
+            Value::from(MSGPACK_RPC_RESPONSE),
+            Value::from(id),
+            error,
+            result,
+        ]
+    }
+
+    fn notification(method: &str, params: Vec<Value>) -> Vec<Value> {
+        vec![
+            Value::from(MSGPACK_RPC_NOTIFICATION),
+            Value::from(method),
+            Value::Array(params),
+        ]
+    }
+
+    /// Reads a request, returning its id, method and params.
+    fn read_request(stream: &mut UnixStream) -> (u64, String, Vec<Value>) {
+        let message = rmpv::decode::read_value(stream).unwrap();
+        match message.as_array().unwrap().as_slice() {
+            [kind, id, method, params] if kind.as_u64() == Some(MSGPACK_RPC_REQUEST) => (
+                id.as_u64().unwrap(),
+                method.as_str().unwrap().to_string(),
+                params.as_array().unwrap().clone(),
+            ),
+            other => panic!("not a request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn request_waits_for_its_response() {
+        let (stream, mut nvim) = UnixStream::pair().unwrap();
+        let nvim = std::thread::spawn(move || {
+            let (id, method, params) = read_request(&mut nvim);
+            assert_eq!(method, "nvim_get_mode");
+            assert!(params.is_empty());
+            write(&mut nvim, notification("other", vec![]));
+            write(
+                &mut nvim,
+                response(id + 1, Value::Nil, Value::from("stale")),
+            );
+            write(&mut nvim, response(id, Value::Nil, Value::from("ok")));
+        });
+
+        let mut client = client(stream);
+        assert_eq!(
+            client.request("nvim_get_mode", vec![]).unwrap(),
+            Value::from("ok")
+        );
+        nvim.join().unwrap();
+    }
+
+    #[test]
+    fn request_ids_increase() {
+        let (stream, mut nvim) = UnixStream::pair().unwrap();
+        let nvim = std::thread::spawn(move || {
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let (id, _, _) = read_request(&mut nvim);
+                write(&mut nvim, response(id, Value::Nil, Value::Nil));
+                ids.push(id);
+            }
+            ids
+        });
+
+        let mut client = client(stream);
+        client.request("a", vec![]).unwrap();
+        client.request("b", vec![]).unwrap();
+        let ids = nvim.join().unwrap();
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn request_returns_the_error() {
+        let (stream, mut nvim) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            let (id, _, _) = read_request(&mut nvim);
+            let error = Value::Array(vec![Value::from(0), Value::from("Invalid method")]);
+            write(&mut nvim, response(id, error, Value::Nil));
+        });
+
+        let mut client = client(stream);
+        assert!(matches!(
+            client.request("nvim_unknown", vec![]),
+            Err(Error::RpcError(_))
+        ));
+    }
+
+    #[test]
+    fn request_fails_when_nvim_exits() {
+        let (stream, nvim) = UnixStream::pair().unwrap();
+        drop(nvim);
+        let mut client = client(stream);
+        assert!(client.request("nvim_get_mode", vec![]).is_err());
+    }
+
+    #[test]
+    fn mode_notifications() {
+        let (stream, mut nvim) = UnixStream::pair().unwrap();
+        write(
+            &mut nvim,
+            notification(MODE_NOTIFICATION, vec![Value::from("i")]),
+        );
+        write(&mut nvim, notification("other", vec![Value::from("n")]));
+        write(&mut nvim, response(7, Value::Nil, Value::Nil));
+        write(&mut nvim, notification(MODE_NOTIFICATION, vec![]));
+
+        let mut client = client(stream);
+        assert_eq!(client.read_mode_notification().unwrap(), Some("i".into()));
+        assert_eq!(client.read_mode_notification().unwrap(), None);
+        assert_eq!(client.read_mode_notification().unwrap(), None);
+        assert_eq!(client.read_mode_notification().unwrap(), None);
+        drop(nvim);
+        assert!(client.read_mode_notification().is_err());
+    }
+
+    #[test]
+    fn watch_subscribes_and_reports_the_modes() {
+        let socket = std::env::temp_dir().join(format!("qmkontext-nvim-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let nvim = std::thread::spawn(move || {
+            let (mut nvim, _) = listener.accept().unwrap();
+            let mut methods = Vec::new();
+            for _ in 0..4 {
+                let (id, method, params) = read_request(&mut nvim);
+                let result = match method.as_str() {
+                    "nvim_get_api_info" => {
+                        Value::Array(vec![Value::from(3), Value::Map(Vec::new())])
+                    }
+                    "nvim_call_function" => {
+                        assert_eq!(params[0], Value::from("getpid"));
+                        Value::from(4242)
+                    }
+                    "nvim_exec_lua" => {
+                        // Subscribes the channel of the client
+                        assert_eq!(params[1], Value::Array(vec![Value::from(3)]));
+                        Value::Nil
+                    }
+                    "nvim_get_mode" => Value::Map(vec![
+                        (Value::from("mode"), Value::from("n")),
+                        (Value::from("blocking"), Value::from(false)),
+                    ]),
+                    other => panic!("unexpected request {other}"),
+                };
+                write(&mut nvim, response(id, Value::Nil, result));
+                methods.push(method);
+            }
+            write(
+                &mut nvim,
+                notification(MODE_NOTIFICATION, vec![Value::from("i")]),
+            );
+            methods
+        });
+
+        let (sender, updates) = crossbeam_channel::unbounded();
+        assert!(UserEventSource::watch_neovim(&socket, &sender).is_err());
+        let methods = nvim.join().unwrap();
+        let _ = std::fs::remove_file(&socket);
+
+        assert_eq!(
+            methods,
+            [
+                "nvim_get_api_info",
+                "nvim_call_function",
+                "nvim_exec_lua",
+                "nvim_get_mode"
+            ]
+        );
+        let updates = updates.try_iter().collect::<Vec<NeovimUpdate>>();
+        assert!(matches!(
+            updates.as_slice(),
+            [
+                NeovimUpdate::Connected { pid: 4242, .. },
+                NeovimUpdate::Mode { mode: normal, .. },
+                NeovimUpdate::Mode { mode: insert, .. },
+            ] if normal == "n" && insert == "i"
+        ));
+    }
+
+    #[test]
+    #[ignore = "requires nvim in the PATH"]
+    fn watch_headless_nvim() {
+        let socket = std::env::temp_dir().join(format!("qmkontext-nvim-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let mut nvim = std::process::Command::new("nvim")
+            .args(["--headless", "--clean", "--listen"])
+            .arg(&socket)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let started = std::time::Instant::now();
+        while !socket.exists() && started.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let (sender, updates) = crossbeam_channel::unbounded();
+        let watched = socket.clone();
+        std::thread::spawn(move || UserEventSource::watch_neovim(&watched, &sender));
+        let timeout = std::time::Duration::from_secs(5);
+        let pid = nvim.id();
+        assert!(matches!(
+            updates.recv_timeout(timeout),
+            Ok(NeovimUpdate::Connected { pid: connected, .. }) if connected == pid
+        ));
+        assert!(matches!(
+            updates.recv_timeout(timeout),
+            Ok(NeovimUpdate::Mode { mode, .. }) if mode == "n"
+        ));
+
+        let mut input = client(UnixStream::connect(&socket).unwrap());
+        input.request("nvim_input", vec![Value::from("i")]).unwrap();
+        assert!(matches!(
+            updates.recv_timeout(timeout),
+            Ok(NeovimUpdate::Mode { mode, .. }) if mode == "i"
+        ));
+
+        let _ = nvim.kill();
+        let _ = nvim.wait();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use std::path::Path;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+const PROC_NET_IPV6_ROUTE: &str = "/proc/net/ipv6_route";
+
+/// `IFF_UP` flag of `/sys/class/net/*/flags`.
+const IFF_UP: u32 = 0x1;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NetworkCheck {
+    /// Whether the named interface (`wg0`, `tun0`...) is up.
+    InterfaceUp { interface: String },
+    /// Whether a default route exists, IPv4 or IPv6.
+    DefaultRoute,
+    /// Whether the machine is connected through a wired or a wifi interface.
+    ConnectionType,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NetworkValues {
+    pub down: u8,
+    pub up: u8,
+    pub wired: u8,
+    pub wifi: u8,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_network(
+        check: NetworkCheck,
+        values: NetworkValues,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) = Self::watch_network(&check, &values, &source, &sender) {
+                error!("error in network [{:?}]: {:?}", check, e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_network(
+        check: &NetworkCheck,
+        values: &NetworkValues,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        let groups = libc::RTMGRP_LINK
+            | libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_ROUTE;
+        socket.bind(&SocketAddr::new(0, groups as u32))?;
+
+        let mut last_value = None;
+        loop {
+            let value = Self::get_network_value(check, values);
+            if last_value != Some(value) {
+                info!("Network state [{:?}] changed to {}", check, value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            // The contents of the notification do not matter, sysfs and procfs are read again
+            socket.recv_from_full()?;
+        }
+    }
+
+    fn get_network_value(check: &NetworkCheck, values: &NetworkValues) -> u8 {
+        match check {
+            NetworkCheck::InterfaceUp { interface } => {
+                if Self::is_interface_up(interface) {
+                    values.up
+                } else {
+                    values.down
+                }
+            }
+            NetworkCheck::DefaultRoute => {
+                if Self::has_default_route() {
+                    values.up
+                } else {
+                    values.down
+                }
+            }
+            NetworkCheck::ConnectionType => {
+                let physical_up = Self::list_interfaces()
+                    .into_iter()
+                    .filter(|i| Self::is_physical_interface(i) && Self::is_interface_up(i))
+                    .collect::<Vec<String>>();
+                if physical_up.iter().any(|i| !Self::is_wireless_interface(i)) {
+                    values.wired
+                } else if !physical_up.is_empty() {
+                    values.wifi
+                } else {
+                    values.down
+                }
+            }
+        }
+    }
+
+    fn list_interfaces() -> Vec<String> {
+        std::fs::read_dir(SYS_CLASS_NET)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn is_interface_up(interface: &str) -> bool {
+        let interface_path = Path::new(SYS_CLASS_NET).join(interface);
+        let operstate = std::fs::read_to_string(interface_path.join("operstate"))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        match operstate.as_str() {
+            "up" => true,
+            // Tunnel interfaces such as wireguard or tun do not report an operational state
+            "unknown" => std::fs::read_to_string(interface_path.join("flags"))
+                .ok()
+                .and_then(|flags| {
+                    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok()
+                })
+                .map(|flags| flags & IFF_UP != 0)
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    fn is_physical_interface(interface: &str) -> bool {
+        Path::new(SYS_CLASS_NET)
+            .join(interface)
+            .join("device")
+            .exists()
+    }
+
+    fn is_wireless_interface(interface: &str) -> bool {
+        let interface_path = Path::new(SYS_CLASS_NET).join(interface);
+        interface_path.join("wireless").exists() || interface_path.join("phy80211").exists()
+    }
+
+    fn has_default_route() -> bool {
+        // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+        let ipv4 = std::fs::read_to_string(PROC_NET_ROUTE)
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .any(|line| {
+                let columns = line.split_whitespace().collect::<Vec<&str>>();
+                columns.len() > 7 && columns[1] == "00000000" && columns[7] == "00000000"
+            });
+
+        // Destination PrefixLen Source SourcePrefixLen NextHop Metric RefCnt Use Flags Iface
+        let ipv6 = std::fs::read_to_string(PROC_NET_IPV6_ROUTE)
+            .unwrap_or_default()
+            .lines()
+            .any(|line| {
+                let columns = line.split_whitespace().collect::<Vec<&str>>();
+                columns.len() > 9
+                    && columns[0].chars().all(|c| c == '0')
+                    && columns[1] == "00"
+                    && columns[9] != "lo"
+            });
+
+        ipv4 || ipv6
+    }
+}
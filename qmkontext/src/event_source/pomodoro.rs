@@ -0,0 +1,71 @@
+use crate::{
+    Event, PomodoroPhase, PomodoroStatus, PomodoroTimer, UserEventConfig, UserEventSource,
+};
+use crossbeam_channel::Sender;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PomodoroPhaseValues {
+    pub idle: u8,
+    pub work: u8,
+    pub short_break: u8,
+    pub long_break: u8,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PomodoroOutput {
+    Phase(PomodoroPhaseValues),
+    /// Remaining time of the phase scaled from `buckets` (just started) down to 0 (finished or idle).
+    Remaining {
+        buckets: u8,
+    },
+}
+
+impl UserEventSource {
+    pub(super) fn loop_pomodoro(
+        timer: PomodoroTimer,
+        output: PomodoroOutput,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let mut last_value = None;
+        loop {
+            let status = timer.status();
+            let value = Self::pomodoro_value(&status, output);
+            if last_value != Some(value) {
+                debug!(
+                    "Pomodoro {:?} with {}s remaining (sending {})",
+                    status.phase, status.remaining_seconds, value
+                );
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn pomodoro_value(status: &PomodoroStatus, output: PomodoroOutput) -> u8 {
+        match output {
+            PomodoroOutput::Phase(values) => match status.phase {
+                PomodoroPhase::Idle => values.idle,
+                PomodoroPhase::Work => values.work,
+                PomodoroPhase::ShortBreak => values.short_break,
+                PomodoroPhase::LongBreak => values.long_break,
+            },
+            PomodoroOutput::Remaining { buckets } => {
+                if status.phase_seconds <= 0 {
+                    return 0;
+                }
+                // Rounded up, so the last bucket only turns off when the phase ends
+                let remaining = status.remaining_seconds.clamp(0, status.phase_seconds);
+                let value =
+                    (remaining * buckets as i64 + status.phase_seconds - 1) / status.phase_seconds;
+                value as u8
+            }
+        }
+    }
+}
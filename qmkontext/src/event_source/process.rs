@@ -0,0 +1,226 @@
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use regex::Regex;
+use std::path::Path;
+
+const PROC_PATH: &str = "/proc";
+const PASSWD_PATH: &str = "/etc/passwd";
+
+/// Rule matching running processes. All the fields that are set must match.
+#[derive(Clone, Debug)]
+pub struct ProcessRule {
+    name: Option<String>,
+    args_regex: Option<Regex>,
+    uid: Option<u32>,
+    value: u8,
+    priority: i32,
+}
+
+impl ProcessRule {
+    /// - `name` is compared against `/proc/PID/comm` and the binary name of argv[0].
+    /// - `args_regex` is matched against the whole command line, with the arguments joined by
+    ///   spaces.
+    /// - `user` is the user name or uid owning the process.
+    /// - When many rules match, the one with the highest `priority` wins.
+    ///
+    /// Fails when the regex is invalid or the user does not exist.
+    pub fn new(
+        name: Option<String>,
+        args_regex: Option<&str>,
+        user: Option<&str>,
+        value: u8,
+        priority: i32,
+    ) -> Result<Self> {
+        let args_regex = match args_regex {
+            Some(regex) => Some(Regex::new(regex).map_err(|e| {
+                Error::UserConfigExecutionError(format!("invalid args_regex {regex}: {e}"))
+            })?),
+            None => None,
+        };
+        let uid = match user {
+            Some(user) => {
+                let uid = resolve_uid(user).ok_or_else(|| {
+                    Error::UserConfigExecutionError(format!("unknown user {user}"))
+                })?;
+                Some(uid)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            name,
+            args_regex,
+            uid,
+            value,
+            priority,
+        })
+    }
+}
+
+/// Returns the uid of a user name or uid.
+pub(crate) fn resolve_uid(user: &str) -> Option<u32> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Some(uid);
+    }
+
+    // name:password:uid:gid:gecos:home:shell
+    std::fs::read_to_string(PASSWD_PATH)
+        .ok()?
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[0] == user)
+        .and_then(|fields| fields[2].parse::<u32>().ok())
+}
+
+struct ProcessData {
+    name: String,
+    binary: String,
+    cmdline: String,
+    uid: Option<u32>,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_process_presence(
+        rules: Vec<ProcessRule>,
+        default_value: u8,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) = Self::step_process_presence(&rules, default_value, &source, &sender) {
+                error!("error in process_presence : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    /// Checks whether the process is `ancestor` or one of its descendants, such as a
+    /// program running inside the focused terminal.
+    pub(super) fn is_descendant(pid: u32, ancestor: u32) -> bool {
+        let mut pid = pid;
+        while pid > 1 {
+            if pid == ancestor {
+                return true;
+            }
+            // pid (comm) state ppid ...; comm may contain spaces and parentheses
+            let stat = match std::fs::read_to_string(format!("{PROC_PATH}/{pid}/stat")) {
+                Ok(s) => s,
+                Err(_) => return false,
+            };
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                .and_then(|ppid| ppid.parse::<u32>().ok());
+            match ppid {
+                Some(ppid) => pid = ppid,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn step_process_presence(
+        rules: &[ProcessRule],
+        default_value: u8,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let mut best_match: Option<&ProcessRule> = None;
+        for entry in std::fs::read_dir(PROC_PATH)? {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let is_pid = entry
+                .file_name()
+                .to_string_lossy()
+                .chars()
+                .all(|c| c.is_ascii_digit());
+            if !is_pid {
+                continue;
+            }
+
+            // Processes may exit while scanning, so unreadable entries are skipped
+            let process = match Self::get_process_data(&entry.path()) {
+                Some(p) => p,
+                None => continue,
+            };
+            for rule in rules {
+                let is_better = best_match
+                    .map(|m| rule.priority > m.priority)
+                    .unwrap_or(true);
+                if is_better && Self::process_matches(rule, &process) {
+                    debug!(
+                        "Process {} matches rule with value {}",
+                        process.name, rule.value
+                    );
+                    best_match = Some(rule);
+                }
+            }
+        }
+
+        let command_data = match best_match {
+            Some(rule) => rule.value,
+            None => {
+                debug!("No process matches the process_presence rules, sending default value");
+                default_value
+            }
+        };
+        let event = Event::Send {
+            command_id: source.command_id,
+            command_data: vec![command_data],
+        };
+        let _ = sender.send(event);
+        Ok(())
+    }
+
+    fn get_process_data(path: &Path) -> Option<ProcessData> {
+        let name = std::fs::read_to_string(path.join("comm")).ok()?;
+        let cmdline = std::fs::read(path.join("cmdline")).ok()?;
+        let args = cmdline
+            .split(|b| *b == 0)
+            .filter(|a| !a.is_empty())
+            .map(|a| String::from_utf8_lossy(a).to_string())
+            .collect::<Vec<String>>();
+        let binary = args
+            .first()
+            .and_then(|a| a.rsplit('/').next())
+            .unwrap_or_default()
+            .to_string();
+
+        // Uid: real effective saved filesystem
+        let uid = std::fs::read_to_string(path.join("status"))
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|uids| uids.split_whitespace().next())
+            .and_then(|uid| uid.parse::<u32>().ok());
+
+        Some(ProcessData {
+            name: name.trim().to_string(),
+            binary,
+            cmdline: args.join(" "),
+            uid,
+        })
+    }
+
+    fn process_matches(rule: &ProcessRule, process: &ProcessData) -> bool {
+        if let Some(name) = &rule.name {
+            if &process.name != name && &process.binary != name {
+                return false;
+            }
+        }
+        if let Some(regex) = &rule.args_regex {
+            if !regex.is_match(&process.cmdline) {
+                return false;
+            }
+        }
+        if let Some(uid) = rule.uid {
+            if process.uid != Some(uid) {
+                return false;
+            }
+        }
+        true
+    }
+}
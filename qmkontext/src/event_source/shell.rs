@@ -0,0 +1,117 @@
+use super::x11::FocusTracker;
+use crate::{Error, Event, Result, ShellSession, ShellSessions, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+pub enum ShellOutput {
+    /// Sends whether the last command of the focused shell failed.
+    LastStatus { ok_value: u8, failed_value: u8 },
+    /// Sends the value of the first rule matching the command running in the focused shell.
+    Command {
+        rules: Vec<ShellCommandRule>,
+        idle_value: u8,
+        running_value: u8,
+    },
+}
+
+/// Rule matching the running command. All the regexes that are set must match.
+#[derive(Clone, Debug)]
+pub struct ShellCommandRule {
+    command_regex: Option<Regex>,
+    cwd_regex: Option<Regex>,
+    value: u8,
+}
+
+impl ShellCommandRule {
+    /// Fails when a regex is invalid.
+    pub fn new(command_regex: Option<&str>, cwd_regex: Option<&str>, value: u8) -> Result<Self> {
+        let compile = |regex: Option<&str>| match regex {
+            Some(regex) => Regex::new(regex).map(Some).map_err(|e| {
+                Error::UserConfigExecutionError(format!("invalid shell rule regex {regex}: {e}"))
+            }),
+            None => Ok(None),
+        };
+        Ok(Self {
+            command_regex: compile(command_regex)?,
+            cwd_regex: compile(cwd_regex)?,
+            value,
+        })
+    }
+}
+
+impl UserEventSource {
+    pub(super) fn loop_shell(
+        sessions: ShellSessions,
+        output: ShellOutput,
+        default_value: u8,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let mut focus = FocusTracker::new();
+        let mut last_value = None;
+        loop {
+            let focused_pid = focus.focused_pid();
+
+            // A terminal may hold many shells (tabs, splits): the last active one is used
+            let shells = sessions.sessions();
+            let focused_shell = focused_pid.and_then(|focused_pid| {
+                shells
+                    .iter()
+                    .filter(|(pid, _)| Self::is_descendant(**pid, focused_pid))
+                    .max_by_key(|(_, shell)| shell.updated_at)
+                    .map(|(_, shell)| shell)
+            });
+            let value = match focused_shell {
+                Some(shell) => Self::shell_value(shell, &output),
+                None => default_value,
+            };
+            if last_value != Some(value) {
+                info!("Focused shell changed (sending {})", value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn shell_value(shell: &ShellSession, output: &ShellOutput) -> u8 {
+        match output {
+            ShellOutput::LastStatus {
+                ok_value,
+                failed_value,
+            } => match shell.last_exit_status {
+                Some(status) if status != 0 => *failed_value,
+                _ => *ok_value,
+            },
+            ShellOutput::Command {
+                rules,
+                idle_value,
+                running_value,
+            } => {
+                let command = match &shell.running_command {
+                    Some(c) => c,
+                    None => return *idle_value,
+                };
+                rules
+                    .iter()
+                    .find(|rule| {
+                        rule.command_regex
+                            .as_ref()
+                            .is_none_or(|regex| regex.is_match(command))
+                            && rule
+                                .cwd_regex
+                                .as_ref()
+                                .is_none_or(|regex| regex.is_match(&shell.cwd))
+                    })
+                    .map(|rule| rule.value)
+                    .unwrap_or(*running_value)
+            }
+        }
+    }
+}
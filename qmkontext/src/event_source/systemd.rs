@@ -0,0 +1,153 @@
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+use zbus::blocking::{Connection, MessageIterator, Proxy, ProxyBuilder};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::{CacheProperties, MatchRule, MessageType};
+
+const SYSTEMD_SERVICE: &str = "org.freedesktop.systemd1";
+const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+const SYSTEMD_MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+const SYSTEMD_UNIT_INTERFACE: &str = "org.freedesktop.systemd1.Unit";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// State reported for `SystemdTarget::AnyFailed` when no unit has failed.
+pub const SYSTEMD_STATE_OK: &str = "ok";
+/// State reported for `SystemdTarget::AnyFailed` when at least one unit has failed.
+pub const SYSTEMD_STATE_FAILED: &str = "failed";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SystemdBus {
+    System,
+    User,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SystemdTarget {
+    /// Reports `failed` when any unit of the manager has failed, `ok` otherwise.
+    AnyFailed,
+    /// Reports the `ActiveState` of the unit (`active`, `failed`, `inactive`...).
+    Unit(String),
+}
+
+impl UserEventSource {
+    pub(super) fn loop_systemd(
+        bus: SystemdBus,
+        target: SystemdTarget,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) =
+                Self::watch_systemd(bus, &target, &mappings, &default_value, &source, &sender)
+            {
+                error!("error in systemd [{:?}]: {:?}", target, e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_systemd(
+        bus: SystemdBus,
+        target: &SystemdTarget,
+        mappings: &HashMap<String, Vec<u8>>,
+        default_value: &[u8],
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let connection = match bus {
+            SystemdBus::System => Connection::system()?,
+            SystemdBus::User => Connection::session()?,
+        };
+        let manager = Self::systemd_proxy(&connection, SYSTEMD_PATH, SYSTEMD_MANAGER_INTERFACE)?;
+        // systemd only emits unit signals while there are subscribed clients
+        manager.call_method("Subscribe", &())?;
+
+        let unit = match target {
+            SystemdTarget::AnyFailed => None,
+            SystemdTarget::Unit(name) => {
+                let path: OwnedObjectPath = manager.call("LoadUnit", &(name.as_str()))?;
+                Some(Self::systemd_proxy(
+                    &connection,
+                    path,
+                    SYSTEMD_UNIT_INTERFACE,
+                )?)
+            }
+        };
+
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(SYSTEMD_SERVICE)?
+            .interface(PROPERTIES_INTERFACE)?
+            .member("PropertiesChanged")?;
+        let rule = match &unit {
+            Some(proxy) => rule.path(proxy.path().to_owned())?,
+            None => rule.path_namespace(SYSTEMD_PATH)?,
+        };
+        let mut signals = MessageIterator::for_match_rule(rule.build(), &connection, None)?;
+
+        let mut last_state = None;
+        loop {
+            let state = match &unit {
+                Some(proxy) => proxy.get_property::<String>("ActiveState")?,
+                None => {
+                    let failed_units = manager.get_property::<u32>("NFailedUnits")?;
+                    if failed_units > 0 {
+                        SYSTEMD_STATE_FAILED.to_string()
+                    } else {
+                        SYSTEMD_STATE_OK.to_string()
+                    }
+                }
+            };
+            if last_state.as_ref() != Some(&state) {
+                let command_data = mappings
+                    .get(&state)
+                    .cloned()
+                    .unwrap_or_else(|| default_value.to_vec());
+                info!(
+                    "systemd [{:?}] state changed to {} ({:?})",
+                    target, state, command_data
+                );
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data,
+                };
+                let _ = sender.send(event);
+                last_state = Some(state);
+            }
+
+            match signals.next() {
+                Some(message) => {
+                    message?;
+                }
+                None => {
+                    return Err(Error::DbusError(
+                        "systemd signal stream finished".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The properties are read on every signal, so they are not cached: the cache is updated by
+    /// the same signals, and may not be up to date yet when they are received.
+    fn systemd_proxy<'a, P>(
+        connection: &Connection,
+        path: P,
+        interface: &'a str,
+    ) -> Result<Proxy<'a>>
+    where
+        P: TryInto<ObjectPath<'a>>,
+        P::Error: Into<zbus::Error>,
+    {
+        Ok(ProxyBuilder::new_bare(connection)
+            .destination(SYSTEMD_SERVICE)?
+            .path(path)?
+            .interface(interface)?
+            .cache_properties(CacheProperties::No)
+            .build()?)
+    }
+}
@@ -0,0 +1,180 @@
+use super::x11::X11Session;
+use crate::{Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use std::collections::HashSet;
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::ConnectionExt as RandrConnectionExt;
+use x11rb::protocol::xproto::{Atom, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window};
+
+/// `UrgencyHint` flag of the ICCCM `WM_HINTS` property.
+const WM_HINTS_URGENCY: u32 = 1 << 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WindowStateProperty {
+    /// Sends 1 if the focused window is fullscreen, 0 otherwise.
+    Fullscreen,
+    /// Sends 1 if any window has the urgency hint set, 0 otherwise.
+    Urgent,
+    /// Sends the index of the monitor that holds the focused window.
+    FocusedMonitor,
+}
+
+struct WindowStateAtoms {
+    active_window: Atom,
+    client_list: Atom,
+    wm_state: Atom,
+    wm_state_fullscreen: Atom,
+    wm_state_demands_attention: Atom,
+    wm_hints: Atom,
+}
+
+impl WindowStateAtoms {
+    fn new(session: &X11Session) -> Result<Self> {
+        Ok(Self {
+            active_window: session.atom("_NET_ACTIVE_WINDOW")?,
+            client_list: session.atom("_NET_CLIENT_LIST")?,
+            wm_state: session.atom("_NET_WM_STATE")?,
+            wm_state_fullscreen: session.atom("_NET_WM_STATE_FULLSCREEN")?,
+            wm_state_demands_attention: session.atom("_NET_WM_STATE_DEMANDS_ATTENTION")?,
+            wm_hints: session.atom("WM_HINTS")?,
+        })
+    }
+}
+
+impl UserEventSource {
+    pub(super) fn loop_window_state(
+        property: WindowStateProperty,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        loop {
+            if let Err(e) = Self::watch_window_state(property, &source, &sender) {
+                error!("error in window_state [{:?}]: {:?}", property, e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn watch_window_state(
+        property: WindowStateProperty,
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        let session = X11Session::connect()?;
+        let atoms = WindowStateAtoms::new(&session)?;
+        session.watch_root_properties()?;
+
+        let mut watched_windows = HashSet::new();
+        let mut last_value = None;
+        loop {
+            Self::watch_client_windows(&session, &atoms, &mut watched_windows)?;
+
+            let value = match property {
+                WindowStateProperty::Fullscreen => Self::is_fullscreen(&session, &atoms)? as u8,
+                WindowStateProperty::Urgent => Self::is_any_urgent(&session, &atoms)? as u8,
+                WindowStateProperty::FocusedMonitor => Self::focused_monitor(&session, &atoms)?,
+            };
+            if last_value != Some(value) {
+                debug!("Window state {:?} changed to {}", property, value);
+                let event = Event::Send {
+                    command_id: source.command_id,
+                    command_data: vec![value],
+                };
+                let _ = sender.send(event);
+                last_value = Some(value);
+            }
+
+            // Any event (focus change, property change, window moved) triggers a recheck
+            session.conn.flush()?;
+            session.conn.wait_for_event()?;
+            while session.conn.poll_for_event()?.is_some() {}
+        }
+    }
+
+    /// Subscribe to property and structure changes on every client window not watched yet.
+    fn watch_client_windows(
+        session: &X11Session,
+        atoms: &WindowStateAtoms,
+        watched_windows: &mut HashSet<Window>,
+    ) -> Result<()> {
+        let clients = session.get_u32_list(session.root, atoms.client_list)?;
+        watched_windows.retain(|w| clients.contains(w));
+        let aux = ChangeWindowAttributesAux::new()
+            .event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY);
+        for window in clients {
+            if watched_windows.insert(window) {
+                // Errors for windows destroyed in the meantime arrive as events and are ignored
+                session.conn.change_window_attributes(window, &aux)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn active_window(session: &X11Session, atoms: &WindowStateAtoms) -> Result<Option<Window>> {
+        Ok(session
+            .get_u32(session.root, atoms.active_window)?
+            .filter(|w| *w != 0))
+    }
+
+    fn is_fullscreen(session: &X11Session, atoms: &WindowStateAtoms) -> Result<bool> {
+        let window = match Self::active_window(session, atoms)? {
+            Some(w) => w,
+            None => return Ok(false),
+        };
+        let states = session
+            .get_u32_list(window, atoms.wm_state)
+            .unwrap_or_default();
+        Ok(states.contains(&atoms.wm_state_fullscreen))
+    }
+
+    fn is_any_urgent(session: &X11Session, atoms: &WindowStateAtoms) -> Result<bool> {
+        let clients = session.get_u32_list(session.root, atoms.client_list)?;
+        for window in clients {
+            let hints = session
+                .get_u32_list(window, atoms.wm_hints)
+                .unwrap_or_default();
+            if hints.first().map(|f| f & WM_HINTS_URGENCY != 0) == Some(true) {
+                return Ok(true);
+            }
+            let states = session
+                .get_u32_list(window, atoms.wm_state)
+                .unwrap_or_default();
+            if states.contains(&atoms.wm_state_demands_attention) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn focused_monitor(session: &X11Session, atoms: &WindowStateAtoms) -> Result<u8> {
+        let (x, y) = match Self::active_window(session, atoms)? {
+            Some(window) => {
+                let geometry = session.conn.get_geometry(window)?.reply()?;
+                let position = session
+                    .conn
+                    .translate_coordinates(window, session.root, 0, 0)?
+                    .reply()?;
+                (
+                    position.dst_x as i32 + geometry.width as i32 / 2,
+                    position.dst_y as i32 + geometry.height as i32 / 2,
+                )
+            }
+            None => {
+                // No focused window, use the monitor where the pointer is
+                let pointer = session.conn.query_pointer(session.root)?.reply()?;
+                (pointer.root_x as i32, pointer.root_y as i32)
+            }
+        };
+
+        let monitors = session
+            .conn
+            .randr_get_monitors(session.root, true)?
+            .reply()?;
+        let index = monitors.monitors.iter().position(|m| {
+            let (mx, my) = (m.x as i32, m.y as i32);
+            x >= mx && x < mx + m.width as i32 && y >= my && y < my + m.height as i32
+        });
+        Ok(index.unwrap_or_default() as u8)
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{Event, TextEncoding, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+
+impl UserEventSource {
+    pub(super) fn loop_window_title(
+        encoding: TextEncoding,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let mut last_text = None;
+        loop {
+            match Self::get_active_program_data() {
+                Ok(program) => {
+                    let text = encoding.encode(&program.name);
+                    if last_text.as_ref() != Some(&text) {
+                        debug!("Window title changed ({} bytes)", text.len());
+                        let event = Event::SendText {
+                            command_id: source.command_id,
+                            text: text.clone(),
+                        };
+                        let _ = sender.send(event);
+                        last_text = Some(text);
+                    }
+                }
+                Err(e) => error!("error in window_title : {:?}", e),
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+}
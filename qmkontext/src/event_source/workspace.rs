@@ -0,0 +1,263 @@
+use super::x11::X11Session;
+use crate::{Error, Event, Result, UserEventConfig, UserEventSource};
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event as X11Event;
+
+const I3_IPC_MAGIC: &[u8] = b"i3-ipc";
+const I3_IPC_GET_WORKSPACES: u32 = 1;
+const I3_IPC_SUBSCRIBE: u32 = 2;
+const I3_IPC_EVENT_WORKSPACE: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WorkspaceBackend {
+    /// Pick sway or Hyprland if their environment variables are set, X11 otherwise.
+    Auto,
+    X11,
+    Sway,
+    Hyprland,
+}
+
+struct WorkspaceData {
+    number: Option<i64>,
+    name: String,
+}
+
+impl UserEventSource {
+    pub(super) fn loop_workspace(
+        backend: WorkspaceBackend,
+        mappings: HashMap<String, Vec<u8>>,
+        default_value: Vec<u8>,
+        source: UserEventConfig,
+        sender: Sender<Event>,
+    ) {
+        let backend = Self::resolve_workspace_backend(backend);
+        info!("Using workspace backend {:?}", backend);
+        loop {
+            let mut on_change = |workspace: WorkspaceData| {
+                Self::send_workspace(&workspace, &mappings, &default_value, &source, &sender)
+            };
+            let result = match backend {
+                WorkspaceBackend::X11 | WorkspaceBackend::Auto => {
+                    Self::watch_x11_workspace(&mut on_change)
+                }
+                WorkspaceBackend::Sway => Self::watch_sway_workspace(&mut on_change),
+                WorkspaceBackend::Hyprland => Self::watch_hyprland_workspace(&mut on_change),
+            };
+            if let Err(e) = result {
+                error!("error in workspace : {:?}", e);
+            }
+
+            std::thread::sleep(source.interval.to_std().unwrap())
+        }
+    }
+
+    fn resolve_workspace_backend(backend: WorkspaceBackend) -> WorkspaceBackend {
+        if backend != WorkspaceBackend::Auto {
+            return backend;
+        }
+        if std::env::var_os("SWAYSOCK").is_some() {
+            WorkspaceBackend::Sway
+        } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            WorkspaceBackend::Hyprland
+        } else {
+            WorkspaceBackend::X11
+        }
+    }
+
+    fn send_workspace(
+        workspace: &WorkspaceData,
+        mappings: &HashMap<String, Vec<u8>>,
+        default_value: &[u8],
+        source: &UserEventConfig,
+        sender: &Sender<Event>,
+    ) {
+        debug!(
+            "Workspace number: {:?} | name: {}",
+            workspace.number, workspace.name
+        );
+        let command_data = match mappings.get(&workspace.name) {
+            Some(value) => value.clone(),
+            None => workspace
+                .number
+                .and_then(|n| u8::try_from(n).ok())
+                .map_or_else(|| default_value.to_vec(), |n| vec![n]),
+        };
+        info!("Found workspace {} ({:?})", workspace.name, command_data);
+        let event = Event::Send {
+            command_id: source.command_id,
+            command_data,
+        };
+        let _ = sender.send(event);
+    }
+}
+
+impl UserEventSource {
+    fn watch_x11_workspace(on_change: &mut dyn FnMut(WorkspaceData)) -> Result<()> {
+        let session = X11Session::connect()?;
+        let current_desktop = session.atom("_NET_CURRENT_DESKTOP")?;
+        let desktop_names = session.atom("_NET_DESKTOP_NAMES")?;
+        session.watch_root_properties()?;
+
+        on_change(Self::get_x11_workspace(
+            &session,
+            current_desktop,
+            desktop_names,
+        )?);
+        loop {
+            if let X11Event::PropertyNotify(e) = session.conn.wait_for_event()? {
+                if e.atom == current_desktop || e.atom == desktop_names {
+                    on_change(Self::get_x11_workspace(
+                        &session,
+                        current_desktop,
+                        desktop_names,
+                    )?);
+                }
+            }
+        }
+    }
+
+    fn get_x11_workspace(
+        session: &X11Session,
+        current_desktop: u32,
+        desktop_names: u32,
+    ) -> Result<WorkspaceData> {
+        let index = session
+            .get_u32(session.root, current_desktop)?
+            .ok_or_else(|| {
+                Error::CannotGetWorkspace("_NET_CURRENT_DESKTOP is not set".to_string())
+            })?;
+        let names = session.get_utf8_list(session.root, desktop_names)?;
+        Ok(WorkspaceData {
+            number: Some(index as i64),
+            name: names.get(index as usize).cloned().unwrap_or_default(),
+        })
+    }
+}
+
+impl UserEventSource {
+    fn watch_sway_workspace(on_change: &mut dyn FnMut(WorkspaceData)) -> Result<()> {
+        let socket_path = std::env::var("SWAYSOCK")
+            .map_err(|_| Error::CannotGetWorkspace("SWAYSOCK is not set".to_string()))?;
+
+        let mut events = UnixStream::connect(&socket_path)?;
+        Self::sway_write(&mut events, I3_IPC_SUBSCRIBE, br#"["workspace"]"#)?;
+        let (_, reply) = Self::sway_read(&mut events)?;
+        let reply: serde_json::Value = serde_json::from_slice(&reply)
+            .map_err(|e| Error::CannotGetWorkspace(format!("invalid subscribe reply: {e}")))?;
+        if reply["success"] != serde_json::Value::Bool(true) {
+            return Err(Error::CannotGetWorkspace(format!(
+                "cannot subscribe to sway workspace events: {reply}"
+            )));
+        }
+
+        on_change(Self::get_sway_workspace(&socket_path)?);
+        loop {
+            let (message_type, _) = Self::sway_read(&mut events)?;
+            if message_type == I3_IPC_EVENT_WORKSPACE {
+                on_change(Self::get_sway_workspace(&socket_path)?);
+            }
+        }
+    }
+
+    fn get_sway_workspace(socket_path: &str) -> Result<WorkspaceData> {
+        let mut stream = UnixStream::connect(socket_path)?;
+        Self::sway_write(&mut stream, I3_IPC_GET_WORKSPACES, &[])?;
+        let (_, reply) = Self::sway_read(&mut stream)?;
+        let workspaces: Vec<serde_json::Value> = serde_json::from_slice(&reply)
+            .map_err(|e| Error::CannotGetWorkspace(format!("invalid workspaces reply: {e}")))?;
+
+        let focused = workspaces
+            .iter()
+            .find(|w| w["focused"].as_bool().unwrap_or(false))
+            .ok_or_else(|| Error::CannotGetWorkspace("no focused sway workspace".to_string()))?;
+        Ok(WorkspaceData {
+            // Named-only workspaces report num = -1
+            number: focused["num"].as_i64().filter(|n| *n >= 0),
+            name: focused["name"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    fn sway_write(stream: &mut UnixStream, message_type: u32, payload: &[u8]) -> Result<()> {
+        let mut message = Vec::with_capacity(I3_IPC_MAGIC.len() + 8 + payload.len());
+        message.extend_from_slice(I3_IPC_MAGIC);
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(payload);
+        stream.write_all(&message)?;
+        Ok(())
+    }
+
+    fn sway_read(stream: &mut UnixStream) -> Result<(u32, Vec<u8>)> {
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header)?;
+        if &header[..6] != I3_IPC_MAGIC {
+            return Err(Error::CannotGetWorkspace(
+                "invalid sway ipc message".to_string(),
+            ));
+        }
+        let length = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+        let message_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+        let mut payload = vec![0u8; length as usize];
+        stream.read_exact(&mut payload)?;
+        Ok((message_type, payload))
+    }
+}
+
+impl UserEventSource {
+    fn watch_hyprland_workspace(on_change: &mut dyn FnMut(WorkspaceData)) -> Result<()> {
+        let socket_dir = Self::hyprland_socket_dir()?;
+        let events = UnixStream::connect(socket_dir.join(".socket2.sock"))?;
+
+        on_change(Self::get_hyprland_workspace(&socket_dir)?);
+        for line in BufReader::new(events).lines() {
+            let line = line?;
+            let event = line.split(">>").next().unwrap_or_default();
+            if matches!(
+                event,
+                "workspace" | "workspacev2" | "focusedmon" | "renameworkspace"
+            ) {
+                on_change(Self::get_hyprland_workspace(&socket_dir)?);
+            }
+        }
+
+        Err(Error::CannotGetWorkspace(
+            "hyprland event socket closed".to_string(),
+        ))
+    }
+
+    fn get_hyprland_workspace(socket_dir: &std::path::Path) -> Result<WorkspaceData> {
+        let mut stream = UnixStream::connect(socket_dir.join(".socket.sock"))?;
+        stream.write_all(b"j/activeworkspace")?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        let workspace: serde_json::Value = serde_json::from_slice(&reply).map_err(|e| {
+            Error::CannotGetWorkspace(format!("invalid activeworkspace reply: {e}"))
+        })?;
+
+        Ok(WorkspaceData {
+            // Special workspaces use negative ids
+            number: workspace["id"].as_i64().filter(|n| *n >= 0),
+            name: workspace["name"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    fn hyprland_socket_dir() -> Result<PathBuf> {
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE").map_err(|_| {
+            Error::CannotGetWorkspace("HYPRLAND_INSTANCE_SIGNATURE is not set".to_string())
+        })?;
+
+        // Newer Hyprland versions place the sockets under XDG_RUNTIME_DIR, older ones under /tmp
+        if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+            let path = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+        Ok(PathBuf::from("/tmp/hypr").join(signature))
+    }
+}
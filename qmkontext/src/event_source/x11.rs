@@ -0,0 +1,119 @@
+use crate::Result;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+};
+use x11rb::rust_connection::RustConnection;
+
+/// Maximum amount of 32-bit words requested when reading a property.
+const MAX_PROPERTY_LENGTH: u32 = 4096;
+
+/// Thin wrapper around an X11 connection with the helpers the X11 based sources need.
+pub(super) struct X11Session {
+    pub conn: RustConnection,
+    pub root: Window,
+}
+
+impl X11Session {
+    pub fn connect() -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        Ok(Self { conn, root })
+    }
+
+    pub fn atom(&self, name: &str) -> Result<Atom> {
+        Ok(self.conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+    }
+
+    /// Subscribe to PropertyNotify events on the root window.
+    pub fn watch_root_properties(&self) -> Result<()> {
+        let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        self.conn
+            .change_window_attributes(self.root, &aux)?
+            .check()?;
+        Ok(())
+    }
+
+    pub fn get_u32_list(&self, window: Window, atom: Atom) -> Result<Vec<u32>> {
+        let reply = self
+            .conn
+            .get_property(false, window, atom, AtomEnum::ANY, 0, MAX_PROPERTY_LENGTH)?
+            .reply()?;
+        Ok(reply
+            .value32()
+            .map(|values| values.collect())
+            .unwrap_or_default())
+    }
+
+    pub fn get_u32(&self, window: Window, atom: Atom) -> Result<Option<u32>> {
+        Ok(self.get_u32_list(window, atom)?.first().copied())
+    }
+
+    /// Reads a property made of NUL-separated UTF-8 strings, such as `_NET_DESKTOP_NAMES`.
+    pub fn get_utf8_list(&self, window: Window, atom: Atom) -> Result<Vec<String>> {
+        let reply = self
+            .conn
+            .get_property(false, window, atom, AtomEnum::ANY, 0, MAX_PROPERTY_LENGTH)?
+            .reply()?;
+        // Each string is NUL-terminated, so only the split after the last one is dropped. The
+        // empty strings in between are kept, as they are unnamed entries.
+        let value = reply.value.strip_suffix(&[0]).unwrap_or(&reply.value);
+        if value.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(value
+            .split(|b| *b == 0)
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect())
+    }
+
+    /// Returns the pid owning the active window, as set in `_NET_WM_PID`.
+    pub fn active_window_pid(&self) -> Result<Option<u32>> {
+        let window = match self.get_u32(self.root, self.atom("_NET_ACTIVE_WINDOW")?)? {
+            Some(w) if w != 0 => w,
+            _ => return Ok(None),
+        };
+        self.get_u32(window, self.atom("_NET_WM_PID")?)
+    }
+}
+
+/// Keeps a connection for querying the pid of the focused window, reconnecting when it fails.
+pub(super) struct FocusTracker {
+    session: Option<X11Session>,
+    warned: bool,
+}
+
+impl FocusTracker {
+    pub fn new() -> Self {
+        Self {
+            session: None,
+            warned: false,
+        }
+    }
+
+    /// Returns `None` when the focused window cannot be known, warning only once until it works again.
+    pub fn focused_pid(&mut self) -> Option<u32> {
+        let result = match &self.session {
+            Some(session) => session.active_window_pid(),
+            None => X11Session::connect().and_then(|session| {
+                let pid = session.active_window_pid();
+                self.session = Some(session);
+                pid
+            }),
+        };
+        match result {
+            Ok(pid) => {
+                self.warned = false;
+                pid
+            }
+            Err(e) => {
+                if !self.warned {
+                    warn!("Cannot get the focused window: {:?}", e);
+                    self.warned = true;
+                }
+                self.session = None;
+                None
+            }
+        }
+    }
+}
@@ -1,14 +1,33 @@
 #[macro_use]
 extern crate tracing;
 
+mod actions;
+mod browser;
+mod control;
+mod delivery;
 mod engine;
 mod error;
 mod event_sink;
 mod event_source;
+mod pomodoro;
+mod profiles;
+mod protocol;
+mod shell;
+mod text;
+mod via;
 
 #[derive(Clone, Debug)]
 pub enum Event {
-    Send { command_id: u8, command_data: u8 },
+    /// `command_data` holds at least 1 byte and fits in the reports of the keyboard
+    /// ([`MAX_PAYLOAD_LENGTH`] bytes with the default reports).
+    Send {
+        command_id: u8,
+        command_data: Vec<u8>,
+    },
+    /// `text` is already encoded (see [`TextEncoding`]), and is split across several reports.
+    SendText { command_id: u8, text: Vec<u8> },
+    /// Report sent by the keyboard to the host. `data` is the rest of the report.
+    Received { command_id: u8, data: Vec<u8> },
 }
 
 pub use chrono;
@@ -16,7 +35,35 @@ pub use hidapi;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub use engine::Engine;
+pub use actions::{ActionBinding, ActionHandler, HostAction};
+pub use browser::{BrowserState, BrowserTab};
+pub use control::{send_control_request, ControlRequest, ControlResponse, ControlServer};
+pub use delivery::{AckSettings, DeliveryStats, DeliveryStatus};
+pub use engine::{Engine, EventHandler};
 pub use error::Error;
-pub use event_sink::{CliSink, EventSink, HidEventSink, SendData};
-pub use event_source::{EventSource, UserEventConfig, UserEventSource, UserEventSourceKind};
+pub use event_sink::{
+    CliSink, EventSink, HidEventSink, RawHidDevice, ReportLayout, SendData, MAX_PAYLOAD_LENGTH,
+    REPORT_HEADER_LENGTH, REPORT_LENGTH,
+};
+pub use event_source::{
+    BrowserOutput, CalendarBuckets, ClipboardBackend, ClipboardRule, EventSource, FileWatchMode,
+    InputMethodBackend, MaildirBucket, NetworkCheck, NetworkValues, PomodoroOutput,
+    PomodoroPhaseValues, ProcessRule, ShellCommandRule, ShellOutput, SystemdBus, SystemdTarget,
+    UserEventConfig, UserEventSource, UserEventSourceKind, WindowStateProperty, WorkspaceBackend,
+    SYSTEMD_STATE_FAILED, SYSTEMD_STATE_OK,
+};
+pub use pomodoro::{
+    PomodoroAction, PomodoroPhase, PomodoroSettings, PomodoroStatus, PomodoroTimer,
+};
+pub use profiles::{MappingProfiles, DEFAULT_PROFILE};
+pub use protocol::{
+    DeviceInfo, ACK_COMMAND_ID, ACK_HEADER_LENGTH, BATCH_COMMAND_ID, BATCH_ENTRY_HEADER_LENGTH,
+    CAPABILITY_ACK, CAPABILITY_BATCH, HELLO_COMMAND_ID, HELLO_MAGIC, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, RESERVED_COMMAND_IDS,
+};
+pub use shell::{ShellEvent, ShellEventKind, ShellSession, ShellSessions};
+pub use text::{text_chunks, TextEncoding, MAX_TEXT_LENGTH, TEXT_CHUNK_LENGTH, TEXT_HEADER_LENGTH};
+pub use via::{
+    Framing, LightingSettings, ViaLighting, ViaLightingChannel, ViaLightingSink,
+    VIA_CUSTOM_SET_VALUE,
+};
@@ -0,0 +1,233 @@
+use crate::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PomodoroPhase {
+    Idle,
+    Work,
+    ShortBreak,
+    LongBreak,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PomodoroAction {
+    /// Starts a work phase when idle, or resumes the current phase when paused.
+    Start,
+    Pause,
+    /// Pauses the timer when running, starts it otherwise.
+    Toggle,
+    /// Goes back to idle and forgets the completed work phases.
+    Reset,
+    /// Only returns the status.
+    Status,
+}
+
+#[derive(Clone, Debug)]
+pub struct PomodoroSettings {
+    pub work: Duration,
+    pub short_break: Duration,
+    pub long_break: Duration,
+    /// Number of work phases after which a long break is taken instead of a short one.
+    pub work_phases_before_long_break: u32,
+    /// Starts the next phase as soon as the current one ends. Otherwise it is left paused.
+    pub auto_continue: bool,
+    /// File where the state is stored, so it survives restarts.
+    pub state_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PomodoroStatus {
+    pub phase: PomodoroPhase,
+    pub running: bool,
+    pub remaining_seconds: i64,
+    pub phase_seconds: i64,
+    pub completed_work_phases: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct PomodoroState {
+    phase: PomodoroPhase,
+    completed_work_phases: u32,
+    /// End of the current phase while running.
+    ends_at: Option<DateTime<Utc>>,
+    /// Remaining milliseconds of the current phase while paused.
+    remaining_ms: i64,
+}
+
+impl Default for PomodoroState {
+    fn default() -> Self {
+        Self {
+            phase: PomodoroPhase::Idle,
+            completed_work_phases: 0,
+            ends_at: None,
+            remaining_ms: 0,
+        }
+    }
+}
+
+/// Pomodoro timer shared between the control socket and the pomodoro sources.
+///
+/// The timer does not tick: the phase and the remaining time are computed from the
+/// wall clock whenever the status is requested, so a phase keeps running while the
+/// daemon is stopped.
+#[derive(Clone)]
+pub struct PomodoroTimer {
+    settings: Arc<PomodoroSettings>,
+    state: Arc<Mutex<PomodoroState>>,
+}
+
+impl PomodoroTimer {
+    pub fn new(settings: PomodoroSettings) -> Self {
+        let state = match &settings.state_path {
+            Some(path) => Self::load_state(path),
+            None => PomodoroState::default(),
+        };
+        Self {
+            settings: Arc::new(settings),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn apply(&self, action: PomodoroAction) -> PomodoroStatus {
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap();
+        let changed = self.advance(&mut state, now);
+        let running = state.ends_at.is_some();
+        match action {
+            PomodoroAction::Start => self.start(&mut state, now),
+            PomodoroAction::Pause => Self::pause(&mut state, now),
+            PomodoroAction::Toggle if running => Self::pause(&mut state, now),
+            PomodoroAction::Toggle => self.start(&mut state, now),
+            PomodoroAction::Reset => *state = PomodoroState::default(),
+            PomodoroAction::Status => {}
+        }
+        if changed || action != PomodoroAction::Status {
+            info!("Pomodoro {:?}: now in phase {:?}", action, state.phase);
+            self.save_state(&state);
+        }
+        self.status_of(&state, now)
+    }
+
+    pub fn status(&self) -> PomodoroStatus {
+        self.apply(PomodoroAction::Status)
+    }
+
+    fn start(&self, state: &mut PomodoroState, now: DateTime<Utc>) {
+        if state.ends_at.is_some() {
+            return;
+        }
+        if state.phase == PomodoroPhase::Idle {
+            state.phase = PomodoroPhase::Work;
+            state.remaining_ms = self.phase_duration(PomodoroPhase::Work).num_milliseconds();
+        }
+        state.ends_at = Some(now + Duration::milliseconds(state.remaining_ms));
+    }
+
+    fn pause(state: &mut PomodoroState, now: DateTime<Utc>) {
+        if let Some(ends_at) = state.ends_at.take() {
+            state.remaining_ms = (ends_at - now).num_milliseconds().max(0);
+        }
+    }
+
+    /// Moves to the next phases while the current one has already ended. Returns whether the phase changed.
+    fn advance(&self, state: &mut PomodoroState, now: DateTime<Utc>) -> bool {
+        let mut changed = false;
+        while let Some(ends_at) = state.ends_at {
+            if ends_at > now {
+                break;
+            }
+            changed = true;
+            let next_phase = match state.phase {
+                PomodoroPhase::Work => {
+                    state.completed_work_phases += 1;
+                    let long_break_every = self.settings.work_phases_before_long_break.max(1);
+                    if state.completed_work_phases.is_multiple_of(long_break_every) {
+                        PomodoroPhase::LongBreak
+                    } else {
+                        PomodoroPhase::ShortBreak
+                    }
+                }
+                _ => PomodoroPhase::Work,
+            };
+            let duration = self.phase_duration(next_phase);
+            state.phase = next_phase;
+            if self.settings.auto_continue {
+                // Chaining from the previous end keeps the phases aligned after a restart
+                state.ends_at = Some(ends_at + duration);
+            } else {
+                state.ends_at = None;
+                state.remaining_ms = duration.num_milliseconds();
+            }
+        }
+        changed
+    }
+
+    fn status_of(&self, state: &PomodoroState, now: DateTime<Utc>) -> PomodoroStatus {
+        let remaining_ms = match state.ends_at {
+            Some(ends_at) => (ends_at - now).num_milliseconds().max(0),
+            None => state.remaining_ms,
+        };
+        PomodoroStatus {
+            phase: state.phase,
+            running: state.ends_at.is_some(),
+            // Rounded up, so the last second of a phase is reported as 1
+            remaining_seconds: (remaining_ms + 999) / 1000,
+            phase_seconds: self.phase_duration(state.phase).num_seconds(),
+            completed_work_phases: state.completed_work_phases,
+        }
+    }
+
+    fn phase_duration(&self, phase: PomodoroPhase) -> Duration {
+        let duration = match phase {
+            PomodoroPhase::Idle => return Duration::zero(),
+            PomodoroPhase::Work => self.settings.work,
+            PomodoroPhase::ShortBreak => self.settings.short_break,
+            PomodoroPhase::LongBreak => self.settings.long_break,
+        };
+        duration.max(Duration::seconds(1))
+    }
+
+    fn load_state(path: &Path) -> PomodoroState {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("Cannot read pomodoro state {}: {:?}", path.display(), e);
+                return PomodoroState::default();
+            }
+        };
+        match serde_json::from_str(&contents) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Invalid pomodoro state {}: {:?}", path.display(), e);
+                PomodoroState::default()
+            }
+        }
+    }
+
+    fn save_state(&self, state: &PomodoroState) {
+        let path = match &self.settings.state_path {
+            Some(p) => p,
+            None => return,
+        };
+        if let Err(e) = Self::write_state(path, state) {
+            warn!("Cannot store pomodoro state {}: {:?}", path.display(), e);
+        }
+    }
+
+    fn write_state(path: &Path, state: &PomodoroState) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let contents =
+            serde_json::to_string(state).map_err(|e| Error::IoError(format!("{:?}", e)))?;
+        // Written through a rename, so a crash never leaves a half-written state
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
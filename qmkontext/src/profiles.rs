@@ -0,0 +1,66 @@
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Name of the profile holding the top-level `current_program` mappings.
+pub const DEFAULT_PROFILE: &str = "default";
+
+type Mappings = Arc<HashMap<String, Vec<u8>>>;
+
+/// Sets of current program mappings that can be switched at runtime, e.g. from the keyboard.
+#[derive(Clone)]
+pub struct MappingProfiles {
+    profiles: Arc<Vec<(String, Mappings)>>,
+    active: Arc<Mutex<usize>>,
+}
+
+impl MappingProfiles {
+    pub fn new(default_mappings: HashMap<String, Vec<u8>>) -> Self {
+        Self {
+            profiles: Arc::new(vec![(
+                DEFAULT_PROFILE.to_string(),
+                Arc::new(default_mappings),
+            )]),
+            active: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn with_profile(mut self, name: String, mappings: HashMap<String, Vec<u8>>) -> Self {
+        Arc::make_mut(&mut self.profiles).push((name, Arc::new(mappings)));
+        self
+    }
+
+    /// Mappings of the active profile.
+    pub fn mappings(&self) -> Mappings {
+        let active = *self.active.lock().unwrap();
+        self.profiles[active].1.clone()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.profiles.iter().any(|(profile, _)| profile == name)
+    }
+
+    pub fn active(&self) -> String {
+        let active = *self.active.lock().unwrap();
+        self.profiles[active].0.clone()
+    }
+
+    /// Switches to the given profile, or to the next one (in config order) when `None`.
+    /// Returns the name of the new active profile.
+    pub fn switch(&self, name: Option<&str>) -> Result<String> {
+        let mut active = self.active.lock().unwrap();
+        *active = match name {
+            Some(name) => self
+                .profiles
+                .iter()
+                .position(|(profile, _)| profile == name)
+                .ok_or_else(|| {
+                    Error::UserConfigExecutionError(format!("unknown profile {name}"))
+                })?,
+            None => (*active + 1) % self.profiles.len(),
+        };
+        let name = self.profiles[*active].0.clone();
+        info!("Switched to mapping profile {}", name);
+        Ok(name)
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{Error, Result, SendData, REPORT_HEADER_LENGTH};
+
+/// Version of the protocol spoken by this host.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Oldest firmware protocol version this host can talk to.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// Command id reserved for the handshake. It cannot be used by the sources.
+pub const HELLO_COMMAND_ID: u8 = 0xFF;
+/// Command id reserved for the commands sent in ack mode: `[ACK_COMMAND_ID, sequence, command_id,
+/// payload...]`. The firmware replies with `[ACK_COMMAND_ID, sequence, handled]`.
+pub const ACK_COMMAND_ID: u8 = 0xFE;
+/// Bytes taken by the ack header (`ACK_COMMAND_ID` and the sequence) in front of the command.
+pub const ACK_HEADER_LENGTH: usize = 2;
+/// Command id reserved for the batches of commands: `[BATCH_COMMAND_ID, count, (command_id,
+/// length, payload...) * count]`.
+pub const BATCH_COMMAND_ID: u8 = 0xFD;
+/// Bytes taken by the command id and the length of every command in a batch.
+pub const BATCH_ENTRY_HEADER_LENGTH: usize = 2;
+/// Bytes before the first command of a batch: `BATCH_COMMAND_ID` and the count.
+const BATCH_HEADER_LENGTH: usize = 2;
+/// Command ids that cannot be used by the sources.
+pub const RESERVED_COMMAND_IDS: [u8; 3] = [BATCH_COMMAND_ID, ACK_COMMAND_ID, HELLO_COMMAND_ID];
+/// Capability flag: the firmware acknowledges the commands sent in ack mode.
+pub const CAPABILITY_ACK: u8 = 1 << 0;
+/// Capability flag: the firmware unpacks the batches of commands.
+pub const CAPABILITY_BATCH: u8 = 1 << 1;
+
+/// Sent after the command id in the replies, so they are not mistaken for the echo of a
+/// firmware that does not know the handshake.
+pub const HELLO_MAGIC: [u8; 2] = *b"QK";
+
+const HELLO_PAGES: usize = 2;
+const HELLO_PAGE_COMMANDS: usize = 128;
+const HELLO_BITMAP_OFFSET: usize = 6;
+const HELLO_CAPABILITIES_OFFSET: usize = HELLO_BITMAP_OFFSET + HELLO_PAGE_COMMANDS / 8;
+
+/// What the firmware reported during the handshake.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub protocol_version: u8,
+    /// Length of the messages read by the firmware: the report without the report id and the
+    /// `QMKONTEXT_REPORT_HEADER`.
+    pub max_message_length: u8,
+    /// `CAPABILITY_*` flags. Firmwares predating them report none.
+    pub capabilities: u8,
+    handled_commands: [bool; 256],
+}
+
+impl DeviceInfo {
+    pub fn is_compatible(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version)
+    }
+
+    pub fn handles(&self, command_id: u8) -> bool {
+        self.handled_commands[command_id as usize]
+    }
+
+    pub fn supports(&self, capability: u8) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+/// Packs the commands in as few batches as possible. Commands left alone in a batch are sent as
+/// they are, as well as the ones the firmware would reject in a batch.
+pub(crate) fn batch(commands: &[SendData], max_payload_length: usize) -> Vec<SendData> {
+    // Length of the batch message as read by qmkontext_on_batch: the command id and the payload
+    let message_length = REPORT_HEADER_LENGTH + max_payload_length;
+    let mut sends = Vec::new();
+    let mut batched: Vec<&SendData> = Vec::new();
+    // Offset of the next entry in the message, after the command id and the count
+    let mut offset = BATCH_HEADER_LENGTH;
+    for command in commands {
+        let payload_length = command.data.len();
+        let entry_length = BATCH_ENTRY_HEADER_LENGTH + payload_length;
+        // Same checks as qmkontext_on_batch, which rejects the entries ending after the message
+        // and the payloads not shorter than the message. Left to `send`, which rejects the
+        // invalid payloads
+        if payload_length == 0
+            || BATCH_HEADER_LENGTH + entry_length > message_length
+            || payload_length >= message_length
+        {
+            flush_batch(&mut batched, &mut sends);
+            offset = BATCH_HEADER_LENGTH;
+            sends.push(command.clone());
+            continue;
+        }
+        if offset + entry_length > message_length {
+            flush_batch(&mut batched, &mut sends);
+            offset = BATCH_HEADER_LENGTH;
+        }
+        batched.push(command);
+        offset += entry_length;
+    }
+    flush_batch(&mut batched, &mut sends);
+    sends
+}
+
+fn flush_batch(batched: &mut Vec<&SendData>, sends: &mut Vec<SendData>) {
+    match batched.as_slice() {
+        [] => {}
+        [command] => sends.push((*command).clone()),
+        commands => {
+            let mut data = vec![commands.len() as u8];
+            for command in commands {
+                data.push(command.command_id);
+                data.push(command.data.len() as u8);
+                data.extend_from_slice(&command.data);
+            }
+            sends.push(SendData {
+                command_id: BATCH_COMMAND_ID,
+                data,
+            });
+        }
+    }
+    batched.clear();
+}
+
+/// Builds the hello report, without the report id.
+pub(crate) fn hello_report() -> Vec<u8> {
+    vec![HELLO_COMMAND_ID, PROTOCOL_VERSION]
+}
+
+/// Collects the handshake replies. The handled command ids are sent as a bitmap split in two
+/// pages, one per report:
+/// `[HELLO_COMMAND_ID, 'Q', 'K', protocol_version, max_message_length, page, bitmap[16], capabilities]`
+#[derive(Default)]
+pub(crate) struct HelloReplies {
+    protocol_version: u8,
+    max_message_length: u8,
+    capabilities: u8,
+    handled_commands: Vec<bool>,
+    received_pages: [bool; HELLO_PAGES],
+}
+
+impl HelloReplies {
+    /// Returns whether the report was a handshake reply. Other reports are ignored.
+    pub(crate) fn push(&mut self, report: &[u8]) -> Result<bool> {
+        if report.len() < HELLO_CAPABILITIES_OFFSET
+            || report[0] != HELLO_COMMAND_ID
+            || report[1..3] != HELLO_MAGIC
+        {
+            return Ok(false);
+        }
+
+        let page = report[5] as usize;
+        if page >= HELLO_PAGES {
+            return Err(Error::ProtocolError(format!(
+                "invalid handshake page {page}"
+            )));
+        }
+        if self.handled_commands.is_empty() {
+            self.handled_commands = vec![false; HELLO_PAGES * HELLO_PAGE_COMMANDS];
+        }
+        self.protocol_version = report[3];
+        self.max_message_length = report[4];
+        self.capabilities = report
+            .get(HELLO_CAPABILITIES_OFFSET)
+            .copied()
+            .unwrap_or_default();
+        for i in 0..HELLO_PAGE_COMMANDS {
+            let byte = report[HELLO_BITMAP_OFFSET + i / 8];
+            self.handled_commands[page * HELLO_PAGE_COMMANDS + i] = byte & (1 << (i % 8)) != 0;
+        }
+        self.received_pages[page] = true;
+        Ok(true)
+    }
+
+    pub(crate) fn device_info(&self) -> Option<DeviceInfo> {
+        if !self.received_pages.iter().all(|received| *received) {
+            return None;
+        }
+        let mut handled_commands = [false; 256];
+        handled_commands.copy_from_slice(&self.handled_commands);
+        Some(DeviceInfo {
+            protocol_version: self.protocol_version,
+            max_message_length: self.max_message_length,
+            capabilities: self.capabilities,
+            handled_commands,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_PAYLOAD_LENGTH;
+
+    fn command(command_id: u8, length: usize) -> SendData {
+        SendData {
+            command_id,
+            data: vec![command_id; length],
+        }
+    }
+
+    fn summary(sends: &[SendData]) -> Vec<(u8, usize)> {
+        sends.iter().map(|s| (s.command_id, s.data.len())).collect()
+    }
+
+    #[test]
+    fn packs_the_commands() {
+        let sends = batch(&[command(1, 1), command(2, 3)], MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0].command_id, BATCH_COMMAND_ID);
+        assert_eq!(sends[0].data, vec![2, 1, 1, 1, 2, 3, 2, 2, 2]);
+    }
+
+    #[test]
+    fn single_commands_are_sent_as_they_are() {
+        let sends = batch(&[command(1, 4)], MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0].command_id, 1);
+        assert_eq!(sends[0].data, vec![1; 4]);
+        assert!(batch(&[], MAX_PAYLOAD_LENGTH).is_empty());
+    }
+
+    #[test]
+    fn fills_the_batches_up_to_the_max_payload_length() {
+        // The count and two entries of 2 + 13 bytes fill the 31 bytes
+        let full = [command(1, 13), command(2, 13)];
+        let sends = batch(&full, MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(
+            summary(&sends),
+            vec![(BATCH_COMMAND_ID, MAX_PAYLOAD_LENGTH)]
+        );
+
+        // One more byte starts a new batch, where the second command is alone
+        let over = [command(1, 13), command(2, 14)];
+        let sends = batch(&over, MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(summary(&sends), vec![(1, 13), (2, 14)]);
+
+        let sends = batch(
+            &[command(1, 13), command(2, 14), command(3, 1)],
+            MAX_PAYLOAD_LENGTH,
+        );
+
+        assert_eq!(
+            summary(&sends),
+            vec![(1, 13), (BATCH_COMMAND_ID, 1 + 16 + 3)]
+        );
+    }
+
+    #[test]
+    fn commands_too_long_for_a_batch() {
+        // The longest payload qmkontext_on_batch accepts
+        let longest = MAX_PAYLOAD_LENGTH - 1 - BATCH_ENTRY_HEADER_LENGTH;
+        let sends = batch(&[command(1, longest), command(2, 1)], MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(summary(&sends), vec![(1, longest), (2, 1)]);
+
+        // Sent alone, without breaking the order of the batches
+        let commands = [
+            command(1, 1),
+            command(2, 1),
+            command(3, longest + 1),
+            command(4, 1),
+            command(5, 1),
+        ];
+        let sends = batch(&commands, MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(
+            summary(&sends),
+            vec![
+                (BATCH_COMMAND_ID, 7),
+                (3, longest + 1),
+                (BATCH_COMMAND_ID, 7)
+            ]
+        );
+        assert_eq!(sends[2].data, vec![2, 4, 1, 4, 5, 1, 5]);
+    }
+
+    #[test]
+    fn empty_payloads_are_left_to_send() {
+        let sends = batch(
+            &[command(1, 1), command(2, 0), command(3, 1)],
+            MAX_PAYLOAD_LENGTH,
+        );
+
+        assert_eq!(summary(&sends), vec![(1, 1), (2, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn smaller_payloads_in_ack_mode() {
+        let max_payload_length = MAX_PAYLOAD_LENGTH - ACK_HEADER_LENGTH;
+        let sends = batch(&[command(1, 12), command(2, 12)], max_payload_length);
+
+        assert_eq!(
+            summary(&sends),
+            vec![(BATCH_COMMAND_ID, max_payload_length)]
+        );
+
+        let sends = batch(&[command(1, 12), command(2, 13)], max_payload_length);
+
+        assert_eq!(summary(&sends), vec![(1, 12), (2, 13)]);
+    }
+}
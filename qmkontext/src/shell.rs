@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShellEventKind {
+    /// A command is about to be run.
+    Preexec,
+    /// A command finished and the prompt is going to be shown.
+    Precmd,
+}
+
+/// Event reported by the shell hooks printed by `shell-init`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ShellEvent {
+    pub kind: ShellEventKind,
+    /// Pid of the shell.
+    pub pid: u32,
+    pub cwd: String,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub exit_status: Option<i32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShellSession {
+    pub running_command: Option<String>,
+    pub last_exit_status: Option<i32>,
+    pub cwd: String,
+    pub updated_at: Instant,
+}
+
+/// State of the shells reporting to the daemon, shared between the control socket and the shell sources.
+#[derive(Clone, Default)]
+pub struct ShellSessions {
+    sessions: Arc<Mutex<HashMap<u32, ShellSession>>>,
+}
+
+impl ShellSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&self, event: ShellEvent) {
+        // The command may contain secrets, so it is never logged
+        debug!("Shell {} reported {:?}", event.pid, event.kind);
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(event.pid).or_insert_with(|| ShellSession {
+            running_command: None,
+            last_exit_status: None,
+            cwd: String::new(),
+            updated_at: Instant::now(),
+        });
+        match event.kind {
+            ShellEventKind::Preexec => session.running_command = event.command,
+            ShellEventKind::Precmd => {
+                session.running_command = None;
+                session.last_exit_status = event.exit_status;
+            }
+        }
+        session.cwd = event.cwd;
+        session.updated_at = Instant::now();
+    }
+
+    /// Returns the sessions of the shells that are still running.
+    pub fn sessions(&self) -> HashMap<u32, ShellSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|pid, _| Path::new(&format!("/proc/{pid}")).exists());
+        sessions.clone()
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{SendData, MAX_PAYLOAD_LENGTH};
+use std::collections::HashMap;
+
+/// Bytes at the start of every text chunk: the chunk index and the total length of the text.
+pub const TEXT_HEADER_LENGTH: usize = 2;
+/// Text bytes carried by each chunk with the default reports.
+pub const TEXT_CHUNK_LENGTH: usize = MAX_PAYLOAD_LENGTH - TEXT_HEADER_LENGTH;
+/// The total length is sent in a single byte.
+pub const MAX_TEXT_LENGTH: usize = u8::MAX as usize;
+
+/// How text is converted into the bytes understood by the keyboard display.
+#[derive(Clone, Debug)]
+pub struct TextEncoding {
+    /// Texts are truncated to this number of bytes (at most [`MAX_TEXT_LENGTH`]).
+    pub max_length: usize,
+    /// Characters of the keyboard font outside ASCII, mapped to their glyph index.
+    pub glyphs: HashMap<char, u8>,
+    /// Byte sent for the characters that cannot be represented.
+    pub replacement: u8,
+}
+
+impl Default for TextEncoding {
+    fn default() -> Self {
+        Self {
+            max_length: MAX_TEXT_LENGTH,
+            glyphs: HashMap::new(),
+            replacement: b'?',
+        }
+    }
+}
+
+impl TextEncoding {
+    /// Maps every character to a glyph, to printable ASCII, or to its ASCII transliteration.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let max_length = self.max_length.min(MAX_TEXT_LENGTH);
+        let mut encoded = Vec::with_capacity(text.len().min(max_length));
+        for c in text.chars() {
+            if let Some(glyph) = self.glyphs.get(&c) {
+                encoded.push(*glyph);
+            } else if c == ' ' || c.is_ascii_graphic() {
+                encoded.push(c as u8);
+            } else if c.is_whitespace() || c.is_control() {
+                encoded.push(b' ');
+            } else {
+                match transliterate(c) {
+                    Some(replacement) => encoded.extend_from_slice(replacement.as_bytes()),
+                    None => encoded.push(self.replacement),
+                }
+            }
+            if encoded.len() >= max_length {
+                encoded.truncate(max_length);
+                break;
+            }
+        }
+        encoded
+    }
+}
+
+/// Splits an encoded text into the payloads sent for `command_id`. Every payload starts with
+/// the chunk index and the total length, so the firmware can reassemble the text.
+pub fn text_chunks(command_id: u8, text: &[u8], max_payload_length: usize) -> Vec<SendData> {
+    let text = &text[..text.len().min(MAX_TEXT_LENGTH)];
+    let total_length = text.len() as u8;
+    if text.is_empty() {
+        return vec![SendData {
+            command_id,
+            data: vec![0, 0],
+        }];
+    }
+
+    text.chunks(max_payload_length.saturating_sub(TEXT_HEADER_LENGTH).max(1))
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut data = Vec::with_capacity(TEXT_HEADER_LENGTH + chunk.len());
+            data.push(index as u8);
+            data.push(total_length);
+            data.extend_from_slice(chunk);
+            SendData { command_id, data }
+        })
+        .collect()
+}
+
+fn transliterate(c: char) -> Option<&'static str> {
+    let ascii = match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ç' | 'ć' | 'č' => "c",
+        'Ď' | 'Đ' | 'Ð' => "D",
+        'ď' | 'đ' | 'ð' => "d",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'Ğ' => "G",
+        'ğ' => "g",
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ī' | 'İ' => "I",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'ı' => "i",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ř' => "R",
+        'ř' => "r",
+        'Ś' | 'Š' | 'Ş' => "S",
+        'ś' | 'š' | 'ş' => "s",
+        'ß' => "ss",
+        'Ť' | 'Ţ' => "T",
+        'ť' | 'ţ' => "t",
+        'Þ' => "TH",
+        'þ' => "th",
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ū' | 'Ů' | 'Ű' => "U",
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '″' | '«' | '»' => "\"",
+        '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' => "-",
+        '…' => "...",
+        '•' | '·' => "*",
+        '×' => "x",
+        '€' => "EUR",
+        '£' => "GBP",
+        '©' => "(c)",
+        '®' => "(R)",
+        '™' => "TM",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reassemble(chunks: &[SendData]) -> Vec<u8> {
+        chunks
+            .iter()
+            .flat_map(|c| c.data[TEXT_HEADER_LENGTH..].iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn encode_ascii() {
+        let encoding = TextEncoding::default();
+        assert_eq!(encoding.encode("Hello, world!"), b"Hello, world!");
+        assert_eq!(encoding.encode("a\tb\nc"), b"a b c");
+    }
+
+    #[test]
+    fn encode_non_ascii() {
+        let encoding = TextEncoding {
+            glyphs: HashMap::from([('♥', 0x80)]),
+            replacement: b'#',
+            ..TextEncoding::default()
+        };
+        assert_eq!(encoding.encode("I ♥ Café"), b"I \x80 Cafe");
+        assert_eq!(encoding.encode("Straße … €"), b"Strasse ... EUR");
+        assert_eq!(encoding.encode("日本"), b"##");
+    }
+
+    #[test]
+    fn encode_truncates() {
+        let encoding = TextEncoding {
+            max_length: 4,
+            ..TextEncoding::default()
+        };
+        assert_eq!(encoding.encode("abcdef"), b"abcd");
+        // Transliterations are cut too
+        assert_eq!(encoding.encode("abc€"), b"abcE");
+
+        let encoding = TextEncoding {
+            max_length: 1000,
+            ..TextEncoding::default()
+        };
+        assert_eq!(encoding.encode(&"a".repeat(300)).len(), MAX_TEXT_LENGTH);
+    }
+
+    #[test]
+    fn chunks_of_a_long_title() {
+        let title = TextEncoding::default().encode(&"x".repeat(120));
+        let chunks = text_chunks(25, &title, MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(chunks.len(), 5);
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.command_id, 25);
+            assert_eq!(chunk.data[0], index as u8);
+            assert_eq!(chunk.data[1], 120);
+            assert!(chunk.data.len() <= MAX_PAYLOAD_LENGTH);
+        }
+        assert_eq!(chunks[0].data.len(), MAX_PAYLOAD_LENGTH);
+        assert_eq!(
+            chunks[4].data.len(),
+            TEXT_HEADER_LENGTH + 120 - 4 * TEXT_CHUNK_LENGTH
+        );
+        assert_eq!(reassemble(&chunks), title);
+    }
+
+    #[test]
+    fn chunks_with_smaller_payloads() {
+        let title: Vec<u8> = (0..60).collect();
+        // The ack header takes 2 bytes of every payload
+        let chunks = text_chunks(25, &title, MAX_PAYLOAD_LENGTH - 2);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].data.len(), MAX_PAYLOAD_LENGTH - 2);
+        assert_eq!(reassemble(&chunks), title);
+    }
+
+    #[test]
+    fn chunks_of_an_empty_text() {
+        let chunks = text_chunks(25, &[], MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data, vec![0, 0]);
+    }
+
+    #[test]
+    fn chunks_are_limited_to_the_max_text_length() {
+        let chunks = text_chunks(25, &[b'a'; 300], MAX_PAYLOAD_LENGTH);
+
+        assert!(chunks.iter().all(|c| c.data[1] as usize == MAX_TEXT_LENGTH));
+        assert_eq!(reassemble(&chunks).len(), MAX_TEXT_LENGTH);
+    }
+}
@@ -0,0 +1,442 @@
+use crate::event_sink::open_device;
+use crate::{EventSink, RawHidDevice, ReportLayout, Result, SendData};
+use hidapi::{HidApi, HidDevice};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// VIA command forwarding a value to a channel: `[VIA_CUSTOM_SET_VALUE, channel, value_id, value...]`.
+/// The channels unknown to VIA are forwarded to `via_custom_value_command_kb`.
+pub const VIA_CUSTOM_SET_VALUE: u8 = 0x07;
+/// Command ids of VIA (`id_get_protocol_version` to `id_dynamic_keymap_set_encoder`).
+const VIA_COMMAND_IDS: std::ops::RangeInclusive<u8> = 0x01..=0x15;
+/// Prefix of the Vial commands.
+const VIAL_PREFIX: u8 = 0xFE;
+/// Replaces the command id of the commands VIA does not handle.
+const VIA_UNHANDLED: u8 = 0xFF;
+/// Channels handled by VIA itself (backlight, rgblight, rgb matrix, audio and led matrix).
+const VIA_QMK_CHANNELS: std::ops::RangeInclusive<u8> = 0x01..=0x05;
+const VIA_RGBLIGHT_CHANNEL: u8 = 0x02;
+const VIA_RGB_MATRIX_CHANNEL: u8 = 0x03;
+// Value ids shared by the rgblight and rgb matrix channels
+const VIA_LIGHTING_BRIGHTNESS: u8 = 0x01;
+const VIA_LIGHTING_EFFECT: u8 = 0x02;
+const VIA_LIGHTING_EFFECT_SPEED: u8 = 0x03;
+const VIA_LIGHTING_COLOR: u8 = 0x04;
+/// How long to wait for VIA to send a command back, which tells whether it was handled.
+const VIA_ECHO_TIMEOUT_MILLIS: i32 = 50;
+
+/// How the qmkontext messages are framed on the raw HID interface, which VIA and Vial also use.
+#[derive(Clone, Copy, Debug)]
+pub enum Framing {
+    /// The command id goes first, as with keyboards without VIA.
+    Raw,
+    /// Messages are prefixed with a byte that is not a VIA or Vial command, so VIA forwards them
+    /// to `raw_hid_receive_kb`.
+    Prefix(u8),
+    /// Messages are sent as `id_custom_set_value` to a channel unknown to VIA, so VIA forwards
+    /// them to `via_custom_value_command_kb`.
+    Via { channel: u8 },
+}
+
+impl Framing {
+    /// Bytes sent before the command id.
+    pub fn header(&self) -> Vec<u8> {
+        match self {
+            Framing::Raw => Vec::new(),
+            Framing::Prefix(prefix) => vec![*prefix],
+            Framing::Via { channel } => vec![VIA_CUSTOM_SET_VALUE, *channel],
+        }
+    }
+
+    /// Returns whether the messages reach qmkontext instead of being handled by VIA or Vial.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Framing::Raw => true,
+            Framing::Prefix(prefix) => {
+                !VIA_COMMAND_IDS.contains(prefix)
+                    && *prefix != VIAL_PREFIX
+                    && *prefix != VIA_UNHANDLED
+            }
+            Framing::Via { channel } => !VIA_QMK_CHANNELS.contains(channel),
+        }
+    }
+}
+
+/// Lighting feature driven through VIA.
+#[derive(Clone, Copy, Debug)]
+pub enum ViaLightingChannel {
+    Rgblight,
+    RgbMatrix,
+}
+
+impl ViaLightingChannel {
+    fn channel_id(&self) -> u8 {
+        match self {
+            ViaLightingChannel::Rgblight => VIA_RGBLIGHT_CHANNEL,
+            ViaLightingChannel::RgbMatrix => VIA_RGB_MATRIX_CHANNEL,
+        }
+    }
+}
+
+/// Lighting set for a value. The unset fields are left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct LightingSettings {
+    /// Effect index, 0 turns the lighting off.
+    pub effect: Option<u8>,
+    pub effect_speed: Option<u8>,
+    /// Hue and saturation.
+    pub color: Option<(u8, u8)>,
+    pub brightness: Option<u8>,
+}
+
+impl LightingSettings {
+    /// `(value_id, value)` of the VIA commands, the effect first as it may reset the others.
+    fn values(&self) -> Vec<(u8, Vec<u8>)> {
+        let mut values = Vec::new();
+        if let Some(effect) = self.effect {
+            values.push((VIA_LIGHTING_EFFECT, vec![effect]));
+        }
+        if let Some(effect_speed) = self.effect_speed {
+            values.push((VIA_LIGHTING_EFFECT_SPEED, vec![effect_speed]));
+        }
+        if let Some((hue, saturation)) = self.color {
+            values.push((VIA_LIGHTING_COLOR, vec![hue, saturation]));
+        }
+        if let Some(brightness) = self.brightness {
+            values.push((VIA_LIGHTING_BRIGHTNESS, vec![brightness]));
+        }
+        values
+    }
+}
+
+/// Lighting of each value sent by the sources, keyed by command id and payload.
+#[derive(Clone, Debug)]
+pub struct ViaLighting {
+    pub channel: ViaLightingChannel,
+    pub mappings: Arc<HashMap<(u8, Vec<u8>), LightingSettings>>,
+}
+
+/// Turns the values into VIA `id_custom_set_value` lighting commands, so keyboards running stock
+/// VIA firmware need no qmkontext code. The changes are not saved to the EEPROM.
+pub struct ViaLightingSink<Device: RawHidDevice = HidDevice> {
+    device: Device,
+    layout: ReportLayout,
+    lighting: ViaLighting,
+}
+
+impl ViaLightingSink<HidDevice> {
+    pub fn open(
+        vid: u16,
+        pid: u16,
+        usage: u16,
+        usage_page: u16,
+        lighting: ViaLighting,
+    ) -> Result<Self> {
+        let api = HidApi::new()?;
+        let device = open_device(&api, vid, pid, usage, usage_page)?;
+        Ok(Self::new(device, lighting))
+    }
+}
+
+impl<Device: RawHidDevice> ViaLightingSink<Device> {
+    pub fn new(device: Device, lighting: ViaLighting) -> Self {
+        Self {
+            device,
+            layout: ReportLayout::default(),
+            lighting,
+        }
+    }
+
+    /// Only the report length, report id and padding are used, VIA reads the commands from the
+    /// first byte.
+    pub fn with_layout(mut self, layout: ReportLayout) -> Self {
+        self.layout = ReportLayout {
+            header: Vec::new(),
+            ..layout
+        };
+        self
+    }
+
+    fn set_value(&self, value_id: u8, value: &[u8]) -> Result<()> {
+        let channel = self.lighting.channel.channel_id();
+        let mut command = vec![VIA_CUSTOM_SET_VALUE, channel, value_id];
+        command.extend_from_slice(value);
+        self.device.write(&self.layout.report(&command))?;
+
+        // VIA sends every command back, with id_unhandled when it is not handled
+        let mut report = vec![0u8; 1 + self.layout.report_length];
+        loop {
+            let read = self
+                .device
+                .read_timeout(&mut report, VIA_ECHO_TIMEOUT_MILLIS)?;
+            if read == 0 {
+                debug!("VIA did not send back value_id={}", value_id);
+                return Ok(());
+            }
+            match self.layout.message(&report[..read]) {
+                Some([VIA_CUSTOM_SET_VALUE, echo_channel, echo_value_id, ..])
+                    if *echo_channel == channel && *echo_value_id == value_id =>
+                {
+                    return Ok(());
+                }
+                Some([VIA_UNHANDLED, echo_channel, echo_value_id, ..])
+                    if *echo_channel == channel && *echo_value_id == value_id =>
+                {
+                    warn!(
+                        "The keyboard does not handle the VIA {:?} channel",
+                        self.lighting.channel
+                    );
+                    return Ok(());
+                }
+                // Reports for other programs using the interface
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl<Device: RawHidDevice> EventSink for ViaLightingSink<Device> {
+    fn send(&self, data: &SendData) -> Result<()> {
+        let settings = match self
+            .lighting
+            .mappings
+            .get(&(data.command_id, data.data.clone()))
+        {
+            Some(settings) => settings,
+            None => {
+                debug!(
+                    "No lighting for command_id={} | data={:?}",
+                    data.command_id, data.data
+                );
+                return Ok(());
+            }
+        };
+        debug!(
+            "Setting lighting for command_id={} | data={:?}: {:?}",
+            data.command_id, data.data, settings
+        );
+        for (value_id, value) in settings.values() {
+            self.set_value(value_id, &value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// Device queuing the reports returned by `respond` for every report written.
+    struct FakeDevice<Respond: Fn(&[u8]) -> Vec<Vec<u8>>> {
+        written: RefCell<Vec<Vec<u8>>>,
+        reports: RefCell<VecDeque<Vec<u8>>>,
+        respond: Respond,
+    }
+
+    impl<Respond: Fn(&[u8]) -> Vec<Vec<u8>>> FakeDevice<Respond> {
+        fn new(respond: Respond) -> Self {
+            Self {
+                written: RefCell::new(Vec::new()),
+                reports: RefCell::new(VecDeque::new()),
+                respond,
+            }
+        }
+    }
+
+    impl<Respond: Fn(&[u8]) -> Vec<Vec<u8>>> RawHidDevice for FakeDevice<Respond> {
+        fn write(&self, report: &[u8]) -> Result<usize> {
+            self.written.borrow_mut().push(report.to_vec());
+            // VIA reads the reports without the report id
+            let replies = (self.respond)(&report[1..]);
+            self.reports.borrow_mut().extend(replies);
+            Ok(report.len())
+        }
+
+        fn read_timeout(&self, report: &mut [u8], _timeout_millis: i32) -> Result<usize> {
+            match self.reports.borrow_mut().pop_front() {
+                Some(reply) => {
+                    report[..reply.len()].copy_from_slice(&reply);
+                    Ok(reply.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn lighting(settings: LightingSettings) -> ViaLighting {
+        ViaLighting {
+            channel: ViaLightingChannel::RgbMatrix,
+            mappings: Arc::new(HashMap::from([((1, vec![2]), settings)])),
+        }
+    }
+
+    fn all_settings() -> LightingSettings {
+        LightingSettings {
+            effect: Some(4),
+            effect_speed: Some(100),
+            color: Some((85, 255)),
+            brightness: Some(200),
+        }
+    }
+
+    fn value(command_id: u8, data: Vec<u8>) -> SendData {
+        SendData { command_id, data }
+    }
+
+    #[test]
+    fn sets_the_values_in_order() {
+        let device = FakeDevice::new(|report| vec![report.to_vec()]);
+        let sink = ViaLightingSink::new(device, lighting(all_settings()));
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        let written = sink.device.written.borrow();
+        let commands: Vec<&[u8]> = written.iter().map(|report| &report[..6]).collect();
+        assert_eq!(
+            commands,
+            vec![
+                &[
+                    0,
+                    VIA_CUSTOM_SET_VALUE,
+                    VIA_RGB_MATRIX_CHANNEL,
+                    VIA_LIGHTING_EFFECT,
+                    4,
+                    0
+                ][..],
+                &[
+                    0,
+                    VIA_CUSTOM_SET_VALUE,
+                    VIA_RGB_MATRIX_CHANNEL,
+                    VIA_LIGHTING_EFFECT_SPEED,
+                    100,
+                    0
+                ],
+                &[
+                    0,
+                    VIA_CUSTOM_SET_VALUE,
+                    VIA_RGB_MATRIX_CHANNEL,
+                    VIA_LIGHTING_COLOR,
+                    85,
+                    255
+                ],
+                &[
+                    0,
+                    VIA_CUSTOM_SET_VALUE,
+                    VIA_RGB_MATRIX_CHANNEL,
+                    VIA_LIGHTING_BRIGHTNESS,
+                    200,
+                    0
+                ],
+            ]
+        );
+        assert!(written.iter().all(|report| report.len() == 1 + 32));
+        assert!(sink.device.reports.borrow().is_empty());
+    }
+
+    #[test]
+    fn unset_values_are_not_sent() {
+        let device = FakeDevice::new(|report| vec![report.to_vec()]);
+        let settings = LightingSettings {
+            brightness: Some(0),
+            ..LightingSettings::default()
+        };
+        let sink = ViaLightingSink::new(device, lighting(settings));
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        let written = sink.device.written.borrow();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0][3], VIA_LIGHTING_BRIGHTNESS);
+    }
+
+    #[test]
+    fn unmapped_values_are_ignored() {
+        let device = FakeDevice::new(|report| vec![report.to_vec()]);
+        let sink = ViaLightingSink::new(device, lighting(all_settings()));
+
+        sink.send(&value(1, vec![3])).unwrap();
+        sink.send(&value(2, vec![2])).unwrap();
+
+        assert!(sink.device.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn skips_the_reports_of_other_programs() {
+        let device = FakeDevice::new(|report| {
+            let mut other_value = report.to_vec();
+            other_value[2] = 0x10;
+            vec![
+                vec![0x51, 1, 2],
+                // Echo of another channel
+                vec![VIA_CUSTOM_SET_VALUE, VIA_RGBLIGHT_CHANNEL, report[2]],
+                other_value,
+                report.to_vec(),
+            ]
+        });
+        let sink = ViaLightingSink::new(device, lighting(all_settings()));
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        // Each value waited for its own echo
+        assert_eq!(sink.device.written.borrow().len(), 4);
+        assert!(sink.device.reports.borrow().is_empty());
+    }
+
+    #[test]
+    fn unhandled_channels() {
+        let device = FakeDevice::new(|report| {
+            let mut unhandled = report.to_vec();
+            unhandled[0] = VIA_UNHANDLED;
+            vec![unhandled]
+        });
+        let sink = ViaLightingSink::new(device, lighting(all_settings()));
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        assert_eq!(sink.device.written.borrow().len(), 4);
+        assert!(sink.device.reports.borrow().is_empty());
+    }
+
+    #[test]
+    fn missing_echoes() {
+        let device = FakeDevice::new(|_| Vec::new());
+        let sink = ViaLightingSink::new(device, lighting(all_settings()));
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        assert_eq!(sink.device.written.borrow().len(), 4);
+    }
+
+    #[test]
+    fn numbered_reports() {
+        // Numbered reports are read along their report id
+        let device = FakeDevice::new(|report| vec![[&[3], report].concat()]);
+        let layout = ReportLayout {
+            report_length: 64,
+            report_id: 3,
+            header: vec![0x51],
+            padding: 0xFF,
+        };
+        let sink = ViaLightingSink::new(device, lighting(all_settings())).with_layout(layout);
+
+        sink.send(&value(1, vec![2])).unwrap();
+
+        let written = sink.device.written.borrow();
+        // The header is not sent, VIA reads the commands from the first byte
+        assert_eq!(
+            &written[0][..5],
+            &[
+                3,
+                VIA_CUSTOM_SET_VALUE,
+                VIA_RGB_MATRIX_CHANNEL,
+                VIA_LIGHTING_EFFECT,
+                4
+            ]
+        );
+        assert_eq!(written[0].len(), 65);
+        assert_eq!(written[0][64], 0xFF);
+        assert!(sink.device.reports.borrow().is_empty());
+    }
+}